@@ -0,0 +1,354 @@
+//! Async encoding and decoding, with tokio.
+//!
+//! [`AsyncEncoder`] has the same methods as [`Encoder`], but writes into
+//! a [`tokio::io::AsyncWrite`]. Values are encoded into an internal buffer,
+//! which is written asynchronously once it is large enough, and upon
+//! finalizing.
+//!
+//! [`write_message`] and [`read_message`] are async versions of
+//! [`crate::message::MessageWriter`] and [`crate::message::MessageReader`].
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{
+    copy::CopyTable,
+    fragment::Fragment,
+    header::Header,
+    message::{decode_len, encode_len, MAX_PREFIX_LEN},
+    packed::PackedElem,
+    ser::Result,
+    types::{Offset, Tag, VariantIdx},
+    Decoder, Encoder, Immediate,
+};
+
+/// The internal buffer is written once it reaches this size.
+const BUF_THRESHOLD: usize = 64 * 1024;
+
+macro_rules! forward {
+    ($(#[$attr:meta])* $name:ident($($arg:ident: $typ:ty),*) -> $ret:ty) => {
+        $(#[$attr])*
+        pub async fn $name(&mut self, $($arg: $typ),*) -> Result<$ret> {
+            let res = self.enc.$name($($arg),*)?;
+            self.write_buf(false).await?;
+            Ok(res)
+        }
+    };
+}
+
+/// An encoder that writes into an async writer.
+///
+/// See [`Encoder`] for the documentation of each method.
+pub struct AsyncEncoder<W> {
+    enc: Encoder<Vec<u8>>,
+    w: W,
+}
+
+impl<W> std::fmt::Debug for AsyncEncoder<W> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "AsyncEncoder {{offset: {}}}", self.enc.offset())
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncEncoder<W> {
+    /// Create an encoder. See [`Encoder::new`].
+    pub fn new(w: W) -> Self {
+        AsyncEncoder {
+            enc: Encoder::new(vec![]),
+            w,
+        }
+    }
+
+    /// Create an encoder that starts with a header. See [`Encoder::with_header`].
+    ///
+    /// The header is written along with the first values.
+    pub fn with_header(w: W, header: Header) -> Result<Self> {
+        Ok(AsyncEncoder {
+            enc: Encoder::with_header(vec![], header)?,
+            w,
+        })
+    }
+
+    /// See [`Encoder::append_to`].
+    pub fn append_to(existing_len: Offset, w: W) -> Self {
+        AsyncEncoder {
+            enc: Encoder::append_to(existing_len, vec![]),
+            w,
+        }
+    }
+
+    /// See [`Encoder::append_to_blob`].
+    pub fn append_to_blob(dec: &Decoder, w: W) -> Self {
+        AsyncEncoder {
+            enc: Encoder::append_to_blob(dec, vec![]),
+            w,
+        }
+    }
+
+    /// See [`Encoder::with_base`].
+    pub fn with_base(base: &Decoder, w: W) -> Self {
+        AsyncEncoder {
+            enc: Encoder::with_base(base, vec![]),
+            w,
+        }
+    }
+
+    /// Current offset, ie. the offset at which the next value will be written.
+    #[inline]
+    pub fn offset(&self) -> Offset {
+        self.enc.offset()
+    }
+
+    /// Write the internal buffer if it's large enough, or if `force` is true.
+    async fn write_buf(&mut self, force: bool) -> Result<()> {
+        let buf = self.enc.get_mut();
+        if force || buf.len() >= BUF_THRESHOLD {
+            self.w.write_all(buf).await?;
+            buf.clear();
+        }
+        Ok(())
+    }
+
+    /// Write the values encoded so far, and flush the writer.
+    pub async fn flush(&mut self) -> Result<()> {
+        self.write_buf(true).await?;
+        self.w.flush().await
+    }
+
+    forward!(write_null() -> Offset);
+    forward!(write_bool(b: bool) -> Offset);
+    forward!(write_i64(n: i64) -> Offset);
+    forward!(write_ref(p: Offset) -> Offset);
+    forward!(write_pointer(p: Offset) -> Offset);
+    forward!(write_f32(f: f32) -> Offset);
+    forward!(write_f64(f: f64) -> Offset);
+    forward!(write_string(s: &str) -> Offset);
+    forward!(write_bytes(b: &[u8]) -> Offset);
+    forward!(write_variant0(c: VariantIdx) -> Offset);
+    forward!(write_immediate(imm: Immediate<'_>) -> Offset);
+    forward!(write_immediate_or_return_pointer(imm: Immediate<'_>) -> Offset);
+    forward!(write_tag(tag: Tag, v: Immediate<'_>) -> Offset);
+    forward!(write_array(arr: &[Immediate<'_>]) -> Offset);
+    forward!(write_map(map: &[(Immediate<'_>, Immediate<'_>)]) -> Offset);
+    forward!(write_variant(c: VariantIdx, args: &[Immediate<'_>]) -> Immediate<'static>);
+    forward!(splice(frag: &Fragment) -> Immediate<'static>);
+    forward!(copy_value_from(dec: &Decoder<'_>, off: Offset, table: &mut CopyTable) -> Offset);
+    forward!(write_u8_array(xs: &[u8]) -> Offset);
+    forward!(write_u16_array(xs: &[u16]) -> Offset);
+    forward!(write_u32_array(xs: &[u32]) -> Offset);
+    forward!(write_u64_array(xs: &[u64]) -> Offset);
+    forward!(write_i8_array(xs: &[i8]) -> Offset);
+    forward!(write_i16_array(xs: &[i16]) -> Offset);
+    forward!(write_i32_array(xs: &[i32]) -> Offset);
+    forward!(write_i64_array(xs: &[i64]) -> Offset);
+    forward!(write_f32_array(xs: &[f32]) -> Offset);
+    forward!(write_f64_array(xs: &[f64]) -> Offset);
+
+    pub async fn write_packed_array<T: PackedElem>(&mut self, xs: &[T]) -> Result<Offset> {
+        let off = self.enc.write_packed_array(xs)?;
+        self.write_buf(false).await?;
+        Ok(off)
+    }
+
+    pub async fn copy_from<'a>(
+        &mut self,
+        dec: &Decoder<'a>,
+        off: Offset,
+        table: &mut CopyTable,
+    ) -> Result<Immediate<'a>> {
+        let imm = self.enc.copy_from(dec, off, table)?;
+        self.write_buf(false).await?;
+        Ok(imm)
+    }
+
+    /// Write the postfix, flush, and return the writer. See [`Encoder::finalize`].
+    pub async fn finalize(mut self, entrypoint: Immediate<'_>) -> Result<W> {
+        self.enc.write_postfix(entrypoint)?;
+        self.flush().await?;
+        Ok(self.w)
+    }
+
+    /// See [`Encoder::finalize_with_roots`].
+    pub async fn finalize_with_roots(
+        mut self,
+        roots: &[(&str, Immediate<'_>)],
+        metadata: &[(&str, Immediate<'_>)],
+    ) -> Result<W> {
+        let dir = self.enc.write_root_directory(roots, metadata)?;
+        self.finalize(Immediate::Pointer(dir)).await
+    }
+
+    /// See [`Encoder::finalize_versioned`].
+    pub async fn finalize_versioned(mut self, entrypoint: Immediate<'_>) -> Result<W> {
+        self.enc.set_versioned();
+        self.finalize(entrypoint).await
+    }
+}
+
+/// Write `blob` as a message. See [`crate::message`].
+pub async fn write_message<W: AsyncWrite + Unpin>(w: &mut W, blob: &[u8]) -> Result<()> {
+    let mut prefix = [0u8; MAX_PREFIX_LEN];
+    w.write_all(encode_len(blob.len(), &mut prefix)).await?;
+    w.write_all(blob).await
+}
+
+/// Read a message into `buf`, and return a decoder for it. See [`crate::message`].
+///
+/// Returns `Ok(None)` if the reader is at its end. Messages longer than `max_len`
+/// are rejected, and the reader is left in the middle of the message.
+pub async fn read_message<'b, R: AsyncRead + Unpin>(
+    r: &mut R,
+    buf: &'b mut Vec<u8>,
+    max_len: usize,
+) -> Result<Option<Decoder<'b>>> {
+    let mut prefix = [0u8; MAX_PREFIX_LEN];
+    let mut n = 0;
+    let len = loop {
+        if r.read(&mut prefix[n..n + 1]).await? == 0 {
+            if n == 0 {
+                return Ok(None);
+            }
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        n += 1;
+        if let Some((len, _)) = decode_len(&prefix[..n], max_len)? {
+            break len;
+        }
+    };
+
+    buf.clear();
+    buf.resize(len, 0);
+    r.read_exact(buf).await?;
+    Ok(Some(Decoder::new(buf)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        header::FLAG_CHECKSUM,
+        value::{self, Value},
+    };
+
+    fn block_on<F: std::future::Future>(f: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(f)
+    }
+
+    fn sample() -> Value {
+        Value::Array(
+            (0..5000)
+                .map(|i| {
+                    Value::Map(vec![
+                        (Value::String("id".to_string()), Value::Int64(i)),
+                        (
+                            Value::String("name".to_string()),
+                            Value::String(format!("item {i}")),
+                        ),
+                    ])
+                })
+                .collect(),
+        )
+    }
+
+    /// Write `v` with the async encoder, for a simple subset of values.
+    async fn write_value<W: AsyncWrite + Unpin>(
+        enc: &mut AsyncEncoder<W>,
+        v: &Value,
+    ) -> Result<Offset> {
+        let Value::Array(items) = v else { panic!() };
+        let mut offs = vec![];
+        for item in items {
+            let Value::Map(pairs) = item else { panic!() };
+            let (Value::Int64(i), Value::String(s)) = (&pairs[0].1, &pairs[1].1) else {
+                panic!()
+            };
+            let s = enc.write_string(s).await?;
+            let m = enc
+                .write_map(&[
+                    (Immediate::String("id"), Immediate::Int64(*i)),
+                    (Immediate::String("name"), Immediate::Pointer(s)),
+                ])
+                .await?;
+            offs.push(Immediate::Pointer(m));
+        }
+        enc.write_array(&offs).await
+    }
+
+    #[test]
+    fn test_async_encoder() {
+        let v = sample();
+        let blob = block_on(async {
+            let enc = AsyncEncoder::with_header(vec![], Header::with_flags(FLAG_CHECKSUM));
+            let mut enc = enc.unwrap();
+            let off = write_value(&mut enc, &v).await.unwrap();
+            enc.finalize(Immediate::Pointer(off)).await.unwrap()
+        });
+
+        let dec = Decoder::new_verified(&blob).unwrap();
+        assert_eq!(value::read_value_from_entrypoint(&dec).unwrap(), v);
+    }
+
+    #[test]
+    fn test_messages() {
+        let v = sample();
+        block_on(async {
+            let (mut client, mut server) = tokio::io::duplex(1024);
+
+            let writer = async {
+                for i in 0..3 {
+                    let mut enc = AsyncEncoder::new(vec![]);
+                    let off = write_value(&mut enc, &v).await.unwrap();
+                    let arr = enc
+                        .write_array(&[Immediate::Int64(i), Immediate::Pointer(off)])
+                        .await
+                        .unwrap();
+                    let blob = enc.finalize(Immediate::Pointer(arr)).await.unwrap();
+                    write_message(&mut client, &blob).await.unwrap();
+                }
+                drop(client);
+            };
+
+            let reader = async {
+                let mut buf = vec![];
+                let mut n = 0;
+                while let Some(dec) = read_message(&mut server, &mut buf, 1 << 20).await.unwrap() {
+                    let mut top = vec![];
+                    dec.get_array(dec.entrypoint().unwrap(), &mut top).unwrap();
+                    assert_eq!(dec.get_i64(top[0]).unwrap(), n);
+                    assert_eq!(value::read_value(&dec, top[1]).unwrap(), v);
+                    n += 1;
+                }
+                assert_eq!(n, 3);
+            };
+
+            tokio::join!(writer, reader);
+        });
+    }
+
+    #[test]
+    fn test_message_errors() {
+        block_on(async {
+            let mut blob = vec![];
+            Encoder::new(&mut blob)
+                .finalize(Immediate::String("hello"))
+                .unwrap();
+            let mut msg = vec![];
+            write_message(&mut msg, &blob).await.unwrap();
+
+            let mut buf = vec![];
+            let mut r = &msg[..];
+            assert!(read_message(&mut r, &mut buf, 100).await.unwrap().is_some());
+            assert!(read_message(&mut r, &mut buf, 100).await.unwrap().is_none());
+
+            let mut r = &msg[..];
+            let e = read_message(&mut r, &mut buf, 2).await.unwrap_err();
+            assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+
+            let mut r = &msg[..msg.len() - 1];
+            let e = read_message(&mut r, &mut buf, 100).await.unwrap_err();
+            assert_eq!(e.kind(), std::io::ErrorKind::UnexpectedEof);
+        });
+    }
+}
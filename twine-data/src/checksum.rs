@@ -0,0 +1,131 @@
+//! Checksums.
+//!
+//! Blobs whose header has the [`crate::header::FLAG_CHECKSUM`] flag end with
+//! a footer containing the CRC32C (Castagnoli) checksum of everything before it,
+//! stored as 4 little-endian bytes.
+
+/// Length of the checksum footer, in bytes.
+pub const FOOTER_LEN: usize = 4;
+
+const POLY: u32 = 0x82f6_3b78; // reversed Castagnoli polynomial
+
+const TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut c = i as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 { (c >> 1) ^ POLY } else { c >> 1 };
+            k += 1;
+        }
+        table[i] = c;
+        i += 1;
+    }
+    table
+};
+
+/// Incremental CRC32C computation.
+#[derive(Debug, Clone, Copy)]
+pub struct Crc32c(u32);
+
+impl Default for Crc32c {
+    fn default() -> Self {
+        Crc32c(!0)
+    }
+}
+
+impl Crc32c {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Resume the computation of a checksum, given the checksum of the
+    /// bytes seen so far (as returned by [`Crc32c::finish`]).
+    pub fn resume(crc: u32) -> Self {
+        Crc32c(!crc)
+    }
+
+    /// Feed more bytes into the checksum.
+    pub fn update(&mut self, bs: &[u8]) {
+        let mut c = self.0;
+        for &b in bs {
+            c = TABLE[((c ^ b as u32) & 0xff) as usize] ^ (c >> 8);
+        }
+        self.0 = c;
+    }
+
+    /// Checksum of all the bytes seen so far.
+    pub fn finish(&self) -> u32 {
+        !self.0
+    }
+}
+
+/// Compute the CRC32C of `bs`.
+pub fn crc32c(bs: &[u8]) -> u32 {
+    let mut c = Crc32c::new();
+    c.update(bs);
+    c.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::header::{Header, FLAG_CHECKSUM};
+    use crate::{Decoder, Encoder, Immediate};
+
+    #[test]
+    fn test_crc32c() {
+        // reference values from RFC 3720
+        assert_eq!(crc32c(b""), 0);
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+        assert_eq!(crc32c(&[0u8; 32]), 0x8a91_36aa);
+        assert_eq!(crc32c(&[0xffu8; 32]), 0x62a8_ab43);
+
+        let mut c = Crc32c::new();
+        c.update(b"1234");
+        c.update(b"56789");
+        assert_eq!(c.finish(), 0xe306_9283);
+    }
+
+    #[test]
+    fn test_footer() {
+        let mut res: Vec<u8> = vec![];
+        let mut enc = Encoder::with_header(&mut res, Header::with_flags(FLAG_CHECKSUM)).unwrap();
+        let s = enc.write_string("hello").unwrap();
+        let arr = enc
+            .write_array(&[Immediate::Pointer(s), Immediate::Int64(42)])
+            .unwrap();
+        enc.finalize(Immediate::Pointer(arr)).unwrap();
+
+        let dec = Decoder::new_verified(&res).unwrap();
+        assert_eq!(dec.entrypoint().unwrap(), arr);
+        let mut offs = vec![];
+        dec.get_array(dec.entrypoint().unwrap(), &mut offs).unwrap();
+        assert_eq!(dec.get_str(offs[0]).unwrap(), "hello");
+
+        for i in 0..res.len() {
+            let mut corrupted = res.clone();
+            corrupted[i] ^= 0x10;
+            // corrupting the header might make the decoder fail early.
+            if let Ok(dec) = Decoder::new(&corrupted) {
+                assert!(dec.verify_checksum().is_err())
+            }
+            assert!(Decoder::new_verified(&corrupted).is_err());
+        }
+    }
+
+    #[test]
+    fn test_no_checksum() {
+        let mut res: Vec<u8> = vec![];
+        let enc = Encoder::new(&mut res);
+        enc.finalize(Immediate::Int64(1)).unwrap();
+
+        let dec = Decoder::new(&res).unwrap();
+        assert_eq!(
+            dec.verify_checksum().unwrap_err().msg,
+            "blob has no checksum"
+        );
+        assert!(Decoder::new_verified(&res).is_err());
+    }
+}
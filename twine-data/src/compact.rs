@@ -0,0 +1,207 @@
+//! Compaction.
+//!
+//! Appending to a blob (see [`crate::edit`]) leaves behind values that are no
+//! longer reachable from the entrypoint. Compaction copies only the reachable
+//! values into a fresh blob. Shared values are copied only once, and
+//! chains of pointers are collapsed.
+
+use std::io;
+
+use crate::{copy::CopyTable, types::Offset, Decoder, Encoder, Immediate};
+
+/// Sizes of a blob before and after compaction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompactStats {
+    /// Size of the original blob, in bytes.
+    pub bytes_before: u64,
+    /// Size of the compacted blob, in bytes.
+    pub bytes_after: u64,
+}
+
+impl CompactStats {
+    /// How many bytes were saved by compacting.
+    pub fn bytes_reclaimed(&self) -> u64 {
+        self.bytes_before.saturating_sub(self.bytes_after)
+    }
+}
+
+/// Counts bytes written through it.
+struct CountingWriter<W> {
+    w: W,
+    n: u64,
+}
+
+impl<W: io::Write> io::Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.w.write(buf)?;
+        self.n += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.w.flush()
+    }
+}
+
+/// Copy the values reachable from each of `roots` into a fresh blob written into `w`.
+///
+/// The entrypoint of the new blob is an array of pointers to the roots.
+/// Returns the new offsets of the roots. If the original blob has a header,
+/// the new blob uses the same header.
+pub fn compact_roots_to<W: io::Write>(
+    dec: &Decoder,
+    roots: &[Offset],
+    w: W,
+) -> io::Result<(Vec<Offset>, CompactStats)> {
+    let mut w = CountingWriter { w, n: 0 };
+    let mut enc = match dec.header() {
+        Some(h) => Encoder::with_header(&mut w, h)?,
+        None => Encoder::new(&mut w),
+    };
+
+    let mut table = CopyTable::new();
+    let mut new_roots = Vec::with_capacity(roots.len());
+    for r in roots {
+        new_roots.push(enc.copy_value_from(dec, *r, &mut table)?);
+    }
+
+    let arr: Vec<_> = new_roots.iter().map(|r| Immediate::Pointer(*r)).collect();
+    let arr = enc.write_array(&arr)?;
+    enc.finalize(Immediate::Pointer(arr))?;
+
+    let stats = CompactStats {
+        bytes_before: dec.len(),
+        bytes_after: w.n,
+    };
+    Ok((new_roots, stats))
+}
+
+/// Copy the values reachable from the entrypoint into a fresh blob written into `w`.
+///
+/// Only the latest version of a blob with a version history (see [`crate::versions`])
+/// is kept. If the original blob has a header, the new blob uses the same header.
+pub fn compact_to<W: io::Write>(dec: &Decoder, w: W) -> io::Result<CompactStats> {
+    let mut w = CountingWriter { w, n: 0 };
+    let mut enc = match dec.header() {
+        Some(h) => Encoder::with_header(&mut w, h)?,
+        None => Encoder::new(&mut w),
+    };
+
+    let mut table = CopyTable::new();
+    let root = enc.copy_value_from(dec, dec.entrypoint()?, &mut table)?;
+    enc.finalize(Immediate::Pointer(root))?;
+
+    Ok(CompactStats {
+        bytes_before: dec.len(),
+        bytes_after: w.n,
+    })
+}
+
+/// Copy the values reachable from the entrypoint into a fresh blob.
+///
+/// See [`compact_to`].
+pub fn compact(dec: &Decoder) -> io::Result<(Vec<u8>, CompactStats)> {
+    let mut res = vec![];
+    let stats = compact_to(dec, &mut res)?;
+    Ok((res, stats))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        edit::{self, PathElem},
+        value::{self, Value},
+    };
+
+    #[test]
+    fn test_compact() {
+        let mut blob: Vec<u8> = vec![];
+        let mut enc = Encoder::new(&mut blob);
+        let long = "a long string that is shared in several places";
+        let s = enc.write_string(long).unwrap();
+        let p1 = enc.write_pointer(s).unwrap();
+        let p2 = enc.write_pointer(p1).unwrap();
+        let arr = enc
+            .write_array(&[
+                Immediate::Pointer(s),
+                Immediate::Pointer(p2),
+                Immediate::Ref(s),
+                Immediate::Int64(0),
+            ])
+            .unwrap();
+        let top = enc
+            .write_map(&[(Immediate::String("items"), Immediate::Pointer(arr))])
+            .unwrap();
+        enc.finalize(Immediate::Pointer(top)).unwrap();
+
+        // make some garbage
+        for i in 1..10 {
+            let dec = Decoder::new(&blob).unwrap();
+            let mut suffix = vec![];
+            let path = [PathElem::Key("items"), PathElem::Index(3)];
+            edit::edit(&dec, &path, &Value::Int64(i), &mut suffix).unwrap();
+            blob.extend_from_slice(&suffix);
+        }
+
+        let dec = Decoder::new(&blob).unwrap();
+        let (compacted, stats) = compact(&dec).unwrap();
+        assert_eq!(stats.bytes_before, blob.len() as u64);
+        assert_eq!(stats.bytes_after, compacted.len() as u64);
+        assert!(stats.bytes_reclaimed() > 0);
+
+        let dec2 = Decoder::new(&compacted).unwrap();
+        let v = value::read_value_from_entrypoint(&dec2).unwrap();
+        let Value::Map(m) = &v else { panic!() };
+        let Value::Array(items) = &m[0].1 else {
+            panic!()
+        };
+        assert_eq!(items[0], Value::String(long.to_string()));
+        assert_eq!(items[1], Value::String(long.to_string()));
+        assert_eq!(items[3], Value::Int64(9));
+
+        // the reference was translated
+        let Value::Ref(r) = items[2] else { panic!() };
+        assert_eq!(dec2.get_str(r).unwrap(), long);
+
+        // the string is stored only once, and the chain of pointers is collapsed
+        assert_eq!(
+            compacted
+                .windows(long.len())
+                .filter(|w| *w == long.as_bytes())
+                .count(),
+            1
+        );
+        assert!(compacted.len() < long.len() + 30);
+    }
+
+    #[test]
+    fn test_compact_roots() {
+        let mut blob: Vec<u8> = vec![];
+        let mut enc = Encoder::new(&mut blob);
+        let a = value::write_value(&mut enc, &Value::Array(vec![Value::Int64(1); 10])).unwrap();
+        let b = enc.write_string("b").unwrap();
+        let _garbage = enc.write_string("garbage").unwrap();
+        let c = enc
+            .write_array(&[Immediate::Pointer(a), Immediate::Pointer(b)])
+            .unwrap();
+        enc.finalize(Immediate::Pointer(c)).unwrap();
+
+        let dec = Decoder::new(&blob).unwrap();
+        let mut res = vec![];
+        let (roots, stats) = compact_roots_to(&dec, &[b, a], &mut res).unwrap();
+        assert_eq!(stats.bytes_after, res.len() as u64);
+
+        let dec2 = Decoder::new(&res).unwrap();
+        assert_eq!(dec2.get_str(roots[0]).unwrap(), "b");
+        assert_eq!(
+            value::read_value(&dec2, roots[1]).unwrap(),
+            value::read_value(&dec, a).unwrap()
+        );
+        let mut offs = vec![];
+        dec2.get_array(dec2.entrypoint().unwrap(), &mut offs)
+            .unwrap();
+        assert_eq!(offs.len(), 2);
+        assert!(!res.windows(7).any(|w| w == b"garbage"));
+    }
+}
@@ -0,0 +1,358 @@
+//! Copying values between blobs.
+//!
+//! [`crate::Encoder::copy_from`] re-encodes a value read from a [`Decoder`],
+//! along with everything it points to, into an encoder. This makes it
+//! possible to assemble a blob from pieces of other blobs.
+//!
+//! Offsets are translated along the way (including the targets of
+//! [`Immediate::Ref`]), and a [`CopyTable`] remembers where each value
+//! was copied so that values shared in the source blob remain shared in the output.
+//! Packed arrays (see [`crate::packed`]) are padded again, so that their elements
+//! are aligned at their new offset.
+
+use std::{collections::HashMap, io};
+
+use crate::{
+    packed::{self, PackedElem},
+    ser::Checkpoint,
+    shallow_value::ShallowValue,
+    types::{Error, Offset, Tag},
+    Decoder, Encoder, Immediate,
+};
+
+/// Translation table from offsets in a source blob to offsets in the encoder
+/// they were copied into.
+///
+/// A table must only be used with one source blob and one encoder, but it can be
+/// reused across several calls to [`Encoder::copy_from`] to preserve sharing
+/// between the copied values.
+#[derive(Debug, Clone, Default)]
+pub struct CopyTable {
+    map: HashMap<Offset, Offset>,
+}
+
+impl CopyTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Offset at which the value at `off` (in the source blob) was copied, if any.
+    pub fn get(&self, off: Offset) -> Option<Offset> {
+        self.map.get(&off).copied()
+    }
+
+    /// Number of values copied so far.
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Forget all translations, eg. to reuse the table with another source blob.
+    pub fn clear(&mut self) {
+        self.map.clear()
+    }
+
+    /// Forget the copies that were discarded by rolling the encoder back to `cp`
+    /// (see [`Encoder::rollback`]), ie. those written at or after its offset.
+    pub fn rollback(&mut self, cp: &Checkpoint) {
+        self.map.retain(|_, new_off| *new_off < cp.offset())
+    }
+}
+
+/// Copies values from a decoder into an encoder.
+pub(crate) struct Copier<'a, 'd, 't> {
+    pub(crate) dec: &'d Decoder<'a>,
+    pub(crate) table: &'t mut CopyTable,
+}
+
+impl<'a, 'd, 't> Copier<'a, 'd, 't> {
+    /// Copy the value at `off`, and return its new offset.
+    pub(crate) fn copy_value<W: io::Write>(
+        &mut self,
+        enc: &mut Encoder<W>,
+        off: Offset,
+    ) -> io::Result<Offset> {
+        let off = self.dec.deref(off)?;
+        if let Some(&new_off) = self.table.map.get(&off) {
+            return Ok(new_off);
+        }
+
+        let new_off = match self.dec.get_shallow_value(off)? {
+            ShallowValue::Imm(imm) => {
+                let imm = self.copy_imm(enc, imm)?;
+                enc.write_immediate(imm)?
+            }
+            ShallowValue::Tag(tag, v) => match self.copy_packed(enc, off, tag)? {
+                Some(new_off) => new_off,
+                None => {
+                    let v = self.copy_child(enc, v)?;
+                    enc.write_tag(tag, v)?
+                }
+            },
+            ShallowValue::Array(arr) => {
+                let mut items = Vec::with_capacity(arr.len_usize()?);
+                for c in arr {
+                    items.push(self.copy_child(enc, c?)?);
+                }
+                enc.write_array(&items)?
+            }
+            ShallowValue::Map(map) => {
+                let mut items = Vec::with_capacity(map.len_usize()?);
+                for pair in map {
+                    let (k, v) = pair?;
+                    let k = self.copy_child(enc, k)?;
+                    let v = self.copy_child(enc, v)?;
+                    items.push((k, v));
+                }
+                enc.write_map(&items)?
+            }
+            ShallowValue::Variant(c, args) => {
+                let mut items = Vec::with_capacity(args.len_usize()?);
+                for a in args {
+                    items.push(self.copy_child(enc, a?)?);
+                }
+                match enc.write_variant(c, &items)? {
+                    Immediate::Pointer(p) => p,
+                    imm => enc.write_immediate(imm)?,
+                }
+            }
+        };
+        self.table.map.insert(off, new_off);
+        Ok(new_off)
+    }
+
+    /// Copy the value at `off`, tagged with `tag`, if it is a packed array.
+    ///
+    /// The padding of the source is relative to its own offset, so the elements
+    /// are written again rather than copied as an opaque byte string.
+    fn copy_packed<W: io::Write>(
+        &mut self,
+        enc: &mut Encoder<W>,
+        off: Offset,
+        tag: Tag,
+    ) -> io::Result<Option<Offset>> {
+        fn copy_as<T: PackedElem, W: io::Write>(
+            dec: &Decoder,
+            enc: &mut Encoder<W>,
+            off: Offset,
+        ) -> io::Result<Option<Offset>> {
+            match dec.get_packed_array::<T>(off) {
+                Ok(xs) => Ok(Some(enc.write_packed_array(&xs)?)),
+                // not a valid packed array, copy it as a regular tag
+                Err(_) => Ok(None),
+            }
+        }
+
+        let dec = self.dec;
+        match tag {
+            packed::TAG_U8 => copy_as::<u8, W>(dec, enc, off),
+            packed::TAG_U16_LE => copy_as::<u16, W>(dec, enc, off),
+            packed::TAG_U32_LE => copy_as::<u32, W>(dec, enc, off),
+            packed::TAG_U64_LE => copy_as::<u64, W>(dec, enc, off),
+            packed::TAG_I8 => copy_as::<i8, W>(dec, enc, off),
+            packed::TAG_I16_LE => copy_as::<i16, W>(dec, enc, off),
+            packed::TAG_I32_LE => copy_as::<i32, W>(dec, enc, off),
+            packed::TAG_I64_LE => copy_as::<i64, W>(dec, enc, off),
+            packed::TAG_F32_LE => copy_as::<f32, W>(dec, enc, off),
+            packed::TAG_F64_LE => copy_as::<f64, W>(dec, enc, off),
+            _ => Ok(None),
+        }
+    }
+
+    /// Copy the immediate at `off`, which is part of a larger value.
+    fn copy_child<W: io::Write>(
+        &mut self,
+        enc: &mut Encoder<W>,
+        off: Offset,
+    ) -> io::Result<Immediate<'a>> {
+        let (high, _) = self.dec.first_byte(off)?;
+        if high == 15 {
+            return Ok(Immediate::Pointer(self.copy_value(enc, off)?));
+        }
+        match self.dec.get_shallow_value(off)? {
+            ShallowValue::Imm(imm) => self.copy_imm(enc, imm),
+            ShallowValue::Variant(c, args) if args.len() == 0 => Ok(Immediate::Variant0(c)),
+            _ => Err(Error {
+                msg: "expected immediate",
+                off,
+            }
+            .into()),
+        }
+    }
+
+    fn copy_imm<W: io::Write>(
+        &mut self,
+        enc: &mut Encoder<W>,
+        imm: Immediate<'a>,
+    ) -> io::Result<Immediate<'a>> {
+        match imm {
+            Immediate::Ref(p) => Ok(Immediate::Ref(self.copy_value(enc, p)?)),
+            Immediate::Pointer(p) => Ok(Immediate::Pointer(self.copy_value(enc, p)?)),
+            imm => Ok(imm),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        types::VariantIdx,
+        value::{self, Value},
+    };
+
+    fn blob_with_ref(s: &str) -> (Vec<u8>, Offset) {
+        let mut res: Vec<u8> = vec![];
+        let mut enc = Encoder::new(&mut res);
+        let _padding = enc.write_string("padding padding padding").unwrap();
+        let off_s = enc.write_string(s).unwrap();
+        let arr = enc
+            .write_array(&[
+                Immediate::Ref(off_s),
+                Immediate::Pointer(off_s),
+                Immediate::Int64(1),
+            ])
+            .unwrap();
+        enc.finalize(Immediate::Pointer(arr)).unwrap();
+        (res, arr)
+    }
+
+    #[test]
+    fn test_copy_from() {
+        let (blob1, arr1) = blob_with_ref("first");
+        let (blob2, arr2) = blob_with_ref("second");
+        let dec1 = Decoder::new(&blob1).unwrap();
+        let dec2 = Decoder::new(&blob2).unwrap();
+
+        let mut res: Vec<u8> = vec![];
+        let mut enc = Encoder::new(&mut res);
+        let mut table1 = CopyTable::new();
+        let mut table2 = CopyTable::new();
+        let a = enc.copy_from(&dec1, arr1, &mut table1).unwrap();
+        let b = enc.copy_from(&dec2, arr2, &mut table2).unwrap();
+        // copying again reuses the previous copy
+        let n = enc.offset();
+        let a2 = enc.copy_from(&dec1, arr1, &mut table1).unwrap();
+        assert_eq!(a, a2);
+        assert_eq!(n, enc.offset());
+        assert_eq!(
+            enc.copy_from(&dec1, dec1.entrypoint().unwrap(), &mut table1)
+                .unwrap(),
+            a
+        );
+        // small scalars are not written
+        let mut offs = vec![];
+        dec1.get_array(arr1, &mut offs).unwrap();
+        assert_eq!(
+            enc.copy_from(&dec1, offs[2], &mut table1).unwrap(),
+            Immediate::Int64(1)
+        );
+        let v0 = {
+            let mut blob = vec![];
+            let mut enc = Encoder::new(&mut blob);
+            let v0 = enc.write_variant(VariantIdx(3), &[]).unwrap();
+            enc.finalize(v0).unwrap();
+            blob
+        };
+        let dec_v0 = Decoder::new(&v0).unwrap();
+        let n = enc.offset();
+        assert_eq!(
+            enc.copy_from(&dec_v0, dec_v0.entrypoint().unwrap(), &mut CopyTable::new())
+                .unwrap(),
+            Immediate::Variant0(VariantIdx(3))
+        );
+        assert_eq!(n, enc.offset());
+        let top = enc.write_array(&[a, b]).unwrap();
+        enc.finalize(Immediate::Pointer(top)).unwrap();
+
+        let dec = Decoder::new(&res).unwrap();
+        let v = value::read_value_from_entrypoint(&dec).unwrap();
+        let Value::Array(items) = v else { panic!() };
+        for (item, s) in items.iter().zip(["first", "second"]) {
+            let Value::Array(item) = item else { panic!() };
+            assert_eq!(item[1], Value::String(s.to_string()));
+            assert_eq!(item[2], Value::Int64(1));
+            // references point to the copied value
+            let Value::Ref(r) = item[0] else { panic!() };
+            assert_eq!(dec.get_str(r).unwrap(), s);
+        }
+        // padding was not copied, strings were copied once
+        assert!(!res.windows(7).any(|w| w == b"padding"));
+        assert_eq!(res.windows(5).filter(|w| *w == b"first").count(), 1);
+        assert_eq!(table1.len(), 2);
+    }
+
+    /// Offset of the elements of the packed array at `off`.
+    fn packed_elems_offset(dec: &Decoder, blob: &[u8], off: Offset) -> usize {
+        let (_, inner) = dec.get_tag(off).unwrap();
+        let bs = dec.get_bytes(inner).unwrap();
+        bs.as_ptr() as usize - blob.as_ptr() as usize + 1 + bs[0] as usize
+    }
+
+    #[test]
+    fn test_copy_packed() {
+        let xs: Vec<u64> = (0..100).collect();
+        let ys = [1.5f32, -2.0, 3.25];
+        let mut src: Vec<u8> = vec![];
+        let mut enc = Encoder::new(&mut src);
+        let a = enc.write_u64_array(&xs).unwrap();
+        let b = enc.write_f32_array(&ys).unwrap();
+        let top = enc
+            .write_array(&[Immediate::Pointer(a), Immediate::Pointer(b)])
+            .unwrap();
+        enc.finalize(Immediate::Pointer(top)).unwrap();
+        let src_dec = Decoder::new(&src).unwrap();
+
+        // copy at every offset modulo 8, so that the source padding is wrong
+        for n in 0..8 {
+            let mut res: Vec<u8> = vec![];
+            let mut enc = Encoder::new(&mut res);
+            let _ = enc.write_string(&"x".repeat(n)).unwrap();
+            let mut table = CopyTable::new();
+            let top2 = enc.copy_from(&src_dec, top, &mut table).unwrap();
+            enc.finalize(top2).unwrap();
+
+            let dec = Decoder::new(&res).unwrap();
+            let mut offs = vec![];
+            dec.get_array(dec.entrypoint().unwrap(), &mut offs).unwrap();
+            let (a2, b2) = (dec.deref(offs[0]).unwrap(), dec.deref(offs[1]).unwrap());
+            assert_eq!(&*dec.get_u64_array(a2).unwrap(), &xs[..]);
+            assert_eq!(&*dec.get_f32_array(b2).unwrap(), &ys[..]);
+            assert_eq!(packed_elems_offset(&dec, &res, a2) % 8, 0);
+            assert_eq!(packed_elems_offset(&dec, &res, b2) % 4, 0);
+        }
+    }
+
+    #[test]
+    fn test_copy_rollback() {
+        let (blob1, arr1) = blob_with_ref("first");
+        let dec1 = Decoder::new(&blob1).unwrap();
+        let mut offs = vec![];
+        dec1.get_array(arr1, &mut offs).unwrap();
+
+        let mut res: Vec<u8> = vec![];
+        let mut enc = Encoder::new(&mut res);
+        let mut table = CopyTable::new();
+        let _ = enc.write_string("kept").unwrap();
+        let cp = enc.checkpoint();
+        let a = enc.copy_from(&dec1, arr1, &mut table).unwrap();
+        assert_eq!(table.len(), 2);
+        enc.rollback(cp).unwrap();
+        table.rollback(&cp);
+        assert!(table.is_empty());
+
+        // the string is copied again, rather than pointing to discarded bytes
+        let s = enc.copy_from(&dec1, offs[1], &mut table).unwrap();
+        assert_eq!(s, Immediate::Pointer(cp.offset()));
+        let a2 = enc.copy_from(&dec1, arr1, &mut table).unwrap();
+        assert_eq!(a, a2);
+        enc.finalize(a2).unwrap();
+        let dec = Decoder::new(&res).unwrap();
+        let v = value::read_value_from_entrypoint(&dec).unwrap();
+        let Value::Array(items) = v else { panic!() };
+        assert_eq!(items[1], Value::String("first".to_string()));
+    }
+}
@@ -2,42 +2,445 @@
 //!
 //! Reading data from `twine` is done by offset.
 
+use std::borrow::Cow;
+
+use crate::checksum::{self, Crc32c, FOOTER_LEN};
+use crate::header::{Header, FLAG_CHECKSUM, HEADER_LEN};
+use crate::packed::{self, PackedElem};
+use crate::roots::TAG_ROOT_DIRECTORY;
 use crate::shallow_value::{ArrayCursor, MapCursor};
+use crate::storage::Storage;
+use crate::versions::TAG_VERSION_RECORD;
 
 pub use super::shallow_value::ShallowValue;
 use super::types::*;
 
+macro_rules! get_packed_array_of {
+    ($name:ident, $typ:ty) => {
+        #[doc = concat!("Read a packed array of `", stringify!($typ), "`.")]
+        ///
+        /// See [`Decoder::get_packed_array`].
+        #[inline]
+        pub fn $name(&self, off: Offset) -> Result<Cow<'a, [$typ]>> {
+            self.get_packed_array(off)
+        }
+    };
+}
+
+/// Where the bytes of a blob come from.
+#[derive(Clone, Copy)]
+enum Source<'a> {
+    /// The blob (or a delta, or a suffix), starting at offset `base.len()`.
+    /// `base` is empty unless this decodes a delta or an appended suffix.
+    Slice {
+        base: &'a [u8],
+        bs: &'a [u8],
+        /// Is `bs` a delta over `base`, rather than a suffix of the same blob?
+        delta: bool,
+    },
+    /// Any other storage.
+    Storage(&'a dyn Storage),
+}
+
 /// A decoder for a twine blob.
+///
+/// The blob is either a single slice, a delta over a base blob
+/// (see [`Decoder::with_base`]), a blob extended with an appended suffix
+/// (see [`Decoder::with_suffix`]), or some other [`Storage`].
 #[derive(Clone)]
 pub struct Decoder<'a> {
-    bs: &'a [u8],
+    src: Source<'a>,
+    header: Option<Header>,
+    /// End of the data, ie. offset right after the postfix.
+    end: Offset,
 }
 
 impl<'a> std::fmt::Debug for Decoder<'a> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Decoder {{bs: {} bytes}}", self.bs.len())
+        match self.src {
+            Source::Slice { base: [], bs, .. } => {
+                write!(f, "Decoder {{bs: {} bytes}}", bs.len())
+            }
+            Source::Slice {
+                base,
+                bs,
+                delta: false,
+            } => write!(
+                f,
+                "Decoder {{prefix: {} bytes, suffix: {} bytes}}",
+                base.len(),
+                bs.len()
+            ),
+            Source::Slice { base, bs, .. } => write!(
+                f,
+                "Decoder {{base: {} bytes, delta: {} bytes}}",
+                base.len(),
+                bs.len()
+            ),
+            Source::Storage(st) => write!(f, "Decoder {{storage: {} bytes}}", st.len()),
+        }
     }
 }
 
+/// Offset right after the postfix of a blob of length `len`.
+fn end_of_data(header: Option<Header>, len: Offset) -> Result<Offset> {
+    let mut end = len;
+    if header.is_some_and(|h| h.has_flag(FLAG_CHECKSUM)) {
+        end = end
+            .checked_sub(FOOTER_LEN as Offset)
+            .filter(|&end| end > HEADER_LEN as Offset)
+            .ok_or(Error {
+                msg: "missing checksum footer",
+                off: 0,
+            })?;
+    }
+    Ok(end)
+}
+
 impl<'a> Decoder<'a> {
     /// Create a new decoder reading from these bytes.
+    ///
+    /// If the bytes start with a [`Header`], it is checked; blobs without
+    /// a header are accepted as well.
     pub fn new(bs: &'a [u8]) -> Result<Self> {
-        if bs.len() > u32::MAX as usize {
+        let header = Header::parse(bs)?;
+        let end = end_of_data(header, bs.len() as Offset)?;
+        Ok(Self {
+            src: Source::Slice {
+                base: &[],
+                bs,
+                delta: false,
+            },
+            header,
+            end,
+        })
+    }
+
+    /// Create a new decoder reading from the given storage.
+    ///
+    /// This is like [`Decoder::new`], but the blob doesn't need to be in memory
+    /// in a single slice. See [`crate::storage`].
+    pub fn with_storage(st: &'a dyn Storage) -> Result<Self> {
+        let mut buf = [0u8; HEADER_LEN];
+        let n = (st.len() as usize).min(HEADER_LEN);
+        st.read_at(0, &mut buf[..n])?;
+        let header = Header::parse(&buf[..n])?;
+        let end = end_of_data(header, st.len())?;
+        Ok(Self {
+            src: Source::Storage(st),
+            header,
+            end,
+        })
+    }
+
+    /// Create a decoder reading from the given storage, which holds
+    /// (part of) a blob whose data ends at `end`. The header is not read from
+    /// the storage.
+    pub(crate) fn with_storage_and_end(
+        st: &'a dyn Storage,
+        header: Option<Header>,
+        end: Offset,
+    ) -> Self {
+        Self {
+            src: Source::Storage(st),
+            header,
+            end,
+        }
+    }
+
+    /// Create a decoder for `delta`, a blob encoded on top of `base`
+    /// (see [`crate::Encoder::with_base`]).
+    ///
+    /// The decoder reads the concatenation of the two blobs, without copying them:
+    /// values in `delta` start at offset `base.len()` and can point to
+    /// values in `base`. The delta has its own postfix but no header, and no checksum;
+    /// [`Decoder::header`] and [`Decoder::verify_checksum`] concern the base blob.
+    ///
+    /// `base` must read from a single slice.
+    pub fn with_base(base: &Decoder<'a>, delta: &'a [u8]) -> Result<Self> {
+        let base_bs = match base.src {
+            Source::Slice { base: [], bs, .. } => bs,
+            Source::Slice { delta: true, .. } => {
+                return Err(Error {
+                    msg: "base blob is already a delta",
+                    off: 0,
+                })
+            }
+            Source::Slice { .. } | Source::Storage(_) => {
+                return Err(Error {
+                    msg: "base blob must be a slice",
+                    off: 0,
+                })
+            }
+        };
+        if delta.is_empty() {
             return Err(Error {
+                msg: "empty delta",
+                off: base.len(),
+            });
+        }
+        let end = (base_bs.len() as Offset)
+            .checked_add(delta.len() as Offset)
+            .ok_or(Error {
                 msg: "byte buffer is too long",
                 off: 0,
+            })?;
+        Ok(Self {
+            src: Source::Slice {
+                base: base_bs,
+                bs: delta,
+                delta: true,
+            },
+            header: base.header,
+            end,
+        })
+    }
+
+    /// Create a decoder for the blob read by `prefix`, extended with `suffix`.
+    ///
+    /// `suffix` is what was appended to the blob since it was `prefix`
+    /// (eg. with [`crate::Encoder::append_to_blob`]): a sequence of values followed
+    /// by a new postfix, and a new footer if the blob has a checksum. This checks that
+    /// it is, that its values only point backwards (into `prefix` or to earlier
+    /// values of the suffix), and that the new checksum matches, if any.
+    /// See [`crate::replicate`].
+    ///
+    /// The decoder reads the concatenation of the two slices, without copying them.
+    /// `prefix` must read from a single slice.
+    pub fn with_suffix(prefix: &Decoder<'a>, suffix: &'a [u8]) -> Result<Self> {
+        let prefix_bs = match prefix.src {
+            Source::Slice { base: [], bs, .. } if !bs.is_empty() => bs,
+            _ => {
+                return Err(Error {
+                    msg: "prefix must be a non-empty slice",
+                    off: 0,
+                })
+            }
+        };
+        let len = (prefix_bs.len() as Offset)
+            .checked_add(suffix.len() as Offset)
+            .ok_or(Error {
+                msg: "byte buffer is too long",
+                off: 0,
+            })?;
+        let dec = Self {
+            src: Source::Slice {
+                base: prefix_bs,
+                bs: suffix,
+                delta: false,
+            },
+            header: prefix.header,
+            end: end_of_data(prefix.header, len)?,
+        };
+        dec.check_suffix(prefix_bs.len() as Offset)?;
+        Ok(dec)
+    }
+
+    /// Check the part of the blob that starts at `prev_len`: values that only point
+    /// backwards, then a postfix whose entrypoint is one of them (or is before
+    /// `prev_len`), then the footer.
+    fn check_suffix(&self, prev_len: Offset) -> Result<()> {
+        let err = Error {
+            msg: "invalid postfix",
+            off: self.end,
+        };
+        let last = self
+            .end
+            .checked_sub(1)
+            .filter(|&l| l >= prev_len)
+            .ok_or(err)?;
+        let entry = last
+            .checked_sub(self.byte(last)? as Offset + 1)
+            .ok_or(err)?;
+
+        let mut off = prev_len;
+        let mut found_entry = entry < prev_len;
+        while off < last {
+            found_entry = found_entry || off == entry;
+            off = self.check_value(off, 0)?;
+        }
+        if off != last || !found_entry {
+            return Err(err);
+        }
+
+        if self.header.is_some_and(|h| h.has_flag(FLAG_CHECKSUM)) {
+            // resume from the previous footer, like `Encoder::append_to_blob`
+            let footer_len = FOOTER_LEN as Offset;
+            let prev_footer = self.slice(prev_len - footer_len, footer_len)?;
+            let mut crc = Crc32c::resume(u32::from_le_bytes(prev_footer.try_into().unwrap()));
+            crc.update(prev_footer);
+            crc.update(self.slice(prev_len, self.end - prev_len)?);
+            let footer = self.slice(self.end, footer_len)?;
+            if crc.finish() != u32::from_le_bytes(footer.try_into().unwrap()) {
+                return Err(Error {
+                    msg: "checksum mismatch",
+                    off: self.end,
+                });
+            }
+        }
+        self.raw_entrypoint()?;
+        Ok(())
+    }
+
+    /// Create a new decoder, and check the blob's checksum.
+    ///
+    /// This fails if the blob has no checksum, or if it doesn't match.
+    pub fn new_verified(bs: &'a [u8]) -> Result<Self> {
+        let dec = Self::new(bs)?;
+        dec.verify_checksum()?;
+        Ok(dec)
+    }
+
+    /// Check that the checksum in the blob's footer matches its content.
+    ///
+    /// This fails if the blob has no checksum (see [`FLAG_CHECKSUM`]).
+    pub fn verify_checksum(&self) -> Result<()> {
+        if !self.header.is_some_and(|h| h.has_flag(FLAG_CHECKSUM)) {
+            return Err(Error {
+                msg: "blob has no checksum",
+                off: 0,
             });
         }
-        Ok(Self { bs })
+
+        let (expected, actual, end) = match self.src {
+            Source::Slice {
+                base, delta: true, ..
+            } => {
+                // the checksum covers the first blob, ie. the base for deltas.
+                let end = base.len() - FOOTER_LEN;
+                let expected = u32::from_le_bytes(base[end..].try_into().unwrap());
+                (expected, checksum::crc32c(&base[..end]), end as Offset)
+            }
+            Source::Slice { base, bs, .. } => {
+                // the checksum covers the whole blob, including the suffix if any.
+                let end = bs.len() - FOOTER_LEN;
+                let expected = u32::from_le_bytes(bs[end..].try_into().unwrap());
+                let mut crc = Crc32c::new();
+                crc.update(base);
+                crc.update(&bs[..end]);
+                (expected, crc.finish(), self.end)
+            }
+            Source::Storage(st) => {
+                let end = self.end;
+                let mut footer = [0u8; FOOTER_LEN];
+                st.read_at(end, &mut footer)?;
+
+                let mut crc = Crc32c::new();
+                let mut buf = vec![0u8; 64 * 1024];
+                let mut off = 0;
+                while off < end {
+                    let n = (end - off).min(buf.len() as Offset) as usize;
+                    st.read_at(off, &mut buf[..n])?;
+                    crc.update(&buf[..n]);
+                    off += n as Offset;
+                }
+                (u32::from_le_bytes(footer), crc.finish(), end)
+            }
+        };
+        if actual != expected {
+            return Err(Error {
+                msg: "checksum mismatch",
+                off: end,
+            });
+        }
+        Ok(())
+    }
+
+    /// The header of the blob, if it has one.
+    #[inline]
+    pub fn header(&self) -> Option<Header> {
+        self.header
+    }
+
+    /// Length of the blob, in bytes.
+    ///
+    /// For a delta, this includes the base blob.
+    #[inline]
+    pub fn len(&self) -> Offset {
+        match self.src {
+            Source::Slice { base, bs, .. } => (base.len() + bs.len()) as Offset,
+            Source::Storage(st) => st.len(),
+        }
+    }
+
+    /// Is the blob empty?
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Does this decode a delta over a base blob?
+    #[inline]
+    pub fn has_base(&self) -> bool {
+        matches!(self.src, Source::Slice { delta: true, .. })
+    }
+
+    /// State of the checksum at the end of the blob, to extend it when appending
+    /// to the blob. `None` if the blob has no checksum, or is a delta.
+    ///
+    /// This resumes from the checksum stored in the footer, so that a blob that
+    /// was corrupted before being extended still fails verification.
+    pub(crate) fn running_checksum(&self) -> Option<Crc32c> {
+        if self.has_base() || !self.header.is_some_and(|h| h.has_flag(FLAG_CHECKSUM)) {
+            return None;
+        }
+        let footer = self.slice(self.end, FOOTER_LEN as u64).ok()?;
+        let mut crc = Crc32c::resume(u32::from_le_bytes(footer.try_into().unwrap()));
+        crc.update(footer);
+        Some(crc)
+    }
+
+    /// Read the byte at the given offset.
+    #[inline]
+    fn byte(&self, off: Offset) -> Result<u8> {
+        let b = match self.src {
+            Source::Slice { base, bs, .. } => {
+                let base_len = base.len() as Offset;
+                if off < base_len {
+                    base.get(off as usize)
+                } else {
+                    bs.get((off - base_len) as usize)
+                }
+            }
+            Source::Storage(st) => return st.byte(off),
+        };
+        b.copied().ok_or(Error {
+            msg: "offset out of bounds",
+            off,
+        })
+    }
+
+    /// Read `len` bytes at the given offset.
+    #[inline]
+    fn slice(&self, off: Offset, len: u64) -> Result<&'a [u8]> {
+        let (base, bs) = match self.src {
+            Source::Slice { base, bs, .. } => (base, bs),
+            Source::Storage(st) => return st.slice(off, len),
+        };
+        let err = Error {
+            msg: "length out of bounds",
+            off,
+        };
+        let end = off.checked_add(len).ok_or(err)?;
+        let base_len = base.len() as Offset;
+        if off >= base_len {
+            bs.get((off - base_len) as usize..(end - base_len) as usize)
+                .ok_or(err)
+        } else if end <= base_len {
+            Ok(&base[off as usize..end as usize])
+        } else {
+            // values never straddle the base and the delta
+            Err(err)
+        }
     }
 
     /// Read (high, low) nibbles at the given offset.
     #[inline]
-    pub(crate) fn first_byte(&self, off: Offset) -> (u8, u8) {
-        let c = self.bs[off as usize];
+    pub(crate) fn first_byte(&self, off: Offset) -> Result<(u8, u8)> {
+        let c = self.byte(off)?;
         let high = c >> 4;
         let low = c & 0xf;
-        (high, low)
+        Ok((high, low))
     }
 
     /// read an integer in LEB128
@@ -48,7 +451,7 @@ impl<'a> Decoder<'a> {
 
         loop {
             n_bytes += 1;
-            let c = self.bs[off as usize];
+            let c = self.byte(off)?;
             off += 1;
             let cur = c & 0x7f;
             res = res | ((cur as u64) << shift);
@@ -86,7 +489,7 @@ impl<'a> Decoder<'a> {
     /// the offset first).
     pub fn deref(&self, mut off: Offset) -> Result<Offset> {
         loop {
-            let (high, low) = self.first_byte(off);
+            let (high, low) = self.first_byte(off)?;
             if high == 15 {
                 let (p, _) = self.u64_with_low(off, low)?;
                 // checked sub
@@ -125,29 +528,26 @@ impl<'a> Decoder<'a> {
     fn str(&'_ self, mut off: Offset, low: u8) -> Result<&'a str> {
         let (len, n_bytes) = self.u64_with_low(off, low)?;
         off = off + 1 + n_bytes;
-        std::str::from_utf8(&self.bs[off as usize..(off as usize + len as usize)]).map_err(|_| {
-            Error {
-                msg: "overflow in string",
-                off,
-            }
+        std::str::from_utf8(self.slice(off, len)?).map_err(|_| Error {
+            msg: "overflow in string",
+            off,
         })
     }
 
     fn bytes(&'_ self, mut off: Offset, low: u8) -> Result<&'a [u8]> {
         let (len, n_bytes) = self.u64_with_low(off, low)?;
         off = off + 1 + n_bytes;
-        Ok(&self.bs[off as usize..(off as usize + len as usize)])
+        self.slice(off, len)
     }
 
     fn float(&'_ self, off: Offset, low: u8) -> Result<f64> {
-        let off1 = (off + 1) as usize;
         if low == 0 {
-            let arr: [u8; 4] = self.bs[off1..off1 + 4].try_into().unwrap();
+            let arr: [u8; 4] = self.slice(off + 1, 4)?.try_into().unwrap();
             let u: u32 = u32::from_le_bytes(arr);
             let f = f32::from_bits(u);
             Ok(f as f64)
         } else if low == 1 {
-            let arr: [u8; 8] = self.bs[off1..off1 + 8].try_into().unwrap();
+            let arr: [u8; 8] = self.slice(off + 1, 8)?.try_into().unwrap();
             let u: u64 = u64::from_le_bytes(arr);
             let f = f64::from_bits(u);
             Ok(f)
@@ -167,35 +567,37 @@ impl<'a> Decoder<'a> {
 
     fn array_cursor(&'_ self, mut off: Offset, low: u8) -> Result<ArrayCursor<'a>> {
         let (len, n_bytes) = self.u64_with_low(off, low)?;
-        if len > u32::MAX as u64 {
+        off = off + 1 + n_bytes;
+        // each item takes at least one byte
+        if len > self.len().saturating_sub(off) {
             return Err(Error {
                 msg: "Size overflow for array",
                 off,
             });
         }
-        off = off + 1 + n_bytes;
         let dec = self.clone();
         Ok(ArrayCursor {
             dec,
             off,
-            n_items: len as u32,
+            n_items: len,
         })
     }
 
     fn map_cursor(&'_ self, mut off: Offset, low: u8) -> Result<MapCursor<'a>> {
         let (len, n_bytes) = self.u64_with_low(off, low)?;
-        if len > u32::MAX as u64 {
+        off = off + 1 + n_bytes;
+        // each pair takes at least two bytes
+        if len > self.len().saturating_sub(off) / 2 {
             return Err(Error {
                 msg: "Size overflow for dict",
                 off,
             });
         }
-        off = off + 1 + n_bytes;
         let dec = self.clone();
         Ok(MapCursor {
             dec,
             off,
-            n_items: len as u32,
+            n_items: len,
         })
     }
 
@@ -240,15 +642,14 @@ impl<'a> Decoder<'a> {
             let (idx, n_bytes_idx) = self.u64_with_low(off, low)?;
             off = off + 1 + n_bytes_idx;
             let (n_items, n_bytes_n_items) = self.leb128(off)?;
-            if n_items > u32::MAX as u64 {
+            off = off + n_bytes_n_items as Offset;
+            if n_items > self.len().saturating_sub(off) {
                 return Err(Error {
                     msg: "overflow in variant arguments",
                     off,
                 });
             }
-            let n_items = n_items as u32;
 
-            off = off + n_bytes_n_items as Offset;
             let arr = ArrayCursor { off, n_items, dec };
             Ok((mk_variant!(idx), arr))
         } else {
@@ -259,9 +660,69 @@ impl<'a> Decoder<'a> {
         }
     }
 
+    /// Check the value that starts at `off`, which must not be nested in another value.
+    ///
+    /// The value must be well-formed, and all the pointers and references in it must
+    /// point into `lower..off`. Returns the offset of the next value.
+    pub(crate) fn check_value(&self, off: Offset, lower: Offset) -> Result<Offset> {
+        let (n_imms, mut cur) = self.value_layout(off)?;
+
+        for _ in 0..n_imms {
+            let (high, low) = self.first_byte(cur)?;
+            if high == 14 || high == 15 {
+                let (p, _) = self.u64_with_low(cur, low)?;
+                let target = cur.checked_sub(p + 1);
+                if !target.is_some_and(|t| t >= lower && t < off) {
+                    return Err(Error {
+                        msg: "pointer out of bounds",
+                        off: cur,
+                    });
+                }
+            }
+            cur = self.skip(cur)?;
+        }
+
+        if cur > self.end {
+            return Err(Error {
+                msg: "value out of bounds",
+                off,
+            });
+        }
+        Ok(cur)
+    }
+
+    /// Layout of the value that starts at `off`, which must not be nested
+    /// in another value: returns the number of immediates it is made of,
+    /// and the offset of the first one.
+    pub(crate) fn value_layout(&self, off: Offset) -> Result<(u64, Offset)> {
+        let (high, low) = self.first_byte(off)?;
+        Ok(match high {
+            6 | 7 => {
+                let (n, n_bytes) = self.u64_with_low(off, low)?;
+                let n = if high == 7 { n.checked_mul(2) } else { Some(n) };
+                let n = n.ok_or(Error {
+                    msg: "length overflow",
+                    off,
+                })?;
+                (n, off + 1 + n_bytes)
+            }
+            8 | 11 => {
+                let (_, n_bytes) = self.u64_with_low(off, low)?;
+                (1, off + 1 + n_bytes)
+            }
+            12 => {
+                let (_, n_bytes) = self.u64_with_low(off, low)?;
+                let (n, n_bytes_n) = self.leb128(off + 1 + n_bytes)?;
+                (n, off + 1 + n_bytes + n_bytes_n as Offset)
+            }
+            // the value is itself an immediate
+            _ => (1, off),
+        })
+    }
+
     /// Skip an immediate value, return offset of next value.
     pub(crate) fn skip(&self, off: Offset) -> Result<Offset> {
-        let (high, low) = self.first_byte(off);
+        let (high, low) = self.first_byte(off)?;
         let off: Offset = match high {
             0 => off + 1,
             1 | 2 => {
@@ -277,13 +738,10 @@ impl<'a> Decoder<'a> {
             }
             4 | 5 => {
                 let (len, n_bytes) = self.u64_with_low(off, low)?;
-                if len > u32::MAX as u64 {
-                    return Err(Error {
-                        msg: "length overflow",
-                        off,
-                    });
-                }
-                off + 1 + n_bytes + len as Offset
+                (off + 1 + n_bytes).checked_add(len).ok_or(Error {
+                    msg: "length overflow",
+                    off,
+                })?
             }
             6 | 7 | 8 => {
                 return Err(Error {
@@ -329,7 +787,7 @@ impl<'a> Decoder<'a> {
         use ShallowValue::*;
 
         off = self.deref(off)?;
-        let (high, low) = self.first_byte(off);
+        let (high, low) = self.first_byte(off)?;
         let v: ShallowValue = match high {
             0 => {
                 if low == 2 {
@@ -447,6 +905,48 @@ impl<'a> Decoder<'a> {
         }
     }
 
+    /// Read a packed array of numbers.
+    ///
+    /// The elements are borrowed directly from the blob if they are suitably
+    /// aligned in memory (which the encoder ensures, provided the blob itself is aligned);
+    /// otherwise they are copied.
+    pub fn get_packed_array<T: PackedElem>(&self, off: Offset) -> Result<Cow<'a, [T]>> {
+        let (tag, off_bytes) = self.get_tag(off)?;
+        if tag != T::TAG {
+            return Err(Error {
+                msg: if packed::is_packed_array_tag(tag) {
+                    "wrong element type for packed array"
+                } else {
+                    "expected packed array"
+                },
+                off,
+            });
+        }
+
+        let bs = self.get_bytes(off_bytes)?;
+        let err = Error {
+            msg: "invalid packed array",
+            off: off_bytes,
+        };
+        let pad = *bs.first().ok_or(err)? as usize;
+        let data = bs.get(1 + pad..).ok_or(err)?;
+        if data.len() % T::SIZE != 0 {
+            return Err(err);
+        }
+        Ok(packed::cast_slice(data))
+    }
+
+    get_packed_array_of!(get_u8_array, u8);
+    get_packed_array_of!(get_u16_array, u16);
+    get_packed_array_of!(get_u32_array, u32);
+    get_packed_array_of!(get_u64_array, u64);
+    get_packed_array_of!(get_i8_array, i8);
+    get_packed_array_of!(get_i16_array, i16);
+    get_packed_array_of!(get_i32_array, i32);
+    get_packed_array_of!(get_i64_array, i64);
+    get_packed_array_of!(get_f32_array, f32);
+    get_packed_array_of!(get_f64_array, f64);
+
     /// Read an array of offsets into `res`
     pub fn get_array(&self, off: Offset, res: &mut Vec<Offset>) -> Result<()> {
         res.clear();
@@ -518,15 +1018,170 @@ impl<'a> Decoder<'a> {
         }
     }
 
+    /// Offsets of the roots map and the metadata map of the root directory.
+    fn root_directory(&self) -> Result<(Offset, Offset)> {
+        let err = Error {
+            msg: "no root directory",
+            off: 0,
+        };
+        let off = self.entrypoint()?;
+        let (tag, off) = match self.get_shallow_value(off)? {
+            ShallowValue::Tag(tag, off) => (tag, off),
+            _ => return Err(Error { off, ..err }),
+        };
+        if tag != TAG_ROOT_DIRECTORY {
+            return Err(Error { off, ..err });
+        }
+
+        let mut arr = match self.get_shallow_value(off)? {
+            ShallowValue::Array(arr) if arr.len() == 2 => arr,
+            _ => {
+                return Err(Error {
+                    msg: "invalid root directory",
+                    off,
+                })
+            }
+        };
+        let roots = arr.next().unwrap()?;
+        let metadata = arr.next().unwrap()?;
+        Ok((roots, metadata))
+    }
+
+    /// Read a map with string keys, dereferencing its values.
+    fn string_map(&self, off: Offset) -> Result<Vec<(&'a str, Offset)>> {
+        match self.get_shallow_value(off)? {
+            ShallowValue::Map(map) => {
+                let mut res = Vec::with_capacity(map.len_usize()?);
+                for pair in map {
+                    let (k, v) = pair?;
+                    res.push((self.get_str(k)?, self.deref(v)?));
+                }
+                Ok(res)
+            }
+            _ => Err(Error {
+                msg: "expected dict",
+                off,
+            }),
+        }
+    }
+
+    /// The named roots of the blob, in the order in which they were written.
+    ///
+    /// This fails if the blob has no root directory (see [`crate::roots`]).
+    pub fn roots(&self) -> Result<Vec<(&'a str, Offset)>> {
+        let (roots, _) = self.root_directory()?;
+        self.string_map(roots)
+    }
+
+    /// Find the root named `name`, if any.
+    ///
+    /// This fails if the blob has no root directory (see [`crate::roots`]).
+    pub fn root(&self, name: &str) -> Result<Option<Offset>> {
+        let (roots, _) = self.root_directory()?;
+        match self.get_shallow_value(roots)? {
+            ShallowValue::Map(map) => {
+                for pair in map {
+                    let (k, v) = pair?;
+                    if self.get_str(k)? == name {
+                        return Ok(Some(self.deref(v)?));
+                    }
+                }
+                Ok(None)
+            }
+            _ => Err(Error {
+                msg: "expected dict",
+                off: roots,
+            }),
+        }
+    }
+
+    /// The metadata stored in the root directory.
+    ///
+    /// This fails if the blob has no root directory (see [`crate::roots`]).
+    pub fn metadata(&self) -> Result<Vec<(&'a str, Offset)>> {
+        let (_, metadata) = self.root_directory()?;
+        self.string_map(metadata)
+    }
+
     /// Find the entrypoint.
     ///
     /// A twine blob is terminated with a postfix (in essence, a pointer to the actual
     /// toplevel value). This reads the postfix and returns the offset of the toplevel value.
+    ///
+    /// If the blob has a version history (see [`crate::versions`]), this
+    /// returns the most recent version.
     pub fn entrypoint(&self) -> Result<Offset> {
-        let last = self.bs.len() as Offset - 1;
-        let off = last - self.bs[last as usize] as Offset - 1;
+        let off = self.raw_entrypoint()?;
+        match self.version_record(off)? {
+            Some((root, _)) => Ok(root),
+            None => Ok(off),
+        }
+    }
+
+    /// The value the postfix points to.
+    pub(crate) fn raw_entrypoint(&self) -> Result<Offset> {
+        let err = Error {
+            msg: "invalid postfix",
+            off: self.end,
+        };
+        let last = self.end.checked_sub(1).ok_or(err)?;
+        let off = last
+            .checked_sub(self.byte(last)? as Offset + 1)
+            .ok_or(err)?;
         self.deref(off)
     }
+
+    /// If `off` is a version record, return the root of this version
+    /// and the previous entrypoint, if any.
+    pub(crate) fn version_record(&self, off: Offset) -> Result<Option<(Offset, Option<Offset>)>> {
+        let off = match self.get_shallow_value(off)? {
+            ShallowValue::Tag(TAG_VERSION_RECORD, off) => off,
+            _ => return Ok(None),
+        };
+
+        let err = Error {
+            msg: "invalid version record",
+            off,
+        };
+        let mut arr = match self.get_shallow_value(off)? {
+            ShallowValue::Array(arr) if arr.len() == 2 => arr,
+            _ => return Err(err),
+        };
+        let root = self.deref(arr.next().unwrap()?)?;
+        let prev = arr.next().unwrap()?;
+        let prev = match self.get_shallow_value(prev)? {
+            ShallowValue::Imm(Immediate::Null) => None,
+            _ => Some(self.deref(prev)?),
+        };
+        if prev.is_some_and(|p| p >= off) {
+            return Err(err);
+        }
+        Ok(Some((root, prev)))
+    }
+
+    /// All the versions of the blob, oldest first.
+    ///
+    /// Each version is given by the offset of its toplevel value, so the
+    /// document as of version `n` is at `dec.versions()?[n]`. A blob without version
+    /// history has a single version, its entrypoint. See [`crate::versions`].
+    pub fn versions(&self) -> Result<Vec<Offset>> {
+        let mut res = vec![];
+        let mut cur = Some(self.raw_entrypoint()?);
+        while let Some(off) = cur {
+            match self.version_record(off)? {
+                Some((root, prev)) => {
+                    res.push(root);
+                    cur = prev;
+                }
+                None => {
+                    res.push(off);
+                    cur = None;
+                }
+            }
+        }
+        res.reverse();
+        Ok(res)
+    }
 }
 
 #[cfg(test)]
@@ -553,10 +1208,86 @@ mod tests {
             let mut ref_v = vec![];
             let ref_len = leb128::write::unsigned( &mut ref_v,n).unwrap();
 
-            let dec = Decoder::new(&ref_v).unwrap();
+            // not a valid blob, it might look like a header.
+            let dec = Decoder {
+                src: Source::Slice { base: &[], bs: &ref_v, delta: false },
+                header: None,
+                end: ref_v.len() as Offset,
+            };
             let (n2, len) = dec.leb128(0).unwrap();
             assert_eq!(n2, n);
             assert_eq!(ref_len, len as usize);
         }
     }
+
+    #[test]
+    fn test_delta() {
+        use crate::{header::Header, value, Encoder};
+
+        let words = [
+            "a rather long string",
+            "another long string",
+            "yet another one",
+        ];
+        let mut base: Vec<u8> = vec![];
+        let mut enc = Encoder::with_header(&mut base, Header::with_flags(FLAG_CHECKSUM)).unwrap();
+        let mut dict = vec![];
+        for w in words {
+            dict.push(Immediate::Pointer(enc.write_string(w).unwrap()));
+        }
+        let dict = enc.write_array(&dict).unwrap();
+        enc.finalize(Immediate::Pointer(dict)).unwrap();
+
+        let base_dec = Decoder::new(&base).unwrap();
+        let mut offs = vec![];
+        base_dec.get_array(dict, &mut offs).unwrap();
+
+        let mut delta: Vec<u8> = vec![];
+        let mut enc = Encoder::with_base(&base_dec, &mut delta);
+        let own = enc.write_string("not in the dictionary").unwrap();
+        let msg = enc
+            .write_array(&[
+                Immediate::Pointer(offs[2]),
+                Immediate::Pointer(own),
+                Immediate::Ref(offs[0]),
+                Immediate::Pointer(offs[2]),
+            ])
+            .unwrap();
+        enc.finalize(Immediate::Pointer(msg)).unwrap();
+        assert!(delta.len() < 40);
+
+        let dec = Decoder::with_base(&base_dec, &delta).unwrap();
+        assert!(dec.has_base());
+        assert_eq!(dec.len(), (base.len() + delta.len()) as Offset);
+        assert_eq!(dec.entrypoint().unwrap(), msg);
+        dec.verify_checksum().unwrap();
+        let v = value::read_value(&dec, msg).unwrap();
+        assert_eq!(
+            v,
+            value::Value::Array(vec![
+                value::Value::String(words[2].to_string()),
+                value::Value::String("not in the dictionary".to_string()),
+                value::Value::Ref(offs[0]),
+                value::Value::String(words[2].to_string()),
+            ])
+        );
+        assert_eq!(dec.get_str(offs[0]).unwrap(), words[0]);
+
+        // the delta can be extended in turn
+        let mut suffix = vec![];
+        let mut enc = Encoder::append_to_blob(&dec, &mut suffix);
+        let arr = enc
+            .write_array(&[Immediate::Pointer(msg), Immediate::Pointer(offs[1])])
+            .unwrap();
+        enc.finalize(Immediate::Pointer(arr)).unwrap();
+        delta.extend_from_slice(&suffix);
+        let dec = Decoder::with_base(&base_dec, &delta).unwrap();
+        let mut items = vec![];
+        dec.get_array(dec.entrypoint().unwrap(), &mut items)
+            .unwrap();
+        assert_eq!(dec.get_str(items[1]).unwrap(), words[1]);
+
+        assert!(Decoder::with_base(&dec, &suffix).is_err());
+        assert!(Decoder::with_base(&base_dec, &[]).is_err());
+    }
 }
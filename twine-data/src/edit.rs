@@ -0,0 +1,287 @@
+//! Path-copying updates.
+//!
+//! Since all pointers go backwards, a blob can be extended by appending
+//! new values after it (see [`Encoder::append_to_blob`]). To update a value
+//! deep inside a document, it suffices to append the new value, and new
+//! copies of the arrays, maps, and variants on the path from the entrypoint
+//! to it; unchanged siblings are reused via pointers. A new postfix then makes the
+//! new version of the document the entrypoint, while the old version
+//! remains readable at its old offset.
+
+use std::io;
+
+use crate::{
+    shallow_value::ShallowValue,
+    types::{Error, Offset},
+    value::{self, Value},
+    Decoder, Encoder, Immediate,
+};
+
+/// One step in a path from a value to one of its sub-values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathElem<'p> {
+    /// Index in an array, or argument of a variant.
+    Index(usize),
+    /// Key in a map (only string keys are supported).
+    Key(&'p str),
+}
+
+/// Immediate that refers to the (immediate) value at offset `off`, without copying
+/// it if it's large.
+pub(crate) fn reuse_immediate<'a>(dec: &Decoder<'a>, off: Offset) -> crate::Result<Immediate<'a>> {
+    let (high, _) = dec.first_byte(off)?;
+    if high == 15 {
+        return Ok(Immediate::Pointer(dec.deref(off)?));
+    }
+    let imm = match dec.get_shallow_value(off)? {
+        ShallowValue::Imm(Immediate::String(_) | Immediate::Bytes(_)) => Immediate::Pointer(off),
+        ShallowValue::Imm(imm) => imm,
+        _ => Immediate::Pointer(off),
+    };
+    Ok(imm)
+}
+
+fn path_error(msg: &'static str, off: Offset) -> io::Error {
+    Error { msg, off }.into()
+}
+
+fn edit_rec<'a, W: io::Write>(
+    enc: &mut Encoder<W>,
+    dec: &Decoder<'a>,
+    off: Offset,
+    path: &[PathElem<'a>],
+    new_value: &'a Value,
+) -> io::Result<Immediate<'a>> {
+    let Some((step, rest)) = path.split_first() else {
+        return value::write_value_or_imm(enc, new_value);
+    };
+
+    let imm = match (dec.get_shallow_value(off)?, step) {
+        (ShallowValue::Tag(tag, inner), _) => {
+            // tags are transparent: follow the same path inside.
+            let v = edit_rec(enc, dec, inner, path, new_value)?;
+            Immediate::Pointer(enc.write_tag(tag, v)?)
+        }
+        (ShallowValue::Array(arr), PathElem::Index(i)) => {
+            let children = arr.collect::<crate::Result<Vec<_>>>()?;
+            let items = edit_children(enc, dec, off, &children, *i, rest, new_value)?;
+            Immediate::Pointer(enc.write_array(&items)?)
+        }
+        (ShallowValue::Variant(c, args), PathElem::Index(i)) => {
+            let children = args.collect::<crate::Result<Vec<_>>>()?;
+            let items = edit_children(enc, dec, off, &children, *i, rest, new_value)?;
+            enc.write_variant(c, &items)?
+        }
+        (ShallowValue::Map(map), PathElem::Key(key)) => {
+            let pairs = map.collect::<crate::Result<Vec<_>>>()?;
+            let mut found = None;
+            for (i, (k, _)) in pairs.iter().enumerate() {
+                if let ShallowValue::Imm(Immediate::String(s)) = dec.get_shallow_value(*k)? {
+                    if s == *key {
+                        found = Some(i);
+                        break;
+                    }
+                }
+            }
+
+            let new_v = match found {
+                Some(i) => Some(edit_rec(enc, dec, pairs[i].1, rest, new_value)?),
+                None if rest.is_empty() => None,
+                None => return Err(path_error("key not found", off)),
+            };
+
+            let mut items = Vec::with_capacity(pairs.len() + 1);
+            for (i, (k, v)) in pairs.iter().enumerate() {
+                let k = reuse_immediate(dec, *k)?;
+                let v = match (found, new_v) {
+                    (Some(j), Some(new_v)) if i == j => new_v,
+                    _ => reuse_immediate(dec, *v)?,
+                };
+                items.push((k, v));
+            }
+            if found.is_none() {
+                // insert the new key
+                let v = value::write_value_or_imm(enc, new_value)?;
+                items.push((Immediate::String(key), v));
+            }
+            Immediate::Pointer(enc.write_map(&items)?)
+        }
+        (ShallowValue::Imm(_), _) => {
+            return Err(path_error("cannot follow path into immediate", off))
+        }
+        _ => return Err(path_error("path does not match value", off)),
+    };
+    Ok(imm)
+}
+
+fn edit_children<'a, W: io::Write>(
+    enc: &mut Encoder<W>,
+    dec: &Decoder<'a>,
+    off: Offset,
+    children: &[Offset],
+    i: usize,
+    path: &[PathElem<'a>],
+    new_value: &'a Value,
+) -> io::Result<Vec<Immediate<'a>>> {
+    let Some(&child) = children.get(i) else {
+        return Err(path_error("index out of bounds", off));
+    };
+    let new_child = edit_rec(enc, dec, child, path, new_value)?;
+
+    let mut items = Vec::with_capacity(children.len());
+    for (j, c) in children.iter().enumerate() {
+        if i == j {
+            items.push(new_child)
+        } else {
+            items.push(reuse_immediate(dec, *c)?)
+        }
+    }
+    Ok(items)
+}
+
+/// Replace the value at `path` (starting from `off`) with `new_value`, appending
+/// the new value and the modified spine to `enc`.
+///
+/// The encoder must append to the blob read by `dec`. Returns the offset
+/// of the new version of the value at `off`.
+/// If the last step of the path is a key that is not present in its map,
+/// it is inserted.
+pub fn edit_in<W: io::Write>(
+    enc: &mut Encoder<W>,
+    dec: &Decoder,
+    off: Offset,
+    path: &[PathElem],
+    new_value: &Value,
+) -> io::Result<Offset> {
+    let imm = edit_rec(enc, dec, off, path, new_value)?;
+    enc.write_immediate_or_return_pointer(imm)
+}
+
+/// Replace the value at `path` (starting from the entrypoint) with `new_value`.
+///
+/// `w` must append to the blob read by `dec`. This writes the modified spine
+/// and a fresh postfix, and returns the new entrypoint. The previous version
+/// is still readable at the previous entrypoint.
+pub fn edit<W: io::Write>(
+    dec: &Decoder,
+    path: &[PathElem],
+    new_value: &Value,
+    w: W,
+) -> io::Result<Offset> {
+    let mut enc = Encoder::append_to_blob(dec, w);
+    let root = edit_in(&mut enc, dec, dec.entrypoint()?, path, new_value)?;
+    enc.finalize(Immediate::Pointer(root))?;
+    Ok(root)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn s(x: &str) -> Value {
+        Value::String(x.to_string())
+    }
+
+    fn doc() -> Value {
+        Value::Map(vec![
+            (s("title"), s("some document with a long title")),
+            (
+                s("users"),
+                Value::Array(vec![
+                    Value::Map(vec![(s("name"), s("alice")), (s("age"), Value::Int64(30))]),
+                    Value::Tag(
+                        42,
+                        Box::new(Value::Map(vec![
+                            (s("name"), s("bob")),
+                            (s("age"), Value::Int64(25)),
+                        ])),
+                    ),
+                ]),
+            ),
+        ])
+    }
+
+    #[test]
+    fn test_edit() {
+        let mut blob: Vec<u8> = vec![];
+        let mut enc = Encoder::new(&mut blob);
+        let root = value::write_value(&mut enc, &doc()).unwrap();
+        enc.finalize(Immediate::Pointer(root)).unwrap();
+        let len_v1 = blob.len();
+
+        let mut suffix = vec![];
+        let dec = Decoder::new(&blob).unwrap();
+        let path = [
+            PathElem::Key("users"),
+            PathElem::Index(1),
+            PathElem::Key("name"),
+        ];
+        let root2 = edit(&dec, &path, &s("robert"), &mut suffix).unwrap();
+        assert!(suffix.len() < len_v1);
+        blob.extend_from_slice(&suffix);
+
+        let mut expected = doc();
+        let Value::Map(m) = &mut expected else {
+            unreachable!()
+        };
+        let Value::Array(users) = &mut m[1].1 else {
+            unreachable!()
+        };
+        let Value::Tag(_, bob) = &mut users[1] else {
+            unreachable!()
+        };
+        let Value::Map(bob) = &mut **bob else {
+            unreachable!()
+        };
+        bob[0].1 = s("robert");
+
+        let dec = Decoder::new(&blob).unwrap();
+        assert_eq!(dec.entrypoint().unwrap(), root2);
+        assert_eq!(value::read_value(&dec, root2).unwrap(), expected);
+        // old version is still there
+        assert_eq!(value::read_value(&dec, root).unwrap(), doc());
+
+        // insert a new key
+        let mut suffix = vec![];
+        let path = [
+            PathElem::Key("users"),
+            PathElem::Index(0),
+            PathElem::Key("email"),
+        ];
+        let root3 = edit(&dec, &path, &s("a@example.com"), &mut suffix).unwrap();
+        blob.extend_from_slice(&suffix);
+
+        let Value::Map(m) = &mut expected else {
+            unreachable!()
+        };
+        let Value::Array(users) = &mut m[1].1 else {
+            unreachable!()
+        };
+        let Value::Map(alice) = &mut users[0] else {
+            unreachable!()
+        };
+        alice.push((s("email"), s("a@example.com")));
+
+        let dec = Decoder::new(&blob).unwrap();
+        assert_eq!(value::read_value(&dec, root3).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_edit_bad_path() {
+        let mut blob: Vec<u8> = vec![];
+        let mut enc = Encoder::new(&mut blob);
+        let root = value::write_value(&mut enc, &doc()).unwrap();
+        enc.finalize(Immediate::Pointer(root)).unwrap();
+        let dec = Decoder::new(&blob).unwrap();
+
+        for path in [
+            &[PathElem::Key("users"), PathElem::Index(2)][..],
+            &[PathElem::Key("nope"), PathElem::Index(0)][..],
+            &[PathElem::Index(0)][..],
+            &[PathElem::Key("title"), PathElem::Index(0)][..],
+        ] {
+            let mut suffix = vec![];
+            assert!(edit(&dec, path, &Value::Null, &mut suffix).is_err());
+        }
+    }
+}
@@ -0,0 +1,178 @@
+//! Relocatable fragments.
+//!
+//! Pointers and references in twine are relative to their own position.
+//! As a consequence, a sequence of values encoded in its own buffer, and that
+//! only points to values within this buffer, can be copied verbatim into another
+//! blob at any offset. A [`Fragment`] is such a self-contained sequence of values;
+//! it can be encoded once (eg. for a commonly used sub-document) and then pasted into
+//! many encoders with [`crate::Encoder::splice`], without re-encoding.
+//!
+//! Packed arrays (see [`crate::packed`]) in a fragment might not be aligned anymore
+//! after splicing, in which case reading them falls back to copying.
+
+use std::io;
+
+use crate::{
+    types::{Error, Offset, Result},
+    Decoder, Encoder, Immediate,
+};
+
+/// A self-contained sequence of encoded values, with a root value.
+///
+/// Fragments can only be created by checking that they are self-contained,
+/// so a fragment is always safe to splice into an encoder.
+#[derive(Debug, Clone)]
+pub struct Fragment {
+    bytes: Vec<u8>,
+    root: Offset,
+}
+
+impl Fragment {
+    /// Check that `bytes` is a sequence of values that only point to each other,
+    /// and that `root` is one of these values.
+    ///
+    /// `bytes` must not contain a header or a postfix.
+    pub fn new(bytes: Vec<u8>, root: Offset) -> Result<Self> {
+        let dec = Decoder::new(&bytes)?;
+        if dec.header().is_some() {
+            return Err(Error {
+                msg: "fragment cannot have a header",
+                off: 0,
+            });
+        }
+
+        let mut off = 0;
+        let mut found_root = false;
+        while off < bytes.len() as Offset {
+            found_root = found_root || off == root;
+            off = dec.check_value(off, 0)?;
+        }
+
+        if !found_root {
+            return Err(Error {
+                msg: "fragment root is not a value",
+                off: root,
+            });
+        }
+        Ok(Fragment { bytes, root })
+    }
+
+    /// Build a fragment by encoding values into a fresh encoder.
+    ///
+    /// `f` returns the root of the fragment.
+    pub fn build<F>(f: F) -> io::Result<Self>
+    where
+        F: for<'e> FnOnce(&mut Encoder<&'e mut Vec<u8>>) -> io::Result<Immediate<'static>>,
+    {
+        let mut bytes = vec![];
+        let mut enc = Encoder::new(&mut bytes);
+        let root = f(&mut enc)?;
+        let root = enc.write_immediate_or_return_pointer(root)?;
+        Ok(Self::new(bytes, root)?)
+    }
+
+    /// The encoded values.
+    #[inline]
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Offset of the root value, relative to the beginning of the fragment.
+    #[inline]
+    pub fn root(&self) -> Offset {
+        self.root
+    }
+
+    /// A decoder to read the fragment's values.
+    pub fn decoder(&self) -> Decoder<'_> {
+        Decoder::new(&self.bytes).expect("fragment was checked")
+    }
+
+    /// Give back the encoded values.
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::value::{self, Value};
+
+    fn common() -> Value {
+        Value::Map(vec![
+            (
+                Value::String("license".to_string()),
+                Value::String("MIT".to_string()),
+            ),
+            (
+                Value::String("tags".to_string()),
+                Value::Array(vec![Value::Int64(1), Value::Int64(2)]),
+            ),
+        ])
+    }
+
+    #[test]
+    fn test_splice() {
+        let frag = Fragment::build(|enc| {
+            let s = enc.write_string("shared")?;
+            let v = value::write_value(enc, &common())?;
+            let arr = enc.write_array(&[Immediate::Ref(s), Immediate::Pointer(v)])?;
+            Ok(Immediate::Pointer(arr))
+        })
+        .unwrap();
+        let Value::Array(items) = value::read_value(&frag.decoder(), frag.root()).unwrap() else {
+            panic!()
+        };
+        assert_eq!(items[1], common());
+
+        let mut res: Vec<u8> = vec![];
+        let mut enc = Encoder::new(&mut res);
+        let _ = enc.write_string("some prefix").unwrap();
+        let a = enc.splice(&frag).unwrap();
+        let _ = enc.write_i64(42).unwrap();
+        let b = enc.splice(&frag).unwrap();
+        let top = enc.write_array(&[a, b]).unwrap();
+        enc.finalize(Immediate::Pointer(top)).unwrap();
+
+        let dec = Decoder::new(&res).unwrap();
+        let v = value::read_value_from_entrypoint(&dec).unwrap();
+        let Value::Array(items) = v else { panic!() };
+        assert_eq!(items.len(), 2);
+        assert_ne!(a, b);
+        for item in items {
+            let Value::Array(item) = item else { panic!() };
+            assert_eq!(item[1], common());
+            let Value::Ref(r) = item[0] else { panic!() };
+            assert_eq!(dec.get_str(r).unwrap(), "shared");
+        }
+    }
+
+    #[test]
+    fn test_not_self_contained() {
+        let mut bytes: Vec<u8> = vec![];
+        let mut enc = Encoder::append_to(100, &mut bytes);
+        let _ = enc.write_string("x").unwrap();
+        let arr = enc
+            .write_array(&[Immediate::Pointer(10), Immediate::Pointer(100)])
+            .unwrap();
+        let arr = arr - 100;
+        assert_eq!(
+            Fragment::new(bytes.clone(), arr).unwrap_err().msg,
+            "pointer out of bounds"
+        );
+        // truncated
+        assert!(Fragment::new(bytes[..3].to_vec(), 0).is_err());
+
+        let mut bytes: Vec<u8> = vec![];
+        let mut enc = Encoder::new(&mut bytes);
+        let _ = enc.write_string("x").unwrap();
+        let _ = enc.write_string("y").unwrap();
+        assert!(Fragment::new(bytes.clone(), 0).is_ok());
+        assert!(Fragment::new(bytes.clone(), 2).is_ok());
+        assert_eq!(
+            Fragment::new(bytes, 1).unwrap_err().msg,
+            "fragment root is not a value"
+        );
+    }
+}
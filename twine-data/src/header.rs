@@ -0,0 +1,164 @@
+//! Blob header.
+//!
+//! A twine blob can optionally start with a fixed-size header, which makes it
+//! possible to recognize twine files and to evolve the format.
+//! The header is [`HEADER_LEN`] bytes long:
+//! - the magic bytes [`MAGIC`];
+//! - a version byte;
+//! - a flags byte.
+//!
+//! The first magic byte uses a reserved tag (13), so that a headerless
+//! blob can never be mistaken for one with a header.
+
+use crate::types::{Error, Offset, Result};
+
+/// Magic bytes at the beginning of a blob with a header.
+pub const MAGIC: [u8; 6] = *b"\xd9twine";
+
+/// Length of the header, in bytes.
+pub const HEADER_LEN: usize = 8;
+
+/// Current (and most recent supported) version of the format.
+pub const VERSION: u8 = 1;
+
+/// Flag: the blob ends with a checksum footer (see [`crate::checksum`]).
+pub const FLAG_CHECKSUM: u8 = 1;
+
+/// Mask of all the flags this version of the library knows about.
+pub const KNOWN_FLAGS: u8 = FLAG_CHECKSUM;
+
+/// Header of a twine blob.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Header {
+    /// Version of the format.
+    pub version: u8,
+    /// Flags describing optional features used in the blob.
+    pub flags: u8,
+}
+
+impl Default for Header {
+    fn default() -> Self {
+        Header {
+            version: VERSION,
+            flags: 0,
+        }
+    }
+}
+
+impl Header {
+    /// Header for the current version, with the given flags.
+    pub fn with_flags(flags: u8) -> Self {
+        Header {
+            version: VERSION,
+            flags,
+        }
+    }
+
+    /// Is the given flag set?
+    #[inline]
+    pub fn has_flag(&self, flag: u8) -> bool {
+        self.flags & flag != 0
+    }
+
+    /// Serialize the header.
+    pub fn to_bytes(&self) -> [u8; HEADER_LEN] {
+        let mut buf = [0u8; HEADER_LEN];
+        buf[..MAGIC.len()].copy_from_slice(&MAGIC);
+        buf[MAGIC.len()] = self.version;
+        buf[MAGIC.len() + 1] = self.flags;
+        buf
+    }
+
+    /// Read the header at the beginning of `bs`, if present.
+    ///
+    /// Returns `Ok(None)` for a headerless blob, and fails if the header
+    /// is invalid or uses features we do not support.
+    pub fn parse(bs: &[u8]) -> Result<Option<Header>> {
+        if bs.first() != Some(&MAGIC[0]) {
+            return Ok(None);
+        }
+
+        if bs.len() < HEADER_LEN || bs[..MAGIC.len()] != MAGIC {
+            return Err(Error {
+                msg: "invalid header",
+                off: 0,
+            });
+        }
+
+        let h = Header {
+            version: bs[MAGIC.len()],
+            flags: bs[MAGIC.len() + 1],
+        };
+        if h.version == 0 || h.version > VERSION {
+            return Err(Error {
+                msg: "unsupported format version",
+                off: MAGIC.len() as Offset,
+            });
+        }
+        if h.flags & !KNOWN_FLAGS != 0 {
+            return Err(Error {
+                msg: "unknown header flags",
+                off: MAGIC.len() as Offset + 1,
+            });
+        }
+        Ok(Some(h))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{value, Decoder, Encoder, Immediate};
+
+    #[test]
+    fn test_header() {
+        let mut res: Vec<u8> = vec![];
+        let mut enc = Encoder::with_header(&mut res, Header::default()).unwrap();
+        let off = enc.write_string("hello").unwrap();
+        enc.finalize(Immediate::Pointer(off)).unwrap();
+        assert_eq!(&res[..HEADER_LEN], &Header::default().to_bytes());
+
+        let dec = Decoder::new(&res).unwrap();
+        assert_eq!(dec.header(), Some(Header::default()));
+        assert_eq!(off, HEADER_LEN as Offset);
+        assert_eq!(
+            value::read_value_from_entrypoint(&dec).unwrap(),
+            value::Value::String("hello".to_string())
+        );
+    }
+
+    #[test]
+    fn test_headerless() {
+        let mut res: Vec<u8> = vec![];
+        let mut enc = Encoder::new(&mut res);
+        let off = enc.write_i64(42).unwrap();
+        enc.finalize(Immediate::Pointer(off)).unwrap();
+
+        let dec = Decoder::new(&res).unwrap();
+        assert_eq!(dec.header(), None);
+        assert_eq!(dec.get_i64(dec.entrypoint().unwrap()).unwrap(), 42);
+    }
+
+    #[test]
+    fn test_bad_header() {
+        let mut bs = Header::default().to_bytes().to_vec();
+        bs.push(0x02);
+        bs.push(0);
+
+        bs[1] = b'x';
+        assert_eq!(Decoder::new(&bs).unwrap_err().msg, "invalid header");
+
+        bs[1] = MAGIC[1];
+        bs[MAGIC.len()] = VERSION + 1;
+        assert_eq!(
+            Decoder::new(&bs).unwrap_err().msg,
+            "unsupported format version"
+        );
+
+        bs[MAGIC.len()] = VERSION;
+        bs[MAGIC.len() + 1] = 0x80 | FLAG_CHECKSUM;
+        assert_eq!(Decoder::new(&bs).unwrap_err().msg, "unknown header flags");
+
+        assert_eq!(Decoder::new(&bs[..3]).unwrap_err().msg, "invalid header");
+    }
+}
@@ -0,0 +1,968 @@
+//! A persistent key-value store.
+//!
+//! [`KvStore`] is a copy-on-write B-tree stored in a single twine file.
+//! Keys are scalar immediates (null, booleans, integers, floats, strings,
+//! byte strings, and variants without arguments), ordered by [`cmp_keys`];
+//! values are arbitrary twine values.
+//!
+//! Nodes are never modified in place: an update appends new copies of the nodes on the
+//! path from the root to the modified leaf, which point to the unchanged nodes.
+//! [`KvStore::commit`] appends these new nodes to the file, followed by a commit
+//! record tagged with [`TAG_KV_COMMIT`] that points to the new root, and by a new postfix
+//! and checksum footer, so that the switch to the new version of the tree is atomic.
+//! Old nodes become garbage, which [`KvStore::compact`] removes.
+//!
+//! The footer of each commit is the CRC32C checksum of the whole file before it
+//! (see [`FLAG_CHECKSUM`]). A crash while committing can leave a partial commit at
+//! the end of the file; when the store is opened, the file is truncated to the
+//! last complete commit. A commit record also contains the length of the file before
+//! the commit, so that a complete commit can be checked on its own: if one is found
+//! after a commit whose checksum doesn't match, the file was corrupted before its
+//! end, and opening the store fails without truncating anything.
+//!
+//! Each node is a map tagged with [`TAG_KV_LEAF`] (from keys to values) or
+//! [`TAG_KV_BRANCH`] (from the smallest key of each child to a pointer to the child).
+//! Nodes that become empty are removed, but nodes are otherwise not rebalanced after
+//! deletions; compaction rebuilds a balanced tree.
+//!
+//! The content of the file is kept in memory; the file provides durability.
+
+use std::{
+    cmp::Ordering,
+    fs::File,
+    io::{self, Read, Seek, SeekFrom, Write},
+    ops::Bound,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    checksum::{Crc32c, FOOTER_LEN},
+    compact::CompactStats,
+    copy::CopyTable,
+    edit::reuse_immediate,
+    header::{Header, FLAG_CHECKSUM, HEADER_LEN},
+    shallow_value::ShallowValue,
+    types::{Error, Offset, Tag},
+    value::{self, Value},
+    Decoder, Encoder, Immediate,
+};
+
+/// Tag of a leaf node.
+pub const TAG_KV_LEAF: Tag = 0x7477_0003;
+
+/// Tag of a branch node.
+pub const TAG_KV_BRANCH: Tag = 0x7477_0004;
+
+/// Tag of a commit record: an array of the length of the file before the commit,
+/// and of a pointer to the root node.
+pub const TAG_KV_COMMIT: Tag = 0x7477_0006;
+
+/// Maximum number of entries in a node.
+pub const MAX_NODE_LEN: usize = 32;
+
+fn kind_rank(v: &Value) -> u8 {
+    match v {
+        Value::Null => 0,
+        Value::Bool(_) => 1,
+        Value::Int64(_) => 2,
+        Value::Float(_) => 3,
+        Value::String(_) => 4,
+        Value::Bytes(_) => 5,
+        Value::Variant0(_) => 6,
+        _ => 7,
+    }
+}
+
+/// Order of keys in the store.
+///
+/// Keys of different kinds are ordered by kind: null, then booleans, integers,
+/// floats, strings, byte strings, and variants. Floats are compared with
+/// [`f64::total_cmp`].
+pub fn cmp_keys(a: &Value, b: &Value) -> Ordering {
+    match (a, b) {
+        (Value::Bool(x), Value::Bool(y)) => x.cmp(y),
+        (Value::Int64(x), Value::Int64(y)) => x.cmp(y),
+        (Value::Float(x), Value::Float(y)) => x.total_cmp(y),
+        (Value::String(x), Value::String(y)) => x.cmp(y),
+        (Value::Bytes(x), Value::Bytes(y)) => x.cmp(y),
+        (Value::Variant0(x), Value::Variant0(y)) => x.cmp(y),
+        _ => kind_rank(a).cmp(&kind_rank(b)),
+    }
+}
+
+fn key_of(imm: Immediate) -> io::Result<Value> {
+    match imm {
+        Immediate::Ref(_) | Immediate::Pointer(_) => {
+            Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid key"))
+        }
+        _ => Ok(Value::from(imm)),
+    }
+}
+
+fn invalid_node(off: Offset) -> io::Error {
+    Error {
+        msg: "invalid kv node",
+        off,
+    }
+    .into()
+}
+
+/// A node, as read from the blob.
+#[derive(Debug)]
+struct Node {
+    leaf: bool,
+    /// For leaves, keys and offsets of values; for branches,
+    /// smallest keys and offsets of children.
+    entries: Vec<(Value, Offset)>,
+}
+
+fn read_node(dec: &Decoder, off: Offset) -> io::Result<Node> {
+    let ShallowValue::Tag(tag @ (TAG_KV_LEAF | TAG_KV_BRANCH), inner) =
+        dec.get_shallow_value(off)?
+    else {
+        return Err(invalid_node(off));
+    };
+    let ShallowValue::Map(map) = dec.get_shallow_value(inner)? else {
+        return Err(invalid_node(off));
+    };
+    let leaf = tag == TAG_KV_LEAF;
+
+    let mut entries = Vec::with_capacity(map.len_usize()?);
+    for kv in map {
+        let (k, v) = kv?;
+        let ShallowValue::Imm(k) = dec.get_shallow_value(k)? else {
+            return Err(invalid_node(k));
+        };
+        let v = if leaf { v } else { dec.deref(v)? };
+        entries.push((key_of(k)?, v));
+    }
+    Ok(Node { leaf, entries })
+}
+
+/// Index of the child of a branch that may contain `key`.
+fn child_index(entries: &[(Value, Offset)], key: &Value) -> usize {
+    entries
+        .partition_point(|(k, _)| cmp_keys(k, key) != Ordering::Greater)
+        .saturating_sub(1)
+}
+
+fn write_leaf<W: Write>(enc: &mut Encoder<W>, entries: &[(Value, Value)]) -> io::Result<Offset> {
+    let mut imms = Vec::with_capacity(entries.len());
+    for (k, v) in entries {
+        imms.push((
+            value::write_value_or_imm(enc, k)?,
+            value::write_value_or_imm(enc, v)?,
+        ));
+    }
+    let map = enc.write_map(&imms)?;
+    enc.write_tag(TAG_KV_LEAF, Immediate::Pointer(map))
+}
+
+fn write_branch<W: Write>(enc: &mut Encoder<W>, entries: &[(Value, Offset)]) -> io::Result<Offset> {
+    let mut imms = Vec::with_capacity(entries.len());
+    for (k, child) in entries {
+        imms.push((
+            value::write_value_or_imm(enc, k)?,
+            Immediate::Pointer(*child),
+        ));
+    }
+    let map = enc.write_map(&imms)?;
+    enc.write_tag(TAG_KV_BRANCH, Immediate::Pointer(map))
+}
+
+/// Write `entries` into as few nodes as possible, of similar sizes.
+/// Returns the smallest key and the offset of each node.
+fn write_nodes<T>(
+    entries: &[(Value, T)],
+    mut write: impl FnMut(&[(Value, T)]) -> io::Result<Offset>,
+) -> io::Result<Vec<(Value, Offset)>> {
+    if entries.is_empty() {
+        return Ok(vec![]);
+    }
+    let n_nodes = entries.len().div_ceil(MAX_NODE_LEN);
+    let chunk_len = entries.len().div_ceil(n_nodes);
+    entries
+        .chunks(chunk_len)
+        .map(|chunk| Ok((chunk[0].0.clone(), write(chunk)?)))
+        .collect()
+}
+
+/// Write a tree whose level of nodes is `nodes`, return its root.
+fn write_root<W: Write>(
+    enc: &mut Encoder<W>,
+    mut nodes: Vec<(Value, Offset)>,
+) -> io::Result<Offset> {
+    loop {
+        match nodes.len() {
+            0 => return write_leaf(enc, &[]),
+            1 => return Ok(nodes[0].1),
+            _ => nodes = write_nodes(&nodes, |c| write_branch(enc, c))?,
+        }
+    }
+}
+
+fn write_commit<W: Write>(
+    enc: &mut Encoder<W>,
+    prev_end: usize,
+    root: Offset,
+) -> io::Result<Offset> {
+    let arr = enc.write_array(&[Immediate::Int64(prev_end as i64), Immediate::Pointer(root)])?;
+    enc.write_tag(TAG_KV_COMMIT, Immediate::Pointer(arr))
+}
+
+/// Read the commit record at the entrypoint of `dec`.
+/// Returns the length of the file before the commit, and the root node.
+fn read_commit(dec: &Decoder) -> io::Result<(usize, Offset)> {
+    let entry = dec.entrypoint()?;
+    let err = Error {
+        msg: "invalid kv commit",
+        off: entry,
+    };
+    let (TAG_KV_COMMIT, inner) = dec.get_tag(entry)? else {
+        return Err(err.into());
+    };
+    let mut fields = vec![];
+    dec.get_array(inner, &mut fields)?;
+    let [prev_end, root] = fields[..] else {
+        return Err(err.into());
+    };
+    let prev_end = usize::try_from(dec.get_i64(prev_end)?).map_err(|_| err)?;
+    let root = dec.deref(root)?;
+    read_node(dec, root)?;
+    Ok((prev_end, root))
+}
+
+/// Length of the longest prefix of `data` that ends with a complete commit.
+///
+/// The bytes after it must be a torn commit: if they contain a complete commit,
+/// the store was corrupted, and this fails.
+fn last_commit_end(data: &[u8]) -> io::Result<usize> {
+    let dec = Decoder::new(data)?;
+    if !dec.header().is_some_and(|h| h.has_flag(FLAG_CHECKSUM)) {
+        return Err(Error {
+            msg: "kv store has no checksum",
+            off: 0,
+        }
+        .into());
+    }
+    if dec.verify_checksum().is_ok() && read_commit(&dec).is_ok() {
+        return Ok(data.len());
+    }
+
+    let footer_at = |p: usize| u32::from_le_bytes(data[p..p + FOOTER_LEN].try_into().unwrap());
+    let commit_at = |end: usize| {
+        let dec = Decoder::new(&data[..end]).ok()?;
+        read_commit(&dec).ok()
+    };
+    let mut crc = Crc32c::new();
+    crc.update(&data[..HEADER_LEN]);
+    let mut last = None;
+    for p in HEADER_LEN..=data.len() - FOOTER_LEN {
+        if crc.finish() == footer_at(p) && commit_at(p + FOOTER_LEN).is_some() {
+            last = Some(p + FOOTER_LEN);
+        }
+        crc.update(&data[p..p + 1]);
+    }
+    let last = last.ok_or(Error {
+        msg: "no complete kv commit",
+        off: 0,
+    })?;
+
+    // a complete commit after `last` checks against the footer of the commit before it
+    for end in last + 1..=data.len() {
+        let Some((prev_end, _)) = commit_at(end) else {
+            continue;
+        };
+        let footer = end - FOOTER_LEN;
+        let mut crc = match prev_end {
+            0 => Crc32c::new(),
+            p if (HEADER_LEN + FOOTER_LEN..=footer).contains(&p) => {
+                Crc32c::resume(footer_at(p - FOOTER_LEN))
+            }
+            _ => continue,
+        };
+        crc.update(&data[prev_end.saturating_sub(FOOTER_LEN)..footer]);
+        if crc.finish() == footer_at(footer) {
+            return Err(Error {
+                msg: "kv store is corrupted before its last commit",
+                off: last as Offset,
+            }
+            .into());
+        }
+    }
+    Ok(last)
+}
+
+/// Branches from the root to a leaf, with the index of the child taken in each.
+type Path_ = Vec<(Vec<(Value, Offset)>, usize)>;
+
+/// Replace the last node of `path` by `nodes`, and write new copies of the
+/// branches on the path. Returns the new root.
+fn write_path<W: Write>(
+    enc: &mut Encoder<W>,
+    mut path: Path_,
+    mut nodes: Vec<(Value, Offset)>,
+) -> io::Result<Offset> {
+    while let Some((mut entries, i)) = path.pop() {
+        entries.splice(i..=i, nodes);
+        nodes = if path.is_empty() && entries.len() == 1 {
+            // a root with a single child is replaced by the child
+            entries
+        } else {
+            write_nodes(&entries, |c| write_branch(enc, c))?
+        };
+    }
+    write_root(enc, nodes)
+}
+
+/// A key-value store in a file. See the [module documentation](self).
+#[derive(Debug)]
+pub struct KvStore {
+    path: PathBuf,
+    file: File,
+    /// Content of the file, followed by the uncommitted nodes.
+    data: Vec<u8>,
+    /// Length of the file.
+    committed: usize,
+    /// Checksum of the content of the file.
+    crc: Crc32c,
+    root: Offset,
+    committed_root: Offset,
+    /// Number of bytes truncated when opening.
+    truncated: u64,
+}
+
+impl KvStore {
+    /// Open the store at `path`, creating it if needed.
+    ///
+    /// This truncates a torn commit at the end of the file, if any.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        let mut data = vec![];
+        file.read_to_end(&mut data)?;
+        if data.is_empty() {
+            let mut enc = Encoder::with_header(&mut data, Header::with_flags(FLAG_CHECKSUM))?;
+            let root = write_leaf(&mut enc, &[])?;
+            let commit = write_commit(&mut enc, 0, root)?;
+            enc.finalize(Immediate::Pointer(commit))?;
+            file.write_all(&data)?;
+            file.sync_data()?;
+        }
+
+        let end = last_commit_end(&data)?;
+        let truncated = (data.len() - end) as u64;
+        if truncated > 0 {
+            data.truncate(end);
+            file.set_len(end as u64)?;
+            file.sync_data()?;
+            file.seek(SeekFrom::Start(end as u64))?;
+        }
+
+        let dec = Decoder::new(&data)?;
+        let (_, root) = read_commit(&dec)?;
+        let crc = dec.running_checksum().expect("store has a checksum");
+        Ok(KvStore {
+            path,
+            file,
+            committed: data.len(),
+            crc,
+            data,
+            root,
+            committed_root: root,
+            truncated,
+        })
+    }
+
+    /// Number of bytes of a torn commit that were truncated when opening the store.
+    #[inline]
+    pub fn truncated_bytes(&self) -> u64 {
+        self.truncated
+    }
+
+    /// A decoder for the store, including uncommitted changes.
+    ///
+    /// Offsets returned by [`KvStore::get`] and [`KvStore::range`] refer to it.
+    pub fn decoder(&self) -> Decoder<'_> {
+        Decoder::new(&self.data).expect("store was checked")
+    }
+
+    /// Offset of the root node of the current version of the tree.
+    #[inline]
+    pub fn root(&self) -> Offset {
+        self.root
+    }
+
+    /// Are there uncommitted changes?
+    #[inline]
+    pub fn is_dirty(&self) -> bool {
+        self.root != self.committed_root
+    }
+
+    /// Find the leaf that may contain `key`.
+    fn find_leaf(&self, key: &Value) -> io::Result<(Path_, Vec<(Value, Offset)>)> {
+        let dec = self.decoder();
+        let mut path = vec![];
+        let mut off = self.root;
+        loop {
+            let node = read_node(&dec, off)?;
+            if node.leaf {
+                return Ok((path, node.entries));
+            }
+            if node.entries.is_empty() {
+                return Err(invalid_node(off));
+            }
+            let i = child_index(&node.entries, key);
+            off = node.entries[i].1;
+            path.push((node.entries, i));
+        }
+    }
+
+    /// Find the value for `key`. Returns its offset in [`KvStore::decoder`].
+    pub fn get(&self, key: Immediate) -> io::Result<Option<Offset>> {
+        let key = key_of(key)?;
+        let (_, leaf) = self.find_leaf(&key)?;
+        Ok(leaf
+            .binary_search_by(|(k, _)| cmp_keys(k, &key))
+            .ok()
+            .map(|i| leaf[i].1))
+    }
+
+    /// Replace `leaf` (the last node of `path`) with `f(leaf)`.
+    fn update_leaf(
+        &mut self,
+        path: Path_,
+        leaf: Vec<(Value, Offset)>,
+        f: impl FnOnce(&mut Encoder<&mut Vec<u8>>, &mut Vec<(Value, Value)>) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let dec = self.decoder();
+        let mut leaf = leaf
+            .into_iter()
+            .map(|(k, v)| Ok((k, Value::from(reuse_immediate(&dec, v)?))))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let mut enc = Encoder::append_to(self.data.len() as Offset, &mut self.data);
+        f(&mut enc, &mut leaf)?;
+        let nodes = write_nodes(&leaf, |c| write_leaf(&mut enc, c))?;
+        self.root = write_path(&mut enc, path, nodes)?;
+        Ok(())
+    }
+
+    /// Set the value for `key`.
+    pub fn put(&mut self, key: Immediate, v: &Value) -> io::Result<()> {
+        let key = key_of(key)?;
+        let (path, leaf) = self.find_leaf(&key)?;
+        self.update_leaf(path, leaf, |enc, leaf| {
+            let v = Value::from(value::write_value_or_imm(enc, v)?);
+            match leaf.binary_search_by(|(k, _)| cmp_keys(k, &key)) {
+                Ok(i) => leaf[i].1 = v,
+                Err(i) => leaf.insert(i, (key, v)),
+            }
+            Ok(())
+        })
+    }
+
+    /// Remove `key`. Returns `false` if it was not present.
+    pub fn delete(&mut self, key: Immediate) -> io::Result<bool> {
+        let key = key_of(key)?;
+        let (path, leaf) = self.find_leaf(&key)?;
+        let Ok(i) = leaf.binary_search_by(|(k, _)| cmp_keys(k, &key)) else {
+            return Ok(false);
+        };
+        self.update_leaf(path, leaf, |_, leaf| {
+            leaf.remove(i);
+            Ok(())
+        })?;
+        Ok(true)
+    }
+
+    /// Iterate over the keys in the given range, in order, with the offsets
+    /// of their values.
+    pub fn range(&self, lo: Bound<Immediate>, hi: Bound<Immediate>) -> io::Result<Range<'_>> {
+        let lo = match lo {
+            Bound::Included(k) => Bound::Included(key_of(k)?),
+            Bound::Excluded(k) => Bound::Excluded(key_of(k)?),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let hi = match hi {
+            Bound::Included(k) => Bound::Included(key_of(k)?),
+            Bound::Excluded(k) => Bound::Excluded(key_of(k)?),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+
+        let mut r = Range {
+            dec: self.decoder(),
+            stack: vec![],
+            hi,
+        };
+        let mut off = self.root;
+        loop {
+            let node = read_node(&r.dec, off)?;
+            let i = match &lo {
+                Bound::Unbounded => 0,
+                Bound::Included(k) | Bound::Excluded(k) if !node.leaf => {
+                    child_index(&node.entries, k)
+                }
+                Bound::Included(k) => node
+                    .entries
+                    .partition_point(|(k2, _)| cmp_keys(k2, k) == Ordering::Less),
+                Bound::Excluded(k) => node
+                    .entries
+                    .partition_point(|(k2, _)| cmp_keys(k2, k) != Ordering::Greater),
+            };
+            let leaf = node.leaf;
+            let child = node.entries.get(i).map(|e| e.1);
+            r.stack.push((node, i));
+            match child {
+                Some(child) if !leaf => off = child,
+                _ => break,
+            }
+        }
+        Ok(r)
+    }
+
+    /// Iterate over all the keys, in order, with the offsets of their values.
+    pub fn iter(&self) -> io::Result<Range<'_>> {
+        self.range(Bound::Unbounded, Bound::Unbounded)
+    }
+
+    /// Write the changes to the file, and switch it to the current version of the tree.
+    ///
+    /// If this fails, the changes are kept uncommitted, and the commit can be retried.
+    pub fn commit(&mut self) -> io::Result<()> {
+        if !self.is_dirty() {
+            return Ok(());
+        }
+        let uncommitted_len = self.data.len();
+        let mut enc = Encoder::append_to(uncommitted_len as Offset, &mut self.data);
+        let commit = write_commit(&mut enc, self.committed, self.root)?;
+        enc.finalize(Immediate::Pointer(commit))?;
+        let mut crc = self.crc;
+        crc.update(&self.data[self.committed..]);
+        let footer = crc.finish().to_le_bytes();
+        crc.update(&footer);
+        self.data.extend_from_slice(&footer);
+
+        let res = self
+            .file
+            .write_all(&self.data[self.committed..])
+            .and_then(|()| self.file.sync_data());
+        if let Err(e) = res {
+            let _ = self.file.set_len(self.committed as u64);
+            let _ = self.file.seek(SeekFrom::Start(self.committed as u64));
+            // keep the changes, so that the commit can be retried
+            self.data.truncate(uncommitted_len);
+            return Err(e);
+        }
+        self.committed = self.data.len();
+        self.crc = crc;
+        self.committed_root = self.root;
+        Ok(())
+    }
+
+    /// Discard the changes since the last commit.
+    pub fn rollback(&mut self) {
+        self.data.truncate(self.committed);
+        self.root = self.committed_root;
+    }
+
+    /// Rewrite the file with only the current version of the tree, balanced.
+    ///
+    /// Uncommitted changes are included. The new file is written next to the
+    /// old one, and then atomically renamed over it.
+    pub fn compact(&mut self) -> io::Result<CompactStats> {
+        let mut out = vec![];
+        {
+            let dec = self.decoder();
+            let mut enc = Encoder::with_header(&mut out, Header::with_flags(FLAG_CHECKSUM))?;
+            let mut table = CopyTable::new();
+            let mut leaf = Vec::with_capacity(MAX_NODE_LEN);
+            let mut nodes = vec![];
+            for entry in self.iter()? {
+                let (k, v) = entry?;
+                let v = Value::from(enc.copy_from(&dec, v, &mut table)?);
+                leaf.push((k, v));
+                if leaf.len() == MAX_NODE_LEN {
+                    nodes.push((leaf[0].0.clone(), write_leaf(&mut enc, &leaf)?));
+                    leaf.clear();
+                }
+            }
+            if !leaf.is_empty() {
+                nodes.push((leaf[0].0.clone(), write_leaf(&mut enc, &leaf)?));
+            }
+            let root = write_root(&mut enc, nodes)?;
+            let commit = write_commit(&mut enc, 0, root)?;
+            enc.finalize(Immediate::Pointer(commit))?;
+        }
+
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".compact");
+        let mut tmp = File::create(&tmp_path)?;
+        tmp.write_all(&out)?;
+        tmp.sync_all()?;
+        drop(tmp);
+        std::fs::rename(&tmp_path, &self.path)?;
+        // make the rename itself durable
+        #[cfg(unix)]
+        {
+            let dir = match self.path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            };
+            File::open(dir)?.sync_all()?;
+        }
+
+        let mut file = File::options().read(true).write(true).open(&self.path)?;
+        file.seek(SeekFrom::End(0))?;
+        let stats = CompactStats {
+            bytes_before: self.data.len() as u64,
+            bytes_after: out.len() as u64,
+        };
+        let dec = Decoder::new(&out)?;
+        let (_, root) = read_commit(&dec)?;
+        self.crc = dec.running_checksum().expect("store has a checksum");
+        self.file = file;
+        self.committed = out.len();
+        self.data = out;
+        self.root = root;
+        self.committed_root = root;
+        Ok(stats)
+    }
+}
+
+/// Iterator over a range of keys of a [`KvStore`], with the offsets of their values.
+#[derive(Debug)]
+pub struct Range<'s> {
+    dec: Decoder<'s>,
+    /// Nodes from the root to the current leaf, with the index of
+    /// the current child or entry.
+    stack: Vec<(Node, usize)>,
+    hi: Bound<Value>,
+}
+
+impl Range<'_> {
+    fn step(&mut self) -> io::Result<Option<(Value, Offset)>> {
+        loop {
+            let Some((node, i)) = self.stack.last_mut() else {
+                return Ok(None);
+            };
+            if node.leaf {
+                let Some((k, v)) = node.entries.get(*i) else {
+                    self.stack.pop();
+                    continue;
+                };
+                *i += 1;
+                let past_end = match &self.hi {
+                    Bound::Included(hi) => cmp_keys(k, hi) == Ordering::Greater,
+                    Bound::Excluded(hi) => cmp_keys(k, hi) != Ordering::Less,
+                    Bound::Unbounded => false,
+                };
+                if past_end {
+                    self.stack.clear();
+                    return Ok(None);
+                }
+                return Ok(Some((k.clone(), *v)));
+            }
+
+            // the current child is done, go to the leftmost leaf of the next one
+            *i += 1;
+            let Some(&(_, mut off)) = node.entries.get(*i) else {
+                self.stack.pop();
+                continue;
+            };
+            loop {
+                let node = read_node(&self.dec, off)?;
+                let child = node.entries.first().map(|e| e.1);
+                let leaf = node.leaf;
+                self.stack.push((node, 0));
+                match child {
+                    Some(child) if !leaf => off = child,
+                    _ => break,
+                }
+            }
+        }
+    }
+}
+
+impl Iterator for Range<'_> {
+    type Item = io::Result<(Value, Offset)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.step() {
+            Ok(item) => item.map(Ok),
+            Err(e) => {
+                self.stack.clear();
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use std::collections::BTreeMap;
+
+    fn tmp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("twine-kv-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn check(store: &KvStore, model: &BTreeMap<i64, Value>) {
+        let dec = store.decoder();
+        let all: Vec<_> = store
+            .iter()
+            .unwrap()
+            .map(|e| {
+                let (k, v) = e.unwrap();
+                (k, value::read_value(&dec, v).unwrap())
+            })
+            .collect();
+        let expected: Vec<_> = model
+            .iter()
+            .map(|(k, v)| (Value::Int64(*k), v.clone()))
+            .collect();
+        assert_eq!(all, expected);
+
+        for k in [-1, 0, 7, 50, 99, 100] {
+            let v = store.get(Immediate::Int64(k)).unwrap();
+            let v = v.map(|v| value::read_value(&dec, v).unwrap());
+            assert_eq!(v.as_ref(), model.get(&k));
+        }
+
+        let range: Vec<_> = store
+            .range(
+                Bound::Excluded(Immediate::Int64(20)),
+                Bound::Included(Immediate::Int64(60)),
+            )
+            .unwrap()
+            .map(|e| e.unwrap().0)
+            .collect();
+        let expected: Vec<_> = model
+            .range((Bound::Excluded(20), Bound::Included(60)))
+            .map(|(k, _)| Value::Int64(*k))
+            .collect();
+        assert_eq!(range, expected);
+    }
+
+    fn sample_value(k: i64, v: i64) -> Value {
+        match v % 3 {
+            0 => Value::Int64(v),
+            1 => Value::String(format!("value {v} for {k}")),
+            _ => Value::Array(vec![Value::Int64(k), Value::Bytes(vec![v as u8; 4])]),
+        }
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(32))]
+        #[test]
+        fn same_as_btreemap(ops in prop::collection::vec((0..100i64, prop::option::of(0..1000i64)), 0..400)) {
+            let path = tmp_path("prop");
+            let mut store = KvStore::open(&path).unwrap();
+            let mut model = BTreeMap::new();
+            for (i, (k, op)) in ops.iter().enumerate() {
+                match op {
+                    Some(v) => {
+                        let v = sample_value(*k, *v);
+                        store.put(Immediate::Int64(*k), &v).unwrap();
+                        model.insert(*k, v);
+                    }
+                    None => {
+                        let present = store.delete(Immediate::Int64(*k)).unwrap();
+                        assert_eq!(present, model.remove(k).is_some());
+                    }
+                }
+                if i % 50 == 0 {
+                    store.commit().unwrap();
+                }
+            }
+            check(&store, &model);
+            store.commit().unwrap();
+            drop(store);
+
+            let mut store = KvStore::open(&path).unwrap();
+            check(&store, &model);
+            store.compact().unwrap();
+            check(&store, &model);
+            drop(store);
+            check(&KvStore::open(&path).unwrap(), &model);
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_kv() {
+        let path = tmp_path("kv");
+        let mut store = KvStore::open(&path).unwrap();
+        assert_eq!(store.iter().unwrap().count(), 0);
+
+        let keys = [
+            Immediate::String("b"),
+            Immediate::Int64(3),
+            Immediate::Null,
+            Immediate::String("a"),
+            Immediate::Float(-1.5),
+            Immediate::Bool(true),
+            Immediate::Int64(-3),
+        ];
+        for (i, k) in keys.iter().enumerate() {
+            store.put(*k, &Value::Int64(i as i64)).unwrap();
+        }
+        assert!(store.put(Immediate::Pointer(0), &Value::Null).is_err());
+        let sorted: Vec<_> = store.iter().unwrap().map(|e| e.unwrap().0).collect();
+        assert_eq!(
+            sorted,
+            vec![
+                Value::Null,
+                Value::Bool(true),
+                Value::Int64(-3),
+                Value::Int64(3),
+                Value::Float(-1.5),
+                Value::String("a".to_string()),
+                Value::String("b".to_string()),
+            ]
+        );
+        store.commit().unwrap();
+
+        // uncommitted changes can be rolled back, and are not visible after reopening
+        for i in 0..1000 {
+            store
+                .put(Immediate::Int64(i), &Value::String(format!("{i}")))
+                .unwrap();
+        }
+        assert!(store.is_dirty());
+        assert_eq!(store.iter().unwrap().count(), 1000 + keys.len() - 1);
+        store.rollback();
+        assert_eq!(store.iter().unwrap().count(), keys.len());
+        store.put(Immediate::Int64(42), &Value::Null).unwrap();
+        drop(store);
+        let mut store = KvStore::open(&path).unwrap();
+        assert_eq!(store.iter().unwrap().count(), keys.len());
+        assert!(store.get(Immediate::Int64(42)).unwrap().is_none());
+
+        // churn creates garbage, which compaction removes
+        for round in 0..20 {
+            for i in 0..200 {
+                let v = Value::String(format!("round {round} value {i}"));
+                store.put(Immediate::Int64(i), &v).unwrap();
+            }
+            store.commit().unwrap();
+        }
+        let dec = store.decoder();
+        let v = store.get(Immediate::Int64(150)).unwrap().unwrap();
+        assert_eq!(dec.get_str(v).unwrap(), "round 19 value 150");
+        let stats = store.compact().unwrap();
+        assert!(stats.bytes_after * 10 < stats.bytes_before);
+        let dec = store.decoder();
+        let v = store.get(Immediate::Int64(150)).unwrap().unwrap();
+        assert_eq!(dec.get_str(v).unwrap(), "round 19 value 150");
+        assert_eq!(store.iter().unwrap().count(), 200 + keys.len() - 1);
+
+        for i in 0..200 {
+            assert!(store.delete(Immediate::Int64(i)).unwrap());
+        }
+        assert!(!store.delete(Immediate::Int64(0)).unwrap());
+        assert_eq!(store.iter().unwrap().count(), keys.len() - 1);
+        store.commit().unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_kv_torn_commit() {
+        let path = tmp_path("torn");
+        let mut store = KvStore::open(&path).unwrap();
+        let mut model = BTreeMap::new();
+        let mut lens = vec![];
+        for round in 0..3 {
+            for i in 0..40 {
+                let v = Value::String(format!("round {round} value {i}"));
+                store.put(Immediate::Int64(i), &v).unwrap();
+                model.insert(i, v);
+            }
+            store.commit().unwrap();
+            lens.push(std::fs::metadata(&path).unwrap().len());
+        }
+        drop(store);
+        let full = std::fs::read(&path).unwrap();
+        let last_len = lens[2] - lens[1];
+
+        // a torn last commit is truncated, and the previous one is intact
+        let mut prev_model = model.clone();
+        for i in 0..40 {
+            prev_model.insert(i, Value::String(format!("round 1 value {i}")));
+        }
+        for cut in [1, 5, 40, last_len - 1] {
+            std::fs::write(&path, &full[..full.len() - cut as usize]).unwrap();
+            let mut store = KvStore::open(&path).unwrap();
+            assert_eq!(store.truncated_bytes(), last_len - cut);
+            assert_eq!(std::fs::metadata(&path).unwrap().len(), lens[1]);
+            check(&store, &prev_model);
+
+            // and the store can be committed to again
+            store.put(Immediate::Int64(99), &Value::Null).unwrap();
+            store.commit().unwrap();
+            drop(store);
+            let store = KvStore::open(&path).unwrap();
+            assert_eq!(store.truncated_bytes(), 0);
+            let mut m = prev_model.clone();
+            m.insert(99, Value::Null);
+            check(&store, &m);
+        }
+
+        // a commit that is complete but doesn't match its checksum is torn too
+        let mut bs = full.clone();
+        let n = bs.len();
+        bs[n - 10] ^= 0x40;
+        std::fs::write(&path, &bs).unwrap();
+        let store = KvStore::open(&path).unwrap();
+        assert_eq!(store.truncated_bytes(), last_len);
+        check(&store, &prev_model);
+        drop(store);
+
+        // a corrupted commit before the last one is an error, and nothing is truncated
+        let mut bs = full.clone();
+        bs[lens[0] as usize + 10] ^= 0x40;
+        std::fs::write(&path, &bs).unwrap();
+        let err = KvStore::open(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(std::fs::read(&path).unwrap(), bs);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_kv_failed_commit() {
+        let path = tmp_path("failed");
+        let mut store = KvStore::open(&path).unwrap();
+        store.put(Immediate::Int64(1), &Value::Null).unwrap();
+        store.commit().unwrap();
+        let len = std::fs::metadata(&path).unwrap().len();
+
+        // writes fail on a read-only handle
+        let mut model = BTreeMap::from([(1, Value::Null)]);
+        for i in 2..50 {
+            let v = Value::String(format!("value {i}"));
+            store.put(Immediate::Int64(i), &v).unwrap();
+            model.insert(i, v);
+        }
+        let file = std::mem::replace(&mut store.file, File::open(&path).unwrap());
+        assert!(store.commit().is_err());
+        assert!(store.is_dirty());
+        check(&store, &model);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), len);
+
+        // the changes are still there, and can be committed
+        store.file = file;
+        store.commit().unwrap();
+        assert!(!store.is_dirty());
+        drop(store);
+        check(&KvStore::open(&path).unwrap(), &model);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,9 +1,32 @@
 //! Twine encoding and decoding
 
+#[cfg(feature = "tokio")]
+pub mod async_io;
+pub mod checksum;
+pub mod compact;
+pub mod copy;
 pub mod deser;
+pub mod edit;
+pub mod fragment;
+pub mod header;
+pub mod kv;
+pub mod message;
+#[cfg(feature = "memmap2")]
+pub mod mmap;
+pub mod owned;
+pub mod packed;
+pub mod pvec;
+pub mod record_log;
+pub mod replicate;
+#[cfg(feature = "rayon")]
+pub mod par;
+pub mod roots;
 pub mod ser;
 pub mod shallow_value;
+pub mod storage;
+pub mod stream;
 pub mod types;
+pub mod versions;
 
 pub use deser::Decoder;
 pub use ser::Encoder;
@@ -0,0 +1,368 @@
+//! Streams of messages.
+//!
+//! A twine blob doesn't record its own length, so to send several blobs over
+//! a socket or a pipe, each blob is framed as a message: the length of the blob,
+//! as a LEB128 integer, followed by the blob itself.
+//!
+//! [`MessageWriter`] and [`MessageReader`] write and read such messages over
+//! [`io::Write`] and [`io::Read`]. With the `tokio` feature, see also
+//! `crate::async_io`; with the `tokio-util` feature, [`MessageCodec`] frames
+//! messages for `tokio_util::codec`.
+
+use std::io::{self, Read, Write};
+
+use crate::{ser::enc_leb128, Decoder, Encoder, Immediate};
+
+/// Default maximum length of a message, in bytes.
+pub const DEFAULT_MAX_LEN: usize = 64 * 1024 * 1024;
+
+/// Maximum length of the length prefix, in bytes.
+pub(crate) const MAX_PREFIX_LEN: usize = 10;
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Encode the length prefix for a blob of `len` bytes.
+pub(crate) fn encode_len(len: usize, buf: &mut [u8; MAX_PREFIX_LEN]) -> &[u8] {
+    let n = enc_leb128(len as u64, buf);
+    &buf[..n]
+}
+
+/// Decode the length prefix at the beginning of `bs`.
+///
+/// Returns the length of the blob and the length of the prefix, or `None`
+/// if `bs` doesn't contain the whole prefix yet. Fails if the prefix is
+/// invalid or if the length is larger than `max_len`.
+pub(crate) fn decode_len(bs: &[u8], max_len: usize) -> io::Result<Option<(usize, usize)>> {
+    let mut len: u64 = 0;
+    for (i, &c) in bs.iter().enumerate().take(MAX_PREFIX_LEN) {
+        if i == MAX_PREFIX_LEN - 1 && c > 1 {
+            // the length wouldn't fit in 64 bits
+            return Err(invalid("invalid message length"));
+        }
+        len |= ((c & 0x7f) as u64) << (7 * i);
+        if c & 0x80 == 0 {
+            if len > max_len as u64 {
+                return Err(invalid("message too large"));
+            }
+            return Ok(Some((len as usize, i + 1)));
+        }
+    }
+    if bs.len() >= MAX_PREFIX_LEN {
+        return Err(invalid("invalid message length"));
+    }
+    Ok(None)
+}
+
+/// Writes messages into a writer.
+#[derive(Debug)]
+pub struct MessageWriter<W> {
+    w: W,
+    /// Buffer to encode messages, reused across messages.
+    buf: Vec<u8>,
+}
+
+impl<W: Write> MessageWriter<W> {
+    pub fn new(w: W) -> Self {
+        MessageWriter { w, buf: vec![] }
+    }
+
+    /// Write a blob as a message.
+    pub fn write_blob(&mut self, blob: &[u8]) -> io::Result<()> {
+        let mut prefix = [0u8; MAX_PREFIX_LEN];
+        self.w.write_all(encode_len(blob.len(), &mut prefix))?;
+        self.w.write_all(blob)
+    }
+
+    /// Encode a blob with `f` and write it as a message.
+    ///
+    /// `f` returns the entrypoint of the blob.
+    pub fn write_with<F>(&mut self, f: F) -> io::Result<()>
+    where
+        F: for<'x> FnOnce(&mut Encoder<&'x mut Vec<u8>>) -> io::Result<Immediate<'static>>,
+    {
+        let mut buf = std::mem::take(&mut self.buf);
+        buf.clear();
+        let res = (|| {
+            let mut enc = Encoder::new(&mut buf);
+            let entrypoint = f(&mut enc)?;
+            enc.finalize(entrypoint)?;
+            self.write_blob(&buf)
+        })();
+        self.buf = buf;
+        res
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.w.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.w
+    }
+}
+
+/// Reads messages from a reader.
+///
+/// The length prefix is read one byte at a time, so a buffered reader
+/// is preferable.
+#[derive(Debug)]
+pub struct MessageReader<R> {
+    r: R,
+    max_len: usize,
+    /// Buffer for the current message.
+    buf: Vec<u8>,
+}
+
+impl<R: Read> MessageReader<R> {
+    /// Read messages of at most [`DEFAULT_MAX_LEN`] bytes from `r`.
+    pub fn new(r: R) -> Self {
+        Self::with_max_len(r, DEFAULT_MAX_LEN)
+    }
+
+    /// Read messages of at most `max_len` bytes from `r`.
+    pub fn with_max_len(r: R, max_len: usize) -> Self {
+        MessageReader {
+            r,
+            max_len,
+            buf: vec![],
+        }
+    }
+
+    /// Read the length prefix. Returns `None` if the reader is at its end.
+    fn read_len(&mut self) -> io::Result<Option<usize>> {
+        let mut prefix = [0u8; MAX_PREFIX_LEN];
+        let mut n = 0;
+        loop {
+            match self.r.read(&mut prefix[n..n + 1]) {
+                Ok(0) if n == 0 => return Ok(None),
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(_) => n += 1,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+            if let Some((len, _)) = decode_len(&prefix[..n], self.max_len)? {
+                return Ok(Some(len));
+            }
+        }
+    }
+
+    /// Read the next message, and return a decoder for it.
+    ///
+    /// Returns `Ok(None)` if the reader is at its end. The decoder borrows
+    /// an internal buffer, which is reused for the next message.
+    pub fn next_message(&mut self) -> io::Result<Option<Decoder<'_>>> {
+        let Some(len) = self.read_len()? else {
+            return Ok(None);
+        };
+        self.buf.clear();
+        self.buf.resize(len, 0);
+        self.r.read_exact(&mut self.buf)?;
+        Ok(Some(Decoder::new(&self.buf)?))
+    }
+
+    pub fn into_inner(self) -> R {
+        self.r
+    }
+}
+
+#[cfg(feature = "tokio-util")]
+pub use codec::MessageCodec;
+
+#[cfg(feature = "tokio-util")]
+mod codec {
+    use super::*;
+    use crate::owned::OwnedDecoder;
+    use bytes::{Buf, Bytes, BytesMut};
+
+    /// A codec for [`tokio_util::codec`], that frames messages.
+    ///
+    /// Decoding yields an [`OwnedDecoder`] for each message, that shares
+    /// the read buffer. Anything that derefs to bytes can be encoded as a message.
+    #[derive(Debug, Clone, Copy)]
+    pub struct MessageCodec {
+        max_len: usize,
+    }
+
+    impl Default for MessageCodec {
+        fn default() -> Self {
+            Self::new(DEFAULT_MAX_LEN)
+        }
+    }
+
+    impl MessageCodec {
+        /// A codec that rejects messages larger than `max_len` bytes.
+        pub fn new(max_len: usize) -> Self {
+            MessageCodec { max_len }
+        }
+    }
+
+    impl tokio_util::codec::Decoder for MessageCodec {
+        type Item = OwnedDecoder<Bytes>;
+        type Error = io::Error;
+
+        fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Self::Item>> {
+            let Some((len, prefix_len)) = decode_len(src, self.max_len)? else {
+                return Ok(None);
+            };
+            if src.len() < prefix_len + len {
+                src.reserve(prefix_len + len - src.len());
+                return Ok(None);
+            }
+            src.advance(prefix_len);
+            let blob = src.split_to(len).freeze();
+            Ok(Some(OwnedDecoder::new(blob)?))
+        }
+    }
+
+    impl<T: AsRef<[u8]>> tokio_util::codec::Encoder<T> for MessageCodec {
+        type Error = io::Error;
+
+        fn encode(&mut self, blob: T, dst: &mut BytesMut) -> io::Result<()> {
+            let blob = blob.as_ref();
+            if blob.len() > self.max_len {
+                return Err(invalid("message too large"));
+            }
+            let mut prefix = [0u8; MAX_PREFIX_LEN];
+            let prefix = encode_len(blob.len(), &mut prefix);
+            dst.reserve(prefix.len() + blob.len());
+            dst.extend_from_slice(prefix);
+            dst.extend_from_slice(blob);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::value::{self, Value};
+
+    fn sample(i: i64) -> Value {
+        Value::Map(vec![
+            (Value::String("id".to_string()), Value::Int64(i)),
+            (
+                Value::String("data".to_string()),
+                Value::Bytes(vec![i as u8; 100 * i as usize]),
+            ),
+        ])
+    }
+
+    fn write_sample(enc: &mut Encoder<&mut Vec<u8>>, i: i64) -> io::Result<Immediate<'static>> {
+        Ok(Immediate::Pointer(value::write_value(enc, &sample(i))?))
+    }
+
+    #[test]
+    fn test_messages() {
+        let mut w = MessageWriter::new(vec![]);
+        for i in 0..10 {
+            w.write_with(|enc| write_sample(enc, i)).unwrap();
+        }
+        w.write_blob(b"\x40\x00").unwrap();
+        let out = w.into_inner();
+
+        let mut r = MessageReader::new(io::BufReader::new(&out[..]));
+        for i in 0..10 {
+            let dec = r.next_message().unwrap().unwrap();
+            assert_eq!(value::read_value_from_entrypoint(&dec).unwrap(), sample(i));
+        }
+        let dec = r.next_message().unwrap().unwrap();
+        assert_eq!(dec.get_str(dec.entrypoint().unwrap()).unwrap(), "");
+        assert!(r.next_message().unwrap().is_none());
+
+        // too large, truncated
+        let mut r = MessageReader::with_max_len(&out[..], 500);
+        let mut n_ok = 0;
+        let e = loop {
+            match r.next_message() {
+                Ok(_) => n_ok += 1,
+                Err(e) => break e,
+            }
+        };
+        assert!(n_ok > 0 && n_ok < 10);
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        let mut r = MessageReader::new(&out[..out.len() - 1]);
+        for _ in 0..10 {
+            r.next_message().unwrap();
+        }
+        let e = r.next_message().unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+
+        assert!(decode_len(&[0xff; 12], usize::MAX).is_err());
+        assert_eq!(decode_len(&[0xff; 3], usize::MAX).unwrap(), None);
+
+        // the 10th byte of the prefix only has room for 1 bit
+        let mut prefix = [0xff; MAX_PREFIX_LEN];
+        prefix[MAX_PREFIX_LEN - 1] = 0x01;
+        assert_eq!(
+            decode_len(&prefix, usize::MAX).unwrap(),
+            Some((u64::MAX as usize, MAX_PREFIX_LEN))
+        );
+        prefix[MAX_PREFIX_LEN - 1] = 0x02;
+        let e = decode_len(&prefix, usize::MAX).unwrap_err();
+        assert_eq!(e.to_string(), "invalid message length");
+    }
+
+    #[cfg(feature = "tokio-util")]
+    #[test]
+    fn test_codec() {
+        use futures::{SinkExt, StreamExt};
+        use tokio_util::codec::{Encoder as _, FramedRead, FramedWrite};
+
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let (client, server) = tokio::io::duplex(64);
+
+            let writer = async {
+                let mut sink = FramedWrite::new(client, MessageCodec::default());
+                for i in 0..10 {
+                    let mut blob = vec![];
+                    let mut enc = Encoder::new(&mut blob);
+                    let entry = write_sample(&mut enc, i).unwrap();
+                    enc.finalize(entry).unwrap();
+                    sink.send(blob).await.unwrap();
+                }
+                let e = MessageCodec::new(5).encode(&[0u8; 6], &mut Default::default());
+                assert!(e.is_err());
+            };
+
+            let reader = async {
+                let mut stream = FramedRead::new(server, MessageCodec::default());
+                let mut decs = vec![];
+                while let Some(dec) = stream.next().await {
+                    decs.push(dec.unwrap());
+                }
+                // decoders are owned and outlive the stream
+                drop(stream);
+                assert_eq!(decs.len(), 10);
+                for (i, dec) in decs.iter().enumerate() {
+                    let v = value::read_value_from_entrypoint(&dec.decoder()).unwrap();
+                    assert_eq!(v, sample(i as i64));
+                }
+            };
+
+            tokio::join!(writer, reader);
+
+            // messages that are too large are rejected
+            let (client, server) = tokio::io::duplex(64);
+            let writer = async {
+                let mut sink = FramedWrite::new(client, MessageCodec::default());
+                let mut blob = vec![];
+                let mut enc = Encoder::new(&mut blob);
+                let entry = write_sample(&mut enc, 5).unwrap();
+                enc.finalize(entry).unwrap();
+                // the reader may hang up early
+                let _ = sink.send(blob).await;
+            };
+            let reader = async {
+                let mut stream = FramedRead::new(server, MessageCodec::new(100));
+                let e = stream.next().await.unwrap().unwrap_err();
+                assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+            };
+            tokio::join!(writer, reader);
+        });
+    }
+}
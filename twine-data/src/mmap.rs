@@ -0,0 +1,225 @@
+//! Memory-mapped files.
+//!
+//! [`MmapDecoder`] maps a twine file in memory and decodes it without reading it
+//! upfront; the OS loads pages on demand and shares them between the processes
+//! that map the same file. [`MmapSink`] is a writer that writes into a mapped file,
+//! growing it as needed.
+//!
+//! As with all memory maps, the file must not be modified (eg. truncated)
+//! by another process while it is mapped.
+
+use std::{
+    fs::File,
+    io::{self, Write},
+    path::Path,
+};
+
+use memmap2::{Mmap, MmapMut};
+
+use crate::Decoder;
+
+/// A twine file mapped in memory.
+///
+/// Use [`MmapDecoder::decoder`] to read it. Strings and byte strings
+/// borrowed from the decoder cannot outlive the `MmapDecoder`.
+#[derive(Debug)]
+pub struct MmapDecoder {
+    map: Mmap,
+}
+
+impl MmapDecoder {
+    /// Map the file at `path`.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::from_file(&File::open(path)?)
+    }
+
+    /// Map the given file. The file can be closed afterwards.
+    ///
+    /// This checks the header of the blob, if it has one.
+    pub fn from_file(file: &File) -> io::Result<Self> {
+        // SAFETY: see the module documentation, the file must not be modified
+        // while it is mapped.
+        let map = unsafe { Mmap::map(file)? };
+        let _ = Decoder::new(&map)?;
+        Ok(MmapDecoder { map })
+    }
+
+    /// The content of the file.
+    #[inline]
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.map
+    }
+
+    /// A decoder for the file.
+    pub fn decoder(&self) -> Decoder<'_> {
+        Decoder::new(&self.map).expect("blob was checked when opening")
+    }
+}
+
+/// A writer into a memory-mapped file.
+///
+/// The file grows (and is re-mapped) as needed. When done, [`MmapSink::finish`]
+/// truncates the file to the number of bytes written; this is also done
+/// when the sink is dropped, ignoring errors.
+#[derive(Debug)]
+pub struct MmapSink {
+    file: File,
+    /// `None` while the file is empty, since empty maps are not allowed.
+    map: Option<MmapMut>,
+    /// Number of bytes written so far.
+    len: usize,
+    /// Current size of the file.
+    cap: usize,
+    closed: bool,
+}
+
+impl MmapSink {
+    /// Minimum size by which the file grows.
+    const MIN_GROWTH: usize = 64 * 1024;
+
+    /// Create (or truncate) the file at `path`, and write into it.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        Self::new(file)
+    }
+
+    /// Write into `file`, which must be opened for reading and writing.
+    /// The file is truncated.
+    pub fn new(file: File) -> io::Result<Self> {
+        file.set_len(0)?;
+        Ok(MmapSink {
+            file,
+            map: None,
+            len: 0,
+            cap: 0,
+            closed: false,
+        })
+    }
+
+    /// Number of bytes written so far.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Has nothing been written yet?
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Grow the file so it can contain at least `min_cap` bytes.
+    fn grow(&mut self, min_cap: usize) -> io::Result<()> {
+        let cap = min_cap.max(self.cap * 2).max(Self::MIN_GROWTH);
+        if let Some(map) = self.map.take() {
+            map.flush()?;
+        }
+        self.file.set_len(cap as u64)?;
+        // SAFETY: we own the file, and it's not modified except through the map.
+        self.map = Some(unsafe { MmapMut::map_mut(&self.file)? });
+        self.cap = cap;
+        Ok(())
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        self.closed = true;
+        if let Some(map) = self.map.take() {
+            map.flush()?;
+        }
+        self.file.set_len(self.len as u64)?;
+        self.file.sync_all()
+    }
+
+    /// Flush the written bytes and truncate the file to its final size.
+    pub fn finish(mut self) -> io::Result<()> {
+        self.close()
+    }
+}
+
+impl Write for MmapSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let end = self.len + buf.len();
+        if end > self.cap {
+            self.grow(end)?;
+        }
+        if let Some(map) = &mut self.map {
+            map[self.len..end].copy_from_slice(buf);
+        }
+        self.len = end;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &self.map {
+            Some(map) => map.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for MmapSink {
+    fn drop(&mut self) {
+        if !self.closed {
+            let _ = self.close();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        header::{Header, FLAG_CHECKSUM},
+        value::{self, Value},
+        Encoder, Immediate,
+    };
+
+    #[test]
+    fn test_mmap() {
+        let path = std::env::temp_dir().join(format!("twine-mmap-{}", std::process::id()));
+        let v = Value::Array(
+            (0..20_000)
+                .map(|i| Value::String(format!("string number {i}")))
+                .collect(),
+        );
+
+        let mut sink = MmapSink::create(&path).unwrap();
+        let mut enc = Encoder::with_header(&mut sink, Header::with_flags(FLAG_CHECKSUM)).unwrap();
+        let off = value::write_value(&mut enc, &v).unwrap();
+        enc.finalize(Immediate::Pointer(off)).unwrap();
+        let len = sink.len();
+        assert!(len > MmapSink::MIN_GROWTH);
+        sink.finish().unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), len as u64);
+
+        let m = MmapDecoder::open(&path).unwrap();
+        assert_eq!(m.as_bytes().len(), len);
+        let dec = m.decoder();
+        dec.verify_checksum().unwrap();
+        assert_eq!(value::read_value_from_entrypoint(&dec).unwrap(), v);
+
+        // dropping the sink also truncates the file
+        {
+            let mut sink = MmapSink::create(&path).unwrap();
+            let enc = Encoder::new(&mut sink);
+            enc.finalize(Immediate::Int64(4)).unwrap();
+        }
+        let m = MmapDecoder::open(&path).unwrap();
+        assert_eq!(m.as_bytes().len(), 2);
+        assert_eq!(
+            m.decoder()
+                .get_i64(m.decoder().entrypoint().unwrap())
+                .unwrap(),
+            4
+        );
+
+        std::fs::write(&path, b"\xd9twi").unwrap();
+        assert!(MmapDecoder::open(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
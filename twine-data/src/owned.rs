@@ -0,0 +1,470 @@
+//! Owned decoding.
+//!
+//! [`crate::Decoder`] borrows the blob it reads, which makes it hard to store
+//! in long-lived structures, or to send to other threads or tasks.
+//! [`OwnedDecoder`] instead holds a cheaply clonable, reference-counted buffer
+//! (an `Arc<[u8]>`, or a `bytes::Bytes` with the `bytes` feature).
+//! The strings and byte strings it returns ([`SharedStr`], [`SharedBytes`])
+//! keep the buffer alive, and so do the shallow values ([`OwnedShallowValue`]);
+//! all of them are `'static` and can be cached and passed around freely.
+
+use std::{fmt, ops::Deref, sync::Arc};
+
+use crate::{
+    shallow_value::ShallowValue,
+    types::{Offset, Tag, VariantIdx},
+    Decoder, Immediate, Result,
+};
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// A reference-counted buffer, that is cheap to clone and always
+/// dereferences to the same bytes.
+///
+/// This trait is sealed.
+pub trait SharedBuffer:
+    Clone + Deref<Target = [u8]> + Send + Sync + 'static + sealed::Sealed
+{
+}
+
+impl sealed::Sealed for Arc<[u8]> {}
+impl SharedBuffer for Arc<[u8]> {}
+
+#[cfg(feature = "bytes")]
+impl sealed::Sealed for bytes::Bytes {}
+#[cfg(feature = "bytes")]
+impl SharedBuffer for bytes::Bytes {}
+
+/// A decoder that owns (a reference to) its blob.
+#[derive(Clone)]
+pub struct OwnedDecoder<B: SharedBuffer = Arc<[u8]>> {
+    buf: B,
+}
+
+impl<B: SharedBuffer> fmt::Debug for OwnedDecoder<B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "OwnedDecoder {{bs: {} bytes}}", self.buf.len())
+    }
+}
+
+impl<B: SharedBuffer> OwnedDecoder<B> {
+    /// Create a decoder for the blob in `buf`.
+    ///
+    /// See [`Decoder::new`].
+    pub fn new(buf: B) -> Result<Self> {
+        let _ = Decoder::new(&buf)?;
+        Ok(OwnedDecoder { buf })
+    }
+
+    /// The underlying buffer.
+    #[inline]
+    pub fn buffer(&self) -> &B {
+        &self.buf
+    }
+
+    /// A borrowing decoder for the same blob, to access the whole API of [`Decoder`].
+    pub fn decoder(&self) -> Decoder<'_> {
+        Decoder::new(&self.buf).expect("blob was checked")
+    }
+
+    /// Find the entrypoint. See [`Decoder::entrypoint`].
+    pub fn entrypoint(&self) -> Result<Offset> {
+        self.decoder().entrypoint()
+    }
+
+    /// Range of `bs` within the buffer. `bs` must come from `self.decoder()`.
+    fn range_of(&self, bs: &[u8]) -> (usize, usize) {
+        let start = bs.as_ptr() as usize - self.buf.as_ptr() as usize;
+        debug_assert!(start + bs.len() <= self.buf.len());
+        (start, bs.len())
+    }
+
+    fn shared_str(&self, s: &str) -> SharedStr<B> {
+        let (start, len) = self.range_of(s.as_bytes());
+        SharedStr {
+            buf: self.buf.clone(),
+            start,
+            len,
+        }
+    }
+
+    fn shared_bytes(&self, bs: &[u8]) -> SharedBytes<B> {
+        let (start, len) = self.range_of(bs);
+        SharedBytes {
+            buf: self.buf.clone(),
+            start,
+            len,
+        }
+    }
+
+    /// Read a string.
+    pub fn get_str(&self, off: Offset) -> Result<SharedStr<B>> {
+        let s = self.decoder().get_str(off)?;
+        Ok(self.shared_str(s))
+    }
+
+    /// Read a byte string.
+    pub fn get_bytes(&self, off: Offset) -> Result<SharedBytes<B>> {
+        let bs = self.decoder().get_bytes(off)?;
+        Ok(self.shared_bytes(bs))
+    }
+
+    /// Read a shallow value. See [`Decoder::get_shallow_value`].
+    pub fn get_shallow_value(&self, off: Offset) -> Result<OwnedShallowValue<B>> {
+        let v = match self.decoder().get_shallow_value(off)? {
+            ShallowValue::Imm(imm) => OwnedShallowValue::Imm(self.owned_immediate(imm)),
+            ShallowValue::Tag(tag, off) => OwnedShallowValue::Tag(tag, off),
+            ShallowValue::Array(arr) => OwnedShallowValue::Array(OwnedArrayCursor {
+                dec: self.clone(),
+                off: arr.off,
+                n_items: arr.n_items,
+            }),
+            ShallowValue::Map(map) => OwnedShallowValue::Map(OwnedMapCursor {
+                dec: self.clone(),
+                off: map.off,
+                n_items: map.n_items,
+            }),
+            ShallowValue::Variant(c, args) => OwnedShallowValue::Variant(
+                c,
+                OwnedArrayCursor {
+                    dec: self.clone(),
+                    off: args.off,
+                    n_items: args.n_items,
+                },
+            ),
+        };
+        Ok(v)
+    }
+
+    fn owned_immediate(&self, imm: Immediate) -> OwnedImmediate<B> {
+        match imm {
+            Immediate::Null => OwnedImmediate::Null,
+            Immediate::Bool(b) => OwnedImmediate::Bool(b),
+            Immediate::Int64(i) => OwnedImmediate::Int64(i),
+            Immediate::Float(f) => OwnedImmediate::Float(f),
+            Immediate::String(s) => OwnedImmediate::String(self.shared_str(s)),
+            Immediate::Bytes(bs) => OwnedImmediate::Bytes(self.shared_bytes(bs)),
+            Immediate::Variant0(c) => OwnedImmediate::Variant0(c),
+            Immediate::Ref(p) => OwnedImmediate::Ref(p),
+            Immediate::Pointer(p) => OwnedImmediate::Pointer(p),
+        }
+    }
+}
+
+impl OwnedDecoder<Arc<[u8]>> {
+    /// Create a decoder that takes ownership of `bs`.
+    pub fn from_vec(bs: Vec<u8>) -> Result<Self> {
+        Self::new(bs.into())
+    }
+}
+
+macro_rules! shared_slice {
+    ($name:ident, $target:ty, $doc:literal) => {
+        #[doc = $doc]
+        ///
+        /// It keeps the whole buffer alive.
+        #[derive(Clone)]
+        pub struct $name<B: SharedBuffer = Arc<[u8]>> {
+            buf: B,
+            start: usize,
+            len: usize,
+        }
+
+        impl<B: SharedBuffer> AsRef<$target> for $name<B> {
+            fn as_ref(&self) -> &$target {
+                self
+            }
+        }
+
+        impl<B: SharedBuffer> fmt::Debug for $name<B> {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                fmt::Debug::fmt(&**self, f)
+            }
+        }
+
+        impl<B: SharedBuffer> PartialEq for $name<B> {
+            fn eq(&self, other: &Self) -> bool {
+                **self == **other
+            }
+        }
+
+        impl<B: SharedBuffer> Eq for $name<B> {}
+
+        impl<B: SharedBuffer> PartialEq<$target> for $name<B> {
+            fn eq(&self, other: &$target) -> bool {
+                &**self == other
+            }
+        }
+
+        impl<'a, B: SharedBuffer> PartialEq<&'a $target> for $name<B> {
+            fn eq(&self, other: &&'a $target) -> bool {
+                &**self == *other
+            }
+        }
+
+        impl<B: SharedBuffer> std::hash::Hash for $name<B> {
+            fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+                (**self).hash(state)
+            }
+        }
+    };
+}
+
+shared_slice!(
+    SharedStr,
+    str,
+    "A string that shares the buffer of an [`OwnedDecoder`]."
+);
+shared_slice!(
+    SharedBytes,
+    [u8],
+    "A byte string that shares the buffer of an [`OwnedDecoder`]."
+);
+
+impl<B: SharedBuffer> Deref for SharedStr<B> {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        let bs = &self.buf[self.start..self.start + self.len];
+        // SAFETY: the bytes were checked to be UTF8 when decoding the string,
+        // and shared buffers always dereference to the same bytes.
+        unsafe { std::str::from_utf8_unchecked(bs) }
+    }
+}
+
+impl<B: SharedBuffer> fmt::Display for SharedStr<B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
+
+impl<B: SharedBuffer> Deref for SharedBytes<B> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf[self.start..self.start + self.len]
+    }
+}
+
+/// An immediate value that owns (a reference to) the buffer it comes from.
+///
+/// See [`Immediate`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum OwnedImmediate<B: SharedBuffer = Arc<[u8]>> {
+    Null,
+    Bool(bool),
+    Int64(i64),
+    Float(f64),
+    String(SharedStr<B>),
+    Bytes(SharedBytes<B>),
+    Variant0(VariantIdx),
+    Ref(Offset),
+    Pointer(Offset),
+}
+
+impl<B: SharedBuffer> OwnedImmediate<B> {
+    /// Borrow as an [`Immediate`], eg. to write it with an encoder.
+    pub fn as_immediate(&self) -> Immediate<'_> {
+        match self {
+            OwnedImmediate::Null => Immediate::Null,
+            OwnedImmediate::Bool(b) => Immediate::Bool(*b),
+            OwnedImmediate::Int64(i) => Immediate::Int64(*i),
+            OwnedImmediate::Float(f) => Immediate::Float(*f),
+            OwnedImmediate::String(s) => Immediate::String(s),
+            OwnedImmediate::Bytes(bs) => Immediate::Bytes(bs),
+            OwnedImmediate::Variant0(c) => Immediate::Variant0(*c),
+            OwnedImmediate::Ref(p) => Immediate::Ref(*p),
+            OwnedImmediate::Pointer(p) => Immediate::Pointer(*p),
+        }
+    }
+}
+
+/// A shallow value that owns (a reference to) the buffer it comes from.
+///
+/// See [`ShallowValue`].
+#[derive(Debug, Clone)]
+pub enum OwnedShallowValue<B: SharedBuffer = Arc<[u8]>> {
+    Imm(OwnedImmediate<B>),
+    Tag(Tag, Offset),
+    Array(OwnedArrayCursor<B>),
+    Map(OwnedMapCursor<B>),
+    Variant(VariantIdx, OwnedArrayCursor<B>),
+}
+
+/// Iterator over the offsets of the items of an array (or the arguments of a variant).
+#[derive(Debug, Clone)]
+pub struct OwnedArrayCursor<B: SharedBuffer = Arc<[u8]>> {
+    dec: OwnedDecoder<B>,
+    off: Offset,
+    n_items: u64,
+}
+
+impl<B: SharedBuffer> OwnedArrayCursor<B> {
+    /// Number of remaining items.
+    pub fn len(&self) -> u64 {
+        self.n_items
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.n_items == 0
+    }
+}
+
+impl<B: SharedBuffer> Iterator for OwnedArrayCursor<B> {
+    type Item = Result<Offset>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.n_items == 0 {
+            return None;
+        }
+
+        let off = self.off;
+        match self.dec.decoder().skip(off) {
+            Ok(off2) => {
+                self.off = off2;
+                self.n_items -= 1;
+                Some(Ok(off))
+            }
+            Err(e) => {
+                self.n_items = 0;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Iterator over the offsets of the keys and values of a map.
+#[derive(Debug, Clone)]
+pub struct OwnedMapCursor<B: SharedBuffer = Arc<[u8]>> {
+    dec: OwnedDecoder<B>,
+    off: Offset,
+    n_items: u64,
+}
+
+impl<B: SharedBuffer> OwnedMapCursor<B> {
+    /// Number of remaining items.
+    pub fn len(&self) -> u64 {
+        self.n_items
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.n_items == 0
+    }
+}
+
+impl<B: SharedBuffer> Iterator for OwnedMapCursor<B> {
+    type Item = Result<(Offset, Offset)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.n_items == 0 {
+            return None;
+        }
+
+        let dec = self.dec.decoder();
+        let k_off = self.off;
+        let pair = dec
+            .skip(k_off)
+            .and_then(|v_off| Ok((v_off, dec.skip(v_off)?)));
+        match pair {
+            Ok((v_off, off2)) => {
+                self.off = off2;
+                self.n_items -= 1;
+                Some(Ok((k_off, v_off)))
+            }
+            Err(e) => {
+                self.n_items = 0;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Encoder;
+
+    fn sample() -> (Vec<u8>, Offset) {
+        let mut res = vec![];
+        let mut enc = Encoder::new(&mut res);
+        let s = enc.write_string("a string that is long enough").unwrap();
+        let m = enc
+            .write_map(&[
+                (Immediate::String("k"), Immediate::Bytes(b"v")),
+                (Immediate::String("p"), Immediate::Pointer(s)),
+            ])
+            .unwrap();
+        let var = enc
+            .write_variant(VariantIdx(2), &[Immediate::Int64(1), Immediate::Null])
+            .unwrap();
+        let arr = enc
+            .write_array(&[
+                Immediate::Pointer(m),
+                var,
+                Immediate::Ref(s),
+                Immediate::Float(1.5),
+            ])
+            .unwrap();
+        let top = enc.write_tag(12, Immediate::Pointer(arr)).unwrap();
+        enc.finalize(Immediate::Pointer(top)).unwrap();
+        (res, top)
+    }
+
+    fn check<B: SharedBuffer>(dec: OwnedDecoder<B>, top: Offset) {
+        assert_eq!(dec.entrypoint().unwrap(), top);
+        let OwnedShallowValue::Tag(12, arr) = dec.get_shallow_value(top).unwrap() else {
+            panic!()
+        };
+        let OwnedShallowValue::Array(items) = dec.get_shallow_value(arr).unwrap() else {
+            panic!()
+        };
+        assert_eq!(items.len(), 4);
+        let items = items.collect::<Result<Vec<_>>>().unwrap();
+
+        let OwnedShallowValue::Map(m) = dec.get_shallow_value(items[0]).unwrap() else {
+            panic!()
+        };
+        let pairs = m.collect::<Result<Vec<_>>>().unwrap();
+        assert_eq!(dec.get_str(pairs[0].0).unwrap(), "k");
+        assert_eq!(dec.get_bytes(pairs[0].1).unwrap(), &b"v"[..]);
+
+        let OwnedShallowValue::Variant(c, args) = dec.get_shallow_value(items[1]).unwrap() else {
+            panic!()
+        };
+        assert_eq!(c, VariantIdx(2));
+        assert_eq!(args.len(), 2);
+
+        let OwnedShallowValue::Imm(OwnedImmediate::Ref(s)) =
+            dec.get_shallow_value(items[2]).unwrap()
+        else {
+            panic!()
+        };
+        let OwnedShallowValue::Imm(f) = dec.get_shallow_value(items[3]).unwrap() else {
+            panic!()
+        };
+        assert_eq!(f.as_immediate(), Immediate::Float(1.5));
+
+        // the string outlives the decoder, and can be sent to another thread
+        let s = dec.get_str(s).unwrap();
+        assert_eq!(s, dec.get_str(pairs[1].1).unwrap());
+        drop(dec);
+        let h = std::thread::spawn(move || s.to_uppercase());
+        assert_eq!(h.join().unwrap(), "A STRING THAT IS LONG ENOUGH");
+    }
+
+    #[test]
+    fn test_owned() {
+        let (res, top) = sample();
+        check(OwnedDecoder::from_vec(res).unwrap(), top);
+        assert!(OwnedDecoder::from_vec(b"\xd9tw".to_vec()).is_err());
+    }
+
+    #[cfg(feature = "bytes")]
+    #[test]
+    fn test_owned_bytes() {
+        let (res, top) = sample();
+        check(OwnedDecoder::new(bytes::Bytes::from(res)).unwrap(), top);
+    }
+}
@@ -0,0 +1,180 @@
+//! Packed arrays of numbers.
+//!
+//! Arrays of numbers can be stored much more compactly (and read much faster)
+//! than arrays of individual immediates. A packed array is a tagged byte string:
+//! - the tag indicates the type of elements, using the tags for little-endian
+//!   typed arrays from [RFC 8746](https://www.rfc-editor.org/rfc/rfc8746.html);
+//! - the first byte of the byte string is a number of padding bytes `p`;
+//! - then come `p` bytes of padding;
+//! - then the elements themselves, in little-endian.
+//!
+//! The encoder picks the padding so that the elements are aligned relative to the
+//! beginning of the blob. If the blob itself is suitably aligned in memory,
+//! the decoder can then return a slice of the elements without copying them.
+
+use std::borrow::Cow;
+
+use crate::types::Tag;
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// A number type that can be stored in a packed array.
+pub trait PackedElem: Copy + sealed::Sealed {
+    /// The tag used for packed arrays of this type.
+    const TAG: Tag;
+
+    /// Size of one element, in bytes.
+    const SIZE: usize;
+
+    /// Write the little-endian representation of `self` into `buf`,
+    /// which is exactly `SIZE` bytes long.
+    fn write_le(self, buf: &mut [u8]);
+
+    /// Read a value from its little-endian representation in `buf`,
+    /// which is exactly `SIZE` bytes long.
+    fn read_le(buf: &[u8]) -> Self;
+}
+
+macro_rules! impl_packed_elem {
+    ($typ:ty, $tag:expr) => {
+        impl sealed::Sealed for $typ {}
+
+        impl PackedElem for $typ {
+            const TAG: Tag = $tag;
+            const SIZE: usize = std::mem::size_of::<$typ>();
+
+            #[inline]
+            fn write_le(self, buf: &mut [u8]) {
+                buf.copy_from_slice(&self.to_le_bytes())
+            }
+
+            #[inline]
+            fn read_le(buf: &[u8]) -> Self {
+                <$typ>::from_le_bytes(buf.try_into().unwrap())
+            }
+        }
+    };
+}
+
+pub const TAG_U8: Tag = 64;
+pub const TAG_U16_LE: Tag = 69;
+pub const TAG_U32_LE: Tag = 70;
+pub const TAG_U64_LE: Tag = 71;
+pub const TAG_I8: Tag = 72;
+pub const TAG_I16_LE: Tag = 77;
+pub const TAG_I32_LE: Tag = 78;
+pub const TAG_I64_LE: Tag = 79;
+pub const TAG_F32_LE: Tag = 85;
+pub const TAG_F64_LE: Tag = 86;
+
+impl_packed_elem!(u8, TAG_U8);
+impl_packed_elem!(u16, TAG_U16_LE);
+impl_packed_elem!(u32, TAG_U32_LE);
+impl_packed_elem!(u64, TAG_U64_LE);
+impl_packed_elem!(i8, TAG_I8);
+impl_packed_elem!(i16, TAG_I16_LE);
+impl_packed_elem!(i32, TAG_I32_LE);
+impl_packed_elem!(i64, TAG_I64_LE);
+impl_packed_elem!(f32, TAG_F32_LE);
+impl_packed_elem!(f64, TAG_F64_LE);
+
+/// Is `tag` the tag of a packed array?
+pub fn is_packed_array_tag(tag: Tag) -> bool {
+    matches!(
+        tag,
+        TAG_U8
+            | TAG_U16_LE
+            | TAG_U32_LE
+            | TAG_U64_LE
+            | TAG_I8
+            | TAG_I16_LE
+            | TAG_I32_LE
+            | TAG_I64_LE
+            | TAG_F32_LE
+            | TAG_F64_LE
+    )
+}
+
+/// View `data` (the elements of a packed array, without padding) as a slice of `T`.
+///
+/// This doesn't copy if `data` is properly aligned and the platform is little-endian.
+pub(crate) fn cast_slice<T: PackedElem>(data: &[u8]) -> Cow<'_, [T]> {
+    debug_assert_eq!(data.len() % T::SIZE, 0);
+    if cfg!(target_endian = "little") && data.as_ptr().align_offset(std::mem::align_of::<T>()) == 0
+    {
+        // SAFETY: the pointer is aligned for `T`, the length is a multiple of
+        // `size_of::<T>()`, every bit pattern is a valid `T`, and `T` is stored
+        // in little-endian which is also the native representation.
+        let slice =
+            unsafe { std::slice::from_raw_parts(data.as_ptr() as *const T, data.len() / T::SIZE) };
+        Cow::Borrowed(slice)
+    } else {
+        Cow::Owned(data.chunks_exact(T::SIZE).map(T::read_le).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Decoder, Encoder};
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_alignment() {
+        let mut res: Vec<u8> = vec![];
+        let mut enc = Encoder::new(&mut res);
+
+        let _ = enc.write_string("x").unwrap();
+        let off = enc.write_f64_array(&[1.0, 2.5, -3.0]).unwrap();
+        let off_i16 = enc.write_i16_array(&[1, -2]).unwrap();
+
+        // the blob must be aligned for the zero-copy path to kick in.
+        let mut aligned: Vec<u64> = vec![0; res.len() / 8 + 1];
+        let buf: &mut [u8] = unsafe {
+            std::slice::from_raw_parts_mut(aligned.as_mut_ptr() as *mut u8, aligned.len() * 8)
+        };
+        buf[..res.len()].copy_from_slice(&res);
+
+        let dec = Decoder::new(&buf[..res.len()]).unwrap();
+        let arr = dec.get_f64_array(off).unwrap();
+        assert_eq!(&arr[..], &[1.0, 2.5, -3.0]);
+        if cfg!(target_endian = "little") {
+            assert!(matches!(arr, Cow::Borrowed(_)));
+        }
+        assert_eq!(&dec.get_i16_array(off_i16).unwrap()[..], &[1, -2]);
+        assert!(dec.get_f32_array(off).is_err());
+
+        // misaligned blob: we fall back to copying.
+        let mut shifted = vec![0u8];
+        shifted.extend_from_slice(&res);
+        let dec = Decoder::new(&shifted[1..]).unwrap();
+        let arr = dec.get_f64_array(off).unwrap();
+        assert!(matches!(arr, Cow::Owned(_)));
+        assert_eq!(&arr[..], &[1.0, 2.5, -3.0]);
+    }
+
+    proptest! {
+        #[test]
+        fn packed_roundtrip(prefix in ".*", xs: Vec<i64>, ys: Vec<f32>, zs: Vec<u16>) {
+            let mut res: Vec<u8> = vec![];
+            let mut enc = Encoder::new(&mut res);
+            let _ = enc.write_string(&prefix).unwrap();
+            let off_xs = enc.write_i64_array(&xs).unwrap();
+            let off_ys = enc.write_f32_array(&ys).unwrap();
+            let off_zs = enc.write_u16_array(&zs).unwrap();
+            let off_arr = enc.write_array(&[off_xs.into(), off_zs.into()]).unwrap();
+
+            let dec = Decoder::new(&res).unwrap();
+            assert_eq!(&dec.get_i64_array(off_xs).unwrap()[..], &xs[..]);
+            let ys2 = dec.get_f32_array(off_ys).unwrap();
+            assert_eq!(ys.len(), ys2.len());
+            assert!(ys.iter().zip(ys2.iter()).all(|(a, b)| a.to_bits() == b.to_bits()));
+
+            let mut offs = vec![];
+            dec.get_array(off_arr, &mut offs).unwrap();
+            assert_eq!(&dec.get_u16_array(offs[1]).unwrap()[..], &zs[..]);
+        }
+    }
+}
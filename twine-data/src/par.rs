@@ -0,0 +1,551 @@
+//! Parallel encoding and decoding.
+//!
+//! ## Encoding
+//!
+//! Since pointers are relative, a value encoded into its own buffer
+//! (starting at offset 0) can be copied anywhere in a blob, as long as it
+//! only points to values in the same buffer (see [`crate::fragment`]).
+//! This module uses this to encode the items of a large array on several threads,
+//! each chunk of items into its own buffer. The buffers are then written into
+//! the encoder in order, and the immediates of the array are translated to
+//! the final offsets of the items.
+//!
+//! The result is byte-for-byte identical to what the sequential encoder produces.
+//! The padding of packed arrays (see [`crate::packed`]) depends on their offset
+//! modulo [`MAX_ALIGN`], so a chunk that contains packed arrays is encoded again
+//! if its final offset doesn't have the same alignment as the offset it was
+//! encoded at. Chunks are encoded in batches, so that only a bounded part of the output
+//! is buffered in memory at any time.
+//!
+//! ## Decoding
+//!
+//! Once the offsets of the items of an array (or map) are known, the items
+//! can be decoded independently. [`read_value_par`] decodes large arrays and maps
+//! in parallel, and [`map_array_par`] and similar functions run user code on each item.
+
+use std::io;
+
+use rayon::prelude::*;
+
+use crate::{
+    shallow_value::ShallowValue,
+    types::{Error, Offset, Result},
+    value::{self, Value},
+    Decoder, Encoder, Immediate,
+};
+
+/// Number of items processed in a single task (eg. encoded in a single buffer).
+pub const CHUNK_LEN: usize = 256;
+
+/// Largest alignment of the elements of packed arrays.
+pub const MAX_ALIGN: Offset = 8;
+
+/// Encoder used for a chunk of items.
+pub type ChunkEncoder<'b> = Encoder<&'b mut Vec<u8>>;
+
+/// A chunk of items, encoded into its own buffer.
+struct Chunk {
+    bytes: Vec<u8>,
+    /// The immediates that refer to the items, as scalar values.
+    imms: Vec<Value>,
+    /// Offset the chunk was encoded at.
+    start: Offset,
+    /// Does the chunk contain packed arrays?
+    packed: bool,
+}
+
+/// Encode `items` into a buffer, as if it started at offset `start`.
+fn encode_chunk<T, F>(items: &[T], start: Offset, f: &F) -> io::Result<Chunk>
+where
+    F: Fn(&mut ChunkEncoder, &T, &mut Vec<Value>) -> io::Result<()>,
+{
+    let mut bytes = vec![];
+    let mut imms = Vec::with_capacity(items.len());
+    let mut enc = Encoder::append_to(start, &mut bytes);
+    for x in items {
+        f(&mut enc, x, &mut imms)?;
+    }
+    let packed = enc.wrote_packed();
+    Ok(Chunk {
+        bytes,
+        imms,
+        start,
+        packed,
+    })
+}
+
+/// Encode `items` in parallel, write them into `enc` in order, and return
+/// the (translated) immediates referring to them.
+fn write_items_par<T, W, F>(
+    enc: &mut Encoder<W>,
+    items: &[T],
+    chunk_len: usize,
+    f: &F,
+) -> io::Result<Vec<Value>>
+where
+    T: Sync,
+    W: io::Write,
+    F: Fn(&mut ChunkEncoder, &T, &mut Vec<Value>) -> io::Result<()> + Sync,
+{
+    let chunk_len = chunk_len.max(1);
+    let batch_len = chunk_len * 4 * rayon::current_num_threads();
+
+    let mut res = Vec::with_capacity(items.len());
+    for batch in items.chunks(batch_len) {
+        let chunks: Vec<Chunk> = batch
+            .par_chunks(chunk_len)
+            .map(|chunk| encode_chunk(chunk, 0, f))
+            .collect::<io::Result<_>>()?;
+        for (mut c, items) in chunks.into_iter().zip(batch.chunks(chunk_len)) {
+            let base = enc.offset();
+            if c.packed && !(base - c.start).is_multiple_of(MAX_ALIGN) {
+                // padding would differ, encode again at the right offset
+                c = encode_chunk(items, base, f)?;
+            }
+            enc.write_raw(&c.bytes)?;
+            res.extend(c.imms.into_iter().map(|v| match v {
+                Value::Pointer(p) => Value::Pointer(base + (p - c.start)),
+                Value::Ref(p) => Value::Ref(base + (p - c.start)),
+                v => v,
+            }));
+        }
+    }
+    Ok(res)
+}
+
+/// Turn scalar values back into immediates.
+fn to_immediates<'a, W: io::Write>(
+    enc: &mut Encoder<W>,
+    vals: &'a [Value],
+) -> io::Result<Vec<Immediate<'a>>> {
+    let mut res = Vec::with_capacity(vals.len());
+    for v in vals {
+        // does not write anything, since `v` is a scalar.
+        res.push(value::write_value_or_imm(enc, v)?);
+    }
+    Ok(res)
+}
+
+/// Write an array whose items are encoded in parallel by `f`.
+///
+/// `f` is called on each item with an encoder for the item's chunk, and returns
+/// the immediate to store in the array. Offsets returned by this encoder are
+/// only valid in the chunk, so `f` must not refer to values written outside of it
+/// (eg. earlier in `enc`, or in another item); they are translated when
+/// the chunk is written into `enc`. `f` may be called more than once on an item,
+/// if the chunk contains packed arrays.
+///
+/// The output is the same as if each item was encoded by `f` directly into `enc`,
+/// in order, followed by [`Encoder::write_array`].
+pub fn write_array_par<T, W, F>(enc: &mut Encoder<W>, items: &[T], f: F) -> io::Result<Offset>
+where
+    T: Sync,
+    W: io::Write,
+    F: for<'x> Fn(&mut ChunkEncoder, &'x T) -> io::Result<Immediate<'x>> + Sync,
+{
+    let vals = write_items_par(enc, items, CHUNK_LEN, &|enc, x, out| {
+        out.push(Value::from(f(enc, x)?));
+        Ok(())
+    })?;
+    let imms = to_immediates(enc, &vals)?;
+    enc.write_array(&imms)
+}
+
+/// Does `v` contain offsets to values that are not part of it?
+fn has_offsets(v: &Value) -> bool {
+    match v {
+        Value::Ref(_) | Value::Pointer(_) => true,
+        Value::Tag(_, v) => has_offsets(v),
+        Value::Array(arr) | Value::Variant(_, arr) => arr.iter().any(has_offsets),
+        Value::Map(map) => map.iter().any(|(k, v)| has_offsets(k) || has_offsets(v)),
+        _ => false,
+    }
+}
+
+fn write_value_or_imm_par<'a, W: io::Write>(
+    enc: &mut Encoder<W>,
+    v: &'a Value,
+    chunk_len: usize,
+) -> io::Result<Immediate<'a>> {
+    let write_one = |enc: &mut ChunkEncoder, x: &Value, out: &mut Vec<Value>| {
+        out.push(Value::from(value::write_value_or_imm(enc, x)?));
+        Ok(())
+    };
+
+    let imm = match v {
+        Value::Tag(tag, v) => {
+            let v = write_value_or_imm_par(enc, v, chunk_len)?;
+            enc.write_tag(*tag, v)?.into()
+        }
+        Value::Array(arr) => {
+            let vals = write_items_par(enc, arr, chunk_len, &write_one)?;
+            let imms = to_immediates(enc, &vals)?;
+            enc.write_array(&imms)?.into()
+        }
+        Value::Map(map) => {
+            let vals = write_items_par(enc, map, chunk_len, &|enc, (k, v), out| {
+                write_one(enc, k, out)?;
+                write_one(enc, v, out)
+            })?;
+            let imms = to_immediates(enc, &vals)?;
+            let pairs: Vec<_> = imms.chunks_exact(2).map(|kv| (kv[0], kv[1])).collect();
+            enc.write_map(&pairs)?.into()
+        }
+        Value::Variant(c, args) => {
+            let vals = write_items_par(enc, args, chunk_len, &write_one)?;
+            let imms = to_immediates(enc, &vals)?;
+            enc.write_variant(*c, &imms)?
+        }
+        _ => value::write_value_or_imm(enc, v)?,
+    };
+    Ok(imm)
+}
+
+fn write_value_par_with<W: io::Write>(
+    enc: &mut Encoder<W>,
+    v: &Value,
+    chunk_len: usize,
+) -> io::Result<Offset> {
+    if has_offsets(v) {
+        // offsets are absolute, they can't be encoded in a separate buffer.
+        return value::write_value(enc, v);
+    }
+    let imm = write_value_or_imm_par(enc, v, chunk_len)?;
+    enc.write_immediate_or_return_pointer(imm)
+}
+
+/// Write a value, encoding the items of its toplevel array (or map, or variant)
+/// in parallel. Return an offset to it.
+///
+/// This produces the same output as [`value::write_value`].
+/// Values that contain [`Value::Ref`] or [`Value::Pointer`] are encoded sequentially.
+pub fn write_value_par<W: io::Write>(enc: &mut Encoder<W>, v: &Value) -> io::Result<Offset> {
+    write_value_par_with(enc, v, CHUNK_LEN)
+}
+
+/// Decode `items` in parallel, in tasks of at least [`CHUNK_LEN`] items.
+fn read_items_par<I, T, F>(items: &[I], f: F) -> Result<Vec<T>>
+where
+    I: Sync,
+    T: Send,
+    F: Fn(&I) -> Result<T> + Sync,
+{
+    items.par_iter().with_min_len(CHUNK_LEN).map(&f).collect()
+}
+
+/// Read a value from a decoder, starting at given offset, decoding
+/// the items of large arrays and maps in parallel.
+///
+/// This returns the same value as [`value::read_value`].
+pub fn read_value_par(d: &Decoder, off: Offset) -> Result<Value> {
+    let v: Value = match d.get_shallow_value(off)? {
+        ShallowValue::Imm(v) => Value::from(v),
+        ShallowValue::Tag(tag, off) => Value::Tag(tag, Box::new(read_value_par(d, off)?)),
+        ShallowValue::Array(arr) => {
+            let offs = arr.collect::<Result<Vec<_>>>()?;
+            Value::Array(read_items_par(&offs, |off| read_value_par(d, *off))?)
+        }
+        ShallowValue::Map(map) => {
+            let pairs = map.collect::<Result<Vec<_>>>()?;
+            Value::Map(read_items_par(&pairs, |(k, v)| {
+                Ok((read_value_par(d, *k)?, read_value_par(d, *v)?))
+            })?)
+        }
+        ShallowValue::Variant(c, args) => {
+            let offs = args.collect::<Result<Vec<_>>>()?;
+            Value::Variant(c, read_items_par(&offs, |off| read_value_par(d, *off))?)
+        }
+    };
+    Ok(v)
+}
+
+/// Find the entrypoint and read a value from it, in parallel.
+///
+/// See [`read_value_par`].
+pub fn read_value_from_entrypoint_par(d: &Decoder) -> Result<Value> {
+    let off = d.entrypoint()?;
+    read_value_par(d, off)
+}
+
+/// Call `f` on the offset of each item of the array at `off`, in parallel,
+/// and collect the results in order.
+pub fn map_array_par<'a, T, E, F>(
+    d: &Decoder<'a>,
+    off: Offset,
+    f: F,
+) -> std::result::Result<Vec<T>, E>
+where
+    T: Send,
+    E: From<Error> + Send,
+    F: Fn(&Decoder<'a>, Offset) -> std::result::Result<T, E> + Sync,
+{
+    let mut offs = vec![];
+    d.get_array(off, &mut offs)?;
+    offs.par_iter()
+        .with_min_len(CHUNK_LEN)
+        .map(|off| f(d, *off))
+        .collect()
+}
+
+/// Call `f` on the offset of each item of the array at `off`, in parallel.
+///
+/// Stops early if `f` fails.
+pub fn for_each_array_par<'a, E, F>(
+    d: &Decoder<'a>,
+    off: Offset,
+    f: F,
+) -> std::result::Result<(), E>
+where
+    E: From<Error> + Send,
+    F: Fn(&Decoder<'a>, Offset) -> std::result::Result<(), E> + Sync,
+{
+    let mut offs = vec![];
+    d.get_array(off, &mut offs)?;
+    offs.par_iter()
+        .with_min_len(CHUNK_LEN)
+        .try_for_each(|off| f(d, *off))
+}
+
+/// Call `f` on the offsets of the key and value of each entry of the map at `off`,
+/// in parallel, and collect the results in order.
+pub fn map_entries_par<'a, T, E, F>(
+    d: &Decoder<'a>,
+    off: Offset,
+    f: F,
+) -> std::result::Result<Vec<T>, E>
+where
+    T: Send,
+    E: From<Error> + Send,
+    F: Fn(&Decoder<'a>, Offset, Offset) -> std::result::Result<T, E> + Sync,
+{
+    let mut pairs = vec![];
+    d.get_dict(off, &mut pairs)?;
+    pairs
+        .par_iter()
+        .with_min_len(CHUNK_LEN)
+        .map(|(k, v)| f(d, *k, *v))
+        .collect()
+}
+
+/// Call `f` on the offsets of the key and value of each entry of the map at `off`,
+/// in parallel.
+///
+/// Stops early if `f` fails.
+pub fn for_each_entry_par<'a, E, F>(
+    d: &Decoder<'a>,
+    off: Offset,
+    f: F,
+) -> std::result::Result<(), E>
+where
+    E: From<Error> + Send,
+    F: Fn(&Decoder<'a>, Offset, Offset) -> std::result::Result<(), E> + Sync,
+{
+    let mut pairs = vec![];
+    d.get_dict(off, &mut pairs)?;
+    pairs
+        .par_iter()
+        .with_min_len(CHUNK_LEN)
+        .try_for_each(|(k, v)| f(d, *k, *v))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{header::Header, value::tests::arb_values};
+    use proptest::prelude::*;
+    use std::sync::atomic::{AtomicI64, Ordering};
+
+    fn seq_and_par(v: &Value, chunk_len: usize) -> (Vec<u8>, Vec<u8>) {
+        let mut seq = vec![];
+        let mut enc = Encoder::with_header(&mut seq, Header::default()).unwrap();
+        let _ = enc.write_string("prefix").unwrap();
+        let off = value::write_value(&mut enc, v).unwrap();
+        enc.finalize(Immediate::Pointer(off)).unwrap();
+
+        let mut par = vec![];
+        let mut enc = Encoder::with_header(&mut par, Header::default()).unwrap();
+        let _ = enc.write_string("prefix").unwrap();
+        let off = write_value_par_with(&mut enc, v, chunk_len).unwrap();
+        enc.finalize(Immediate::Pointer(off)).unwrap();
+        (seq, par)
+    }
+
+    proptest! {
+        #[test]
+        fn same_as_sequential(v in arb_values(), chunk_len in 1..5usize) {
+            let v = Value::Array(vec![v.clone(), Value::Int64(1), v]);
+            let (seq, par) = seq_and_par(&v, chunk_len);
+            prop_assert_eq!(seq, par);
+        }
+
+        #[test]
+        fn read_par_same_as_sequential(v in arb_values()) {
+            let mut res = vec![];
+            let mut enc = Encoder::new(&mut res);
+            let off = value::write_value(&mut enc, &v).unwrap();
+            let dec = Decoder::new(&res).unwrap();
+            prop_assert_eq!(read_value_par(&dec, off).unwrap(), v);
+        }
+    }
+
+    #[test]
+    fn test_write_value_par() {
+        let rec = |i: i64| {
+            Value::Map(vec![
+                (Value::String("id".to_string()), Value::Int64(i)),
+                (
+                    Value::String("name".to_string()),
+                    Value::String(format!("record {i}")),
+                ),
+                (
+                    Value::String("data".to_string()),
+                    Value::Bytes(vec![i as u8; (i % 40) as usize]),
+                ),
+            ])
+        };
+        let v = Value::Tag(3, Box::new(Value::Array((0..5000).map(rec).collect())));
+        let (seq, par) = seq_and_par(&v, 7);
+        assert_eq!(seq, par);
+
+        let dec = Decoder::new(&par).unwrap();
+        assert_eq!(value::read_value_from_entrypoint(&dec).unwrap(), v);
+    }
+
+    #[test]
+    fn test_with_pointers() {
+        let v = Value::Array(vec![Value::Pointer(0), Value::Ref(0), Value::Int64(1)]);
+        let (seq, par) = seq_and_par(&v, 1);
+        assert_eq!(seq, par);
+    }
+
+    struct Record {
+        id: i64,
+        name: String,
+    }
+
+    fn write_rec<W: io::Write>(enc: &mut Encoder<W>, r: &Record) -> io::Result<Offset> {
+        let name = if r.id % 2 == 0 {
+            Immediate::String(&r.name)
+        } else {
+            Immediate::Pointer(enc.write_string(&r.name)?)
+        };
+        enc.write_array(&[Immediate::Int64(r.id), name])
+    }
+
+    #[test]
+    fn test_write_array_par() {
+        let records: Vec<_> = (0..1000)
+            .map(|id| Record {
+                id,
+                name: format!("this is record number {id}"),
+            })
+            .collect();
+
+        let mut par = vec![];
+        let mut enc = Encoder::new(&mut par);
+        let _ = enc.write_string("prefix").unwrap();
+        let off = write_array_par(&mut enc, &records, |enc, r| {
+            Ok(if r.id % 3 == 0 {
+                Immediate::String(&r.name)
+            } else {
+                Immediate::Pointer(write_rec(enc, r)?)
+            })
+        })
+        .unwrap();
+        enc.finalize(Immediate::Pointer(off)).unwrap();
+
+        let mut seq = vec![];
+        let mut enc = Encoder::new(&mut seq);
+        let _ = enc.write_string("prefix").unwrap();
+        let mut imms = vec![];
+        for r in &records {
+            imms.push(if r.id % 3 == 0 {
+                Immediate::String(&r.name)
+            } else {
+                Immediate::Pointer(write_rec(&mut enc, r).unwrap())
+            });
+        }
+        let off = enc.write_array(&imms).unwrap();
+        enc.finalize(Immediate::Pointer(off)).unwrap();
+
+        assert_eq!(seq, par);
+    }
+
+    #[test]
+    fn test_write_array_par_packed() {
+        // some chunks have packed arrays of various alignments, some have none
+        let items: Vec<u64> = (0..3000).collect();
+        fn write<'x>(enc: &mut ChunkEncoder, i: &'x u64) -> io::Result<Immediate<'x>> {
+            let xs: Vec<u64> = (0..i % 7).collect();
+            Ok(Immediate::Pointer(match i % 1000 {
+                0..400 => enc.write_string(&"s".repeat((i % 5) as usize))?,
+                400..700 => enc.write_u64_array(&xs)?,
+                _ => {
+                    let ys: Vec<u16> = xs.iter().map(|x| *x as u16).collect();
+                    let _ = enc.write_string("x")?;
+                    enc.write_u16_array(&ys)?
+                }
+            }))
+        }
+
+        for prefix in ["", "odd", "four"] {
+            let mut par = vec![];
+            let mut enc = Encoder::new(&mut par);
+            let _ = enc.write_string(prefix).unwrap();
+            let off = write_array_par(&mut enc, &items, write).unwrap();
+            enc.finalize(Immediate::Pointer(off)).unwrap();
+
+            let mut seq = vec![];
+            let mut enc = Encoder::new(&mut seq);
+            let _ = enc.write_string(prefix).unwrap();
+            let mut imms = vec![];
+            for i in &items {
+                imms.push(write(&mut enc, i).unwrap());
+            }
+            let off = enc.write_array(&imms).unwrap();
+            enc.finalize(Immediate::Pointer(off)).unwrap();
+            assert_eq!(seq, par);
+        }
+    }
+
+    #[test]
+    fn test_map_array_par() {
+        let n = 10_000i64;
+        let v = Value::Array(
+            (0..n)
+                .map(|i| Value::Map(vec![(Value::String("x".to_string()), Value::Int64(i))]))
+                .collect(),
+        );
+        let mut res = vec![];
+        let mut enc = Encoder::new(&mut res);
+        let off = value::write_value(&mut enc, &v).unwrap();
+        enc.finalize(Immediate::Pointer(off)).unwrap();
+
+        let dec = Decoder::new(&res).unwrap();
+        assert_eq!(read_value_from_entrypoint_par(&dec).unwrap(), v);
+
+        let xs: Vec<i64> = map_array_par(&dec, off, |dec, item| {
+            map_entries_par(dec, item, |dec, _, v| dec.get_i64(v)).map(|x: Vec<i64>| x[0])
+        })
+        .unwrap();
+        assert_eq!(xs, (0..n).collect::<Vec<_>>());
+
+        let sum = AtomicI64::new(0);
+        for_each_array_par(&dec, off, |dec, item| {
+            for_each_entry_par(dec, item, |dec, _, v| {
+                sum.fetch_add(dec.get_i64(v)?, Ordering::Relaxed);
+                Ok::<_, Error>(())
+            })
+        })
+        .unwrap();
+        assert_eq!(sum.into_inner(), n * (n - 1) / 2);
+
+        // errors are propagated
+        let r: Result<Vec<()>> = map_array_par(&dec, off, |dec, item| {
+            let _ = dec.get_str(item)?;
+            Ok(())
+        });
+        assert_eq!(r.unwrap_err().msg, "expected string");
+        assert!(for_each_array_par(&dec, 0, |_, _| Ok::<_, Error>(())).is_err());
+    }
+}
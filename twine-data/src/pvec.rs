@@ -0,0 +1,566 @@
+//! Persistent vectors.
+//!
+//! A [`PVec`] is a vector stored as a 32-way trie of twine arrays: leaves
+//! are arrays of up to [`NODE_LEN`] elements, and inner nodes are arrays
+//! of up to [`NODE_LEN`] pointers to their children. All the leaves are
+//! at the same depth, and are full except for the last one.
+//!
+//! Updates use path copying (see [`crate::edit`]): pushing or replacing elements
+//! appends new copies of the nodes on the path to the modified leaves, and reuses
+//! all the other nodes. Appending to a large list thus only writes a
+//! few small arrays, instead of the whole list.
+//!
+//! A vector is stored as a tag [`TAG_PVEC`] around the array `[len, depth, root]`,
+//! where `depth` is the number of inner nodes between the root and the leaves,
+//! and `root` points to the root node.
+
+use std::{io, ops::RangeBounds};
+
+use crate::{
+    edit::reuse_immediate,
+    shallow_value::ShallowValue,
+    types::{Error, Offset, Tag},
+    Decoder, Encoder, Immediate, Result,
+};
+
+/// Tag of a persistent vector.
+pub const TAG_PVEC: Tag = 0x7477_0005;
+
+/// Maximum number of children of a node.
+pub const NODE_LEN: usize = 32;
+
+const BITS: u32 = NODE_LEN.trailing_zeros();
+
+/// Number of elements in a full node of the given height (0 for leaves).
+fn capacity(height: u32) -> u64 {
+    1u64.checked_shl(BITS * (height + 1)).unwrap_or(u64::MAX)
+}
+
+fn invalid(msg: &'static str, off: Offset) -> Error {
+    Error { msg, off }
+}
+
+fn read_node(dec: &Decoder, off: Offset) -> Result<Vec<Offset>> {
+    match dec.get_shallow_value(off)? {
+        ShallowValue::Array(arr) if arr.len() <= NODE_LEN as u64 => arr.collect(),
+        _ => Err(invalid("invalid pvec node", off)),
+    }
+}
+
+/// A persistent vector in a blob.
+///
+/// This is only a handle to the vector; the elements are read from a
+/// [`Decoder`] for the blob, and updates are appended to it with an [`Encoder`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PVec {
+    off: Offset,
+    len: u64,
+    depth: u32,
+    root: Offset,
+}
+
+/// The rightmost node of one level of the trie, being updated.
+#[derive(Debug, Default)]
+struct Level<'a> {
+    /// Elements (for leaves) or pointers to children, except the child that
+    /// is itself being updated.
+    entries: Vec<Immediate<'a>>,
+}
+
+impl PVec {
+    /// Read the vector at `off`.
+    pub fn read(dec: &Decoder, off: Offset) -> Result<Self> {
+        let off = dec.deref(off)?;
+        let ShallowValue::Tag(TAG_PVEC, inner) = dec.get_shallow_value(off)? else {
+            return Err(invalid("expected pvec", off));
+        };
+        let ShallowValue::Array(arr) = dec.get_shallow_value(inner)? else {
+            return Err(invalid("invalid pvec", off));
+        };
+        let fields = arr.collect::<Result<Vec<_>>>()?;
+        let [len, depth, root] = fields[..] else {
+            return Err(invalid("invalid pvec", off));
+        };
+        let (
+            ShallowValue::Imm(Immediate::Int64(len @ 0..)),
+            ShallowValue::Imm(Immediate::Int64(depth @ 0..12)),
+        ) = (dec.get_shallow_value(len)?, dec.get_shallow_value(depth)?)
+        else {
+            return Err(invalid("invalid pvec", off));
+        };
+        let depth = depth as u32;
+        if len as u64 > capacity(depth) {
+            return Err(invalid("invalid pvec", off));
+        }
+        Ok(PVec {
+            off,
+            len: len as u64,
+            depth,
+            root: dec.deref(root)?,
+        })
+    }
+
+    /// Write an empty vector.
+    pub fn write_empty<W: io::Write>(enc: &mut Encoder<W>) -> io::Result<Self> {
+        let root = enc.write_array(&[])?;
+        Self::write_header(enc, 0, 0, root)
+    }
+
+    fn write_header<W: io::Write>(
+        enc: &mut Encoder<W>,
+        len: u64,
+        depth: u32,
+        root: Offset,
+    ) -> io::Result<Self> {
+        let arr = enc.write_array(&[
+            Immediate::Int64(len as i64),
+            Immediate::Int64(depth as i64),
+            Immediate::Pointer(root),
+        ])?;
+        let off = enc.write_tag(TAG_PVEC, Immediate::Pointer(arr))?;
+        Ok(PVec {
+            off,
+            len,
+            depth,
+            root,
+        })
+    }
+
+    /// Offset of the vector.
+    #[inline]
+    pub fn offset(&self) -> Offset {
+        self.off
+    }
+
+    #[inline]
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Offsets of the elements of the leaf that contains element `i`.
+    fn leaf(&self, dec: &Decoder, i: u64) -> Result<Vec<Offset>> {
+        let mut node = self.root;
+        for h in (1..=self.depth).rev() {
+            let children = read_node(dec, node)?;
+            let j = ((i >> (BITS * h)) as usize) % NODE_LEN;
+            let child = *children
+                .get(j)
+                .ok_or_else(|| invalid("pvec node is too short", node))?;
+            node = dec.deref(child)?;
+        }
+        read_node(dec, node)
+    }
+
+    /// Offset of the element at index `i`, or `None` if `i` is out of bounds.
+    pub fn get(&self, dec: &Decoder, i: u64) -> Result<Option<Offset>> {
+        if i >= self.len {
+            return Ok(None);
+        }
+        let leaf = self.leaf(dec, i)?;
+        match leaf.get(i as usize % NODE_LEN) {
+            Some(off) => Ok(Some(dec.deref(*off)?)),
+            None => Err(invalid("pvec node is too short", self.root)),
+        }
+    }
+
+    /// Iterate over the offsets of the elements in `range`.
+    ///
+    /// The range is clamped to the length of the vector.
+    pub fn slice<'a, 'd>(
+        &self,
+        dec: &'d Decoder<'a>,
+        range: impl RangeBounds<u64>,
+    ) -> Iter<'a, 'd> {
+        use std::ops::Bound;
+        let start = match range.start_bound() {
+            Bound::Included(i) => *i,
+            Bound::Excluded(i) => i.saturating_add(1),
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(i) => i.saturating_add(1),
+            Bound::Excluded(i) => *i,
+            Bound::Unbounded => self.len,
+        };
+        let end = end.min(self.len);
+        Iter {
+            dec,
+            vec: *self,
+            next: start.min(end),
+            end,
+            leaf: vec![],
+        }
+    }
+
+    /// Iterate over the offsets of all the elements.
+    pub fn iter<'a, 'd>(&self, dec: &'d Decoder<'a>) -> Iter<'a, 'd> {
+        self.slice(dec, ..)
+    }
+
+    /// Replace the element at index `i` with `v`.
+    ///
+    /// The encoder must append to the blob read by `dec`. Returns the new version
+    /// of the vector; this one is unchanged.
+    pub fn set<W: io::Write>(
+        &self,
+        enc: &mut Encoder<W>,
+        dec: &Decoder,
+        i: u64,
+        v: Immediate,
+    ) -> io::Result<Self> {
+        if i >= self.len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "index out of bounds",
+            ));
+        }
+
+        // read the path from the root to the leaf
+        let mut path = vec![];
+        let mut node = self.root;
+        for h in (0..=self.depth).rev() {
+            let children = read_node(dec, node)?;
+            let j = ((i >> (BITS * h)) as usize) % NODE_LEN;
+            if j >= children.len() {
+                return Err(invalid("pvec node is too short", node).into());
+            }
+            if h > 0 {
+                node = dec.deref(children[j])?;
+            }
+            path.push((children, j));
+        }
+
+        let mut new_child = v;
+        let mut leaf = true;
+        while let Some((children, j)) = path.pop() {
+            let mut items = Vec::with_capacity(children.len());
+            for (k, c) in children.into_iter().enumerate() {
+                items.push(if k == j {
+                    new_child
+                } else if leaf {
+                    reuse_immediate(dec, c)?
+                } else {
+                    Immediate::Pointer(dec.deref(c)?)
+                });
+            }
+            new_child = Immediate::Pointer(enc.write_array(&items)?);
+            leaf = false;
+        }
+        let Immediate::Pointer(root) = new_child else {
+            unreachable!()
+        };
+        Self::write_header(enc, self.len, self.depth, root)
+    }
+
+    /// Push `v` at the end of the vector.
+    ///
+    /// The encoder must append to the blob read by `dec`. Returns the new version
+    /// of the vector; this one is unchanged.
+    pub fn push<W: io::Write>(
+        &self,
+        enc: &mut Encoder<W>,
+        dec: &Decoder,
+        v: Immediate,
+    ) -> io::Result<Self> {
+        self.extend(enc, dec, [v])
+    }
+
+    /// Push all the items of `vs` at the end of the vector.
+    ///
+    /// This is more efficient than repeated calls to [`PVec::push`], since
+    /// the nodes on the right edge of the trie are written only once.
+    pub fn extend<'a, W: io::Write>(
+        &self,
+        enc: &mut Encoder<W>,
+        dec: &Decoder<'a>,
+        vs: impl IntoIterator<Item = Immediate<'a>>,
+    ) -> io::Result<Self> {
+        let mut vs = vs.into_iter().peekable();
+        if vs.peek().is_none() {
+            return Ok(*self);
+        }
+
+        let mut levels = self.load_right_edge(dec)?;
+        let mut len = self.len;
+        for v in vs {
+            add_to_level(enc, &mut levels, 0, v)?;
+            len += 1;
+        }
+
+        // write the right edge, bottom-up
+        let mut child = None;
+        let top = levels.len() - 1;
+        for level in &mut levels[..top] {
+            let mut entries = std::mem::take(&mut level.entries);
+            entries.extend(child.map(Immediate::Pointer));
+            child = if entries.is_empty() {
+                None
+            } else {
+                Some(enc.write_array(&entries)?)
+            };
+        }
+        let mut entries = std::mem::take(&mut levels[top].entries);
+        entries.extend(child.map(Immediate::Pointer));
+        match entries[..] {
+            // a root with a single child is replaced by the child
+            [Immediate::Pointer(root)] if top > 0 => {
+                Self::write_header(enc, len, top as u32 - 1, root)
+            }
+            _ => {
+                let root = enc.write_array(&entries)?;
+                Self::write_header(enc, len, top as u32, root)
+            }
+        }
+    }
+
+    /// Load the rightmost nodes of each level that are not full.
+    fn load_right_edge<'a>(&self, dec: &Decoder<'a>) -> Result<Vec<Level<'a>>> {
+        let mut levels: Vec<Level> = (0..=self.depth).map(|_| Level::default()).collect();
+        if self.len > 0 && self.len == capacity(self.depth) {
+            // the root is full, it will become the first child of a new root
+            levels.push(Level {
+                entries: vec![Immediate::Pointer(self.root)],
+            });
+            return Ok(levels);
+        }
+
+        let mut node = self.root;
+        let mut remaining = self.len;
+        for h in (0..=self.depth).rev() {
+            let children = read_node(dec, node)?;
+            let level = &mut levels[h as usize];
+            if h == 0 {
+                if children.len() as u64 != remaining {
+                    return Err(invalid("invalid pvec leaf", node));
+                }
+                for c in children {
+                    level.entries.push(reuse_immediate(dec, c)?);
+                }
+                break;
+            }
+
+            let n_full = (remaining / capacity(h - 1)) as usize;
+            remaining %= capacity(h - 1);
+            if children.len() != n_full + (remaining > 0) as usize {
+                return Err(invalid("invalid pvec node", node));
+            }
+            for c in &children[..n_full] {
+                level.entries.push(Immediate::Pointer(dec.deref(*c)?));
+            }
+            if remaining == 0 {
+                break;
+            }
+            node = dec.deref(children[n_full])?;
+        }
+        Ok(levels)
+    }
+}
+
+/// Add `v` to the rightmost node at height `h`, and write the node as soon as it is full.
+fn add_to_level<'a, W: io::Write>(
+    enc: &mut Encoder<W>,
+    levels: &mut Vec<Level<'a>>,
+    h: usize,
+    v: Immediate<'a>,
+) -> io::Result<()> {
+    levels[h].entries.push(v);
+    if levels[h].entries.len() == NODE_LEN {
+        let node = enc.write_array(&levels[h].entries)?;
+        levels[h].entries.clear();
+        if h + 1 == levels.len() {
+            levels.push(Level::default());
+        }
+        add_to_level(enc, levels, h + 1, Immediate::Pointer(node))?;
+    }
+    Ok(())
+}
+
+/// Iterator over the offsets of a range of elements of a [`PVec`].
+#[derive(Debug)]
+pub struct Iter<'a, 'd> {
+    dec: &'d Decoder<'a>,
+    vec: PVec,
+    next: u64,
+    end: u64,
+    /// The leaf that contains `next`, if it was read already.
+    leaf: Vec<Offset>,
+}
+
+impl Iterator for Iter<'_, '_> {
+    type Item = Result<Offset>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next >= self.end {
+            return None;
+        }
+        let i = self.next;
+        if self.leaf.is_empty() || i.is_multiple_of(NODE_LEN as u64) {
+            match self.vec.leaf(self.dec, i) {
+                Ok(leaf) => self.leaf = leaf,
+                Err(e) => {
+                    self.next = self.end;
+                    return Some(Err(e));
+                }
+            }
+        }
+        self.next += 1;
+        match self.leaf.get((i % NODE_LEN as u64) as usize) {
+            Some(off) => Some(self.dec.deref(*off)),
+            None => {
+                self.next = self.end;
+                Some(Err(invalid("pvec node is too short", self.vec.root)))
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let n = usize::try_from(self.end - self.next);
+        (n.unwrap_or(usize::MAX), n.ok())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::value::{self, Value};
+
+    fn elem(i: u64) -> Value {
+        if i.is_multiple_of(2) {
+            Value::Int64(i as i64)
+        } else {
+            Value::String(format!("message number {i}"))
+        }
+    }
+
+    /// Apply `f` to the vector at the entrypoint of `blob`, and append the result.
+    fn update(
+        blob: &mut Vec<u8>,
+        f: impl FnOnce(&mut Encoder<&mut Vec<u8>>, &Decoder, PVec) -> PVec,
+    ) {
+        let mut suffix = vec![];
+        {
+            let dec = Decoder::new(blob).unwrap();
+            let v = PVec::read(&dec, dec.entrypoint().unwrap()).unwrap();
+            let mut enc = Encoder::append_to_blob(&dec, &mut suffix);
+            let v = f(&mut enc, &dec, v);
+            enc.finalize(Immediate::Pointer(v.offset())).unwrap();
+        }
+        blob.extend_from_slice(&suffix);
+    }
+
+    fn check(blob: &[u8], expected: &[Value]) {
+        let dec = Decoder::new(blob).unwrap();
+        let v = PVec::read(&dec, dec.entrypoint().unwrap()).unwrap();
+        assert_eq!(v.len(), expected.len() as u64);
+        let all = v
+            .iter(&dec)
+            .map(|off| value::read_value(&dec, off.unwrap()).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(all, expected);
+
+        let n = expected.len() as u64;
+        for i in [0, 1, 31, 32, 33, n / 2, n.saturating_sub(1), n, n + 10] {
+            let got = v.get(&dec, i).unwrap();
+            let got = got.map(|off| value::read_value(&dec, off).unwrap());
+            assert_eq!(got.as_ref(), expected.get(i as usize));
+        }
+        let (lo, hi) = (n / 3, 2 * n / 3 + 5);
+        let sl = v
+            .slice(&dec, lo..hi)
+            .map(|off| value::read_value(&dec, off.unwrap()).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(sl, expected[lo as usize..(hi.min(n) as usize)]);
+    }
+
+    #[test]
+    fn test_pvec() {
+        let mut blob = vec![];
+        let mut enc = Encoder::new(&mut blob);
+        let v = PVec::write_empty(&mut enc).unwrap();
+        enc.finalize(Immediate::Pointer(v.offset())).unwrap();
+        check(&blob, &[]);
+
+        // one element at a time, across several levels of the trie
+        let mut expected = vec![];
+        for i in 0..1100 {
+            let e = elem(i);
+            update(&mut blob, |enc, dec, v| {
+                let imm = value::write_value_or_imm(enc, &e).unwrap();
+                v.push(enc, dec, imm).unwrap()
+            });
+            expected.push(e);
+            if [0, 1, 31, 32, 33, 1023, 1024, 1025].contains(&i) {
+                check(&blob, &expected);
+            }
+        }
+        check(&blob, &expected);
+
+        // pushing writes a few small nodes, not the whole vector
+        let len_before = blob.len();
+        update(&mut blob, |enc, dec, v| {
+            v.push(enc, dec, Immediate::Int64(-1)).unwrap()
+        });
+        assert!(blob.len() - len_before < 200);
+        expected.push(Value::Int64(-1));
+
+        // in bulk
+        let more: Vec<_> = (0..2000).map(|i| Value::Int64(i * 3)).collect();
+        update(&mut blob, |enc, dec, v| {
+            let imms: Vec<_> = more
+                .iter()
+                .map(|e| value::write_value_or_imm(enc, e).unwrap())
+                .collect();
+            v.extend(enc, dec, imms).unwrap()
+        });
+        expected.extend(more);
+        check(&blob, &expected);
+
+        for i in [0, 5, 32, 1024, 3000, expected.len() as u64 - 1] {
+            let e = Value::String(format!("replaced {i}"));
+            update(&mut blob, |enc, dec, v| {
+                let imm = value::write_value_or_imm(enc, &e).unwrap();
+                v.set(enc, dec, i, imm).unwrap()
+            });
+            expected[i as usize] = e;
+        }
+        check(&blob, &expected);
+
+        let dec = Decoder::new(&blob).unwrap();
+        let v = PVec::read(&dec, dec.entrypoint().unwrap()).unwrap();
+        let mut suffix = vec![];
+        let mut enc = Encoder::append_to_blob(&dec, &mut suffix);
+        assert!(v.set(&mut enc, &dec, v.len(), Immediate::Null).is_err());
+        assert!(PVec::read(&dec, 0).is_err());
+    }
+
+    #[test]
+    fn test_pvec_full_root() {
+        // exactly fill a leaf, then a two-level trie, in bulk
+        for n in [32u64, 1024] {
+            let mut blob = vec![];
+            let mut enc = Encoder::new(&mut blob);
+            let v = PVec::write_empty(&mut enc).unwrap();
+            enc.finalize(Immediate::Pointer(v.offset())).unwrap();
+            let expected: Vec<_> = (0..n).map(|i| Value::Int64(i as i64)).collect();
+            update(&mut blob, |enc, dec, v| {
+                v.extend(enc, dec, (0..n).map(|i| Immediate::Int64(i as i64)))
+                    .unwrap()
+            });
+            check(&blob, &expected);
+
+            let len_before = blob.len();
+            update(&mut blob, |enc, dec, v| {
+                v.push(enc, dec, Immediate::Null).unwrap()
+            });
+            assert!(blob.len() - len_before < 100);
+            let mut expected = expected;
+            expected.push(Value::Null);
+            check(&blob, &expected);
+        }
+    }
+}
@@ -0,0 +1,416 @@
+//! Append-only logs of records.
+//!
+//! A [`RecordLog`] stores many twine blobs (the records) in a single file,
+//! one after the other. The file starts with [`MAGIC`], followed by the records;
+//! each record is framed as:
+//! - the length `n` of the blob, as 4 little-endian bytes;
+//! - the CRC32C checksum of the length, as 4 little-endian bytes;
+//! - the CRC32C checksum of the length and the blob, as 4 little-endian bytes;
+//! - the `n` bytes of the blob.
+//!
+//! Records are numbered from 0, in the order they were appended. When the log
+//! is opened, it is scanned to build an index of the records, which allows
+//! reading any record by its number.
+//!
+//! A crash while appending can leave a partial record at the end of the file,
+//! possibly followed by garbage (eg. zeros, if the length of the file was updated
+//! but not its content). Such a torn record is detected when the log is opened,
+//! and truncated: its frame is cut short by the end of the file, or one of its
+//! checksums doesn't match and no valid frame follows it. A record whose checksums
+//! don't match but that is followed by a valid record was corrupted after it was
+//! written: opening the log then fails, and nothing is truncated.
+
+use std::{
+    fs::File,
+    io::{self, BufReader, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use crate::{
+    checksum::{self, Crc32c},
+    storage::read_exact_at,
+    Decoder, Encoder, Immediate,
+};
+
+/// Magic bytes at the beginning of a log file.
+pub const MAGIC: [u8; 8] = *b"\xd9twlog\x00\x01";
+
+/// Length of the frame before each record, in bytes.
+const FRAME_HEADER_LEN: u64 = 12;
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn len_crc(len: [u8; 4]) -> u32 {
+    checksum::crc32c(&len)
+}
+
+fn frame_crc(len: [u8; 4], blob: &[u8]) -> u32 {
+    let mut crc = Crc32c::new();
+    crc.update(&len);
+    crc.update(blob);
+    crc.finish()
+}
+
+/// Is there a valid frame that starts after `start` in `file`?
+///
+/// This moves the position of the file.
+fn has_valid_frame_after(file: &File, start: u64, file_len: u64) -> io::Result<bool> {
+    let mut r = BufReader::new(file);
+    let mut pos = start + 1;
+    if file_len.saturating_sub(pos) < FRAME_HEADER_LEN {
+        return Ok(false);
+    }
+    r.seek(SeekFrom::Start(pos))?;
+    let mut header = [0u8; FRAME_HEADER_LEN as usize];
+    r.read_exact(&mut header)?;
+    let mut blob = vec![];
+    loop {
+        let len_bytes: [u8; 4] = header[..4].try_into().unwrap();
+        let len = u32::from_le_bytes(len_bytes) as u64;
+        if len_crc(len_bytes) == u32::from_le_bytes(header[4..8].try_into().unwrap())
+            && pos + FRAME_HEADER_LEN + len <= file_len
+        {
+            blob.resize(len as usize, 0);
+            read_exact_at(file, &mut blob, pos + FRAME_HEADER_LEN)?;
+            if frame_crc(len_bytes, &blob) == u32::from_le_bytes(header[8..].try_into().unwrap()) {
+                return Ok(true);
+            }
+            r.seek(SeekFrom::Start(pos + FRAME_HEADER_LEN))?;
+        }
+
+        pos += 1;
+        if pos + FRAME_HEADER_LEN > file_len {
+            return Ok(false);
+        }
+        header.copy_within(1.., 0);
+        r.read_exact(&mut header[FRAME_HEADER_LEN as usize - 1..])?;
+    }
+}
+
+/// An append-only log of twine records, in a file.
+#[derive(Debug)]
+pub struct RecordLog {
+    file: File,
+    /// Offset of the frame of each record.
+    index: Vec<u64>,
+    /// End of the last record.
+    end: u64,
+    /// Number of bytes truncated when opening.
+    truncated: u64,
+    /// Buffer to encode records, reused across records.
+    buf: Vec<u8>,
+}
+
+impl RecordLog {
+    /// Open the log at `path`, creating it if needed.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        Self::from_file(file)
+    }
+
+    /// Use `file`, which must be opened for reading and writing,
+    /// as a log. An empty file is initialized as an empty log.
+    ///
+    /// This scans the file and truncates a torn record at the end, if any.
+    pub fn from_file(mut file: File) -> io::Result<Self> {
+        let file_len = file.metadata()?.len();
+        if file_len == 0 {
+            file.write_all(&MAGIC)?;
+            file.sync_data()?;
+        }
+        let file_len = file_len.max(MAGIC.len() as u64);
+
+        file.seek(SeekFrom::Start(0))?;
+        let mut r = BufReader::new(&mut file);
+        let mut magic = [0u8; MAGIC.len()];
+        r.read_exact(&mut magic)
+            .map_err(|_| invalid("not a record log"))?;
+        if magic != MAGIC {
+            return Err(invalid("not a record log"));
+        }
+
+        let mut index = vec![];
+        let mut end = MAGIC.len() as u64;
+        let mut blob = vec![];
+        while end < file_len {
+            let mut header = [0u8; FRAME_HEADER_LEN as usize];
+            if file_len - end < FRAME_HEADER_LEN {
+                break;
+            }
+            r.read_exact(&mut header)?;
+            let len_bytes: [u8; 4] = header[..4].try_into().unwrap();
+            let len_ok = len_crc(len_bytes) == u32::from_le_bytes(header[4..8].try_into().unwrap());
+            let len = u32::from_le_bytes(len_bytes) as u64;
+            let crc = u32::from_le_bytes(header[8..].try_into().unwrap());
+            let frame_end = end + FRAME_HEADER_LEN + len;
+            if len_ok && frame_end > file_len {
+                // the blob was only partially written
+                break;
+            }
+
+            let frame_ok = len_ok && {
+                blob.clear();
+                blob.resize(len as usize, 0);
+                r.read_exact(&mut blob)?;
+                frame_crc(len_bytes, &blob) == crc
+            };
+            if !frame_ok {
+                if has_valid_frame_after(r.get_ref(), end, file_len)? {
+                    return Err(invalid(if len_ok {
+                        "corrupted record"
+                    } else {
+                        "corrupted record length"
+                    }));
+                }
+                // the last record was only partially written
+                break;
+            }
+
+            index.push(end);
+            end = frame_end;
+        }
+        drop(r);
+
+        if end < file_len {
+            file.set_len(end)?;
+            file.sync_data()?;
+        }
+        file.seek(SeekFrom::Start(end))?;
+
+        Ok(RecordLog {
+            file,
+            index,
+            end,
+            truncated: file_len - end,
+            buf: vec![],
+        })
+    }
+
+    /// Number of records.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    /// Number of bytes of a torn record that were truncated when opening the log.
+    #[inline]
+    pub fn truncated_bytes(&self) -> u64 {
+        self.truncated
+    }
+
+    /// Append a blob as a new record, and return its number.
+    ///
+    /// The record is not durable until [`RecordLog::sync`] is called.
+    pub fn append_blob(&mut self, blob: &[u8]) -> io::Result<usize> {
+        let _ = Decoder::new(blob)?;
+        let len: u32 = blob
+            .len()
+            .try_into()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "record too large"))?;
+        let len_bytes = len.to_le_bytes();
+        let mut header = [0u8; FRAME_HEADER_LEN as usize];
+        header[..4].copy_from_slice(&len_bytes);
+        header[4..8].copy_from_slice(&len_crc(len_bytes).to_le_bytes());
+        header[8..].copy_from_slice(&frame_crc(len_bytes, blob).to_le_bytes());
+
+        // reads may have moved the position of the file
+        let res = self
+            .file
+            .seek(SeekFrom::Start(self.end))
+            .and_then(|_| self.file.write_all(&header))
+            .and_then(|()| self.file.write_all(blob));
+        if let Err(e) = res {
+            // remove the partial record, so we can keep appending
+            let _ = self.file.set_len(self.end);
+            let _ = self.file.seek(SeekFrom::Start(self.end));
+            return Err(e);
+        }
+
+        self.index.push(self.end);
+        self.end += FRAME_HEADER_LEN + len as u64;
+        Ok(self.index.len() - 1)
+    }
+
+    /// Encode a record with `f`, append it, and return its number.
+    ///
+    /// `f` returns the entrypoint of the record.
+    pub fn append_with<F>(&mut self, f: F) -> io::Result<usize>
+    where
+        F: for<'x> FnOnce(&mut Encoder<&'x mut Vec<u8>>) -> io::Result<Immediate<'static>>,
+    {
+        let mut buf = std::mem::take(&mut self.buf);
+        buf.clear();
+        let res = (|| {
+            let mut enc = Encoder::new(&mut buf);
+            let entrypoint = f(&mut enc)?;
+            enc.finalize(entrypoint)?;
+            self.append_blob(&buf)
+        })();
+        self.buf = buf;
+        res
+    }
+
+    /// Read record number `n` into `buf`, and return a decoder for it.
+    pub fn read<'b>(&self, n: usize, buf: &'b mut Vec<u8>) -> io::Result<Decoder<'b>> {
+        let off = *self
+            .index
+            .get(n)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no such record"))?;
+        let mut header = [0u8; FRAME_HEADER_LEN as usize];
+        read_exact_at(&self.file, &mut header, off)?;
+        let len_bytes: [u8; 4] = header[..4].try_into().unwrap();
+        let crc = u32::from_le_bytes(header[8..].try_into().unwrap());
+
+        buf.clear();
+        buf.resize(u32::from_le_bytes(len_bytes) as usize, 0);
+        read_exact_at(&self.file, buf, off + FRAME_HEADER_LEN)?;
+        if frame_crc(len_bytes, buf) != crc {
+            return Err(invalid("corrupted record"));
+        }
+        Ok(Decoder::new(buf)?)
+    }
+
+    /// Make the records appended so far durable.
+    pub fn sync(&self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::value::{self, Value};
+
+    fn record(i: i64) -> Value {
+        Value::Map(vec![
+            (Value::String("seq".to_string()), Value::Int64(i)),
+            (
+                Value::String("event".to_string()),
+                Value::String(format!("event number {i}")),
+            ),
+        ])
+    }
+
+    fn append(log: &mut RecordLog, i: i64) -> usize {
+        log.append_with(|enc| Ok(Immediate::Pointer(value::write_value(enc, &record(i))?)))
+            .unwrap()
+    }
+
+    fn check(log: &RecordLog, n: usize) {
+        assert_eq!(log.len(), n);
+        let mut buf = vec![];
+        for i in [0, n / 2, n - 1] {
+            let dec = log.read(i, &mut buf).unwrap();
+            assert_eq!(
+                value::read_value_from_entrypoint(&dec).unwrap(),
+                record(i as i64)
+            );
+        }
+        assert!(log.read(n, &mut buf).is_err());
+    }
+
+    #[test]
+    fn test_record_log() {
+        let path = std::env::temp_dir().join(format!("twine-log-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut log = RecordLog::open(&path).unwrap();
+        assert!(log.is_empty());
+        for i in 0..100 {
+            assert_eq!(append(&mut log, i), i as usize);
+        }
+        log.sync().unwrap();
+        check(&log, 100);
+        drop(log);
+
+        let mut log = RecordLog::open(&path).unwrap();
+        assert_eq!(log.truncated_bytes(), 0);
+        check(&log, 100);
+        append(&mut log, 100);
+        drop(log);
+        let full_len = std::fs::metadata(&path).unwrap().len();
+
+        // torn writes: the last record is incomplete, or its content is wrong
+        for torn_len in [3, 20] {
+            let file = File::options().write(true).open(&path).unwrap();
+            file.set_len(full_len - torn_len).unwrap();
+            drop(file);
+            let mut log = RecordLog::open(&path).unwrap();
+            assert!(log.truncated_bytes() > 0);
+            check(&log, 100);
+            append(&mut log, 100);
+            check(&log, 101);
+        }
+        let mut bs = std::fs::read(&path).unwrap();
+        let n = bs.len();
+        bs[n - 2] ^= 0xff;
+        std::fs::write(&path, &bs).unwrap();
+        let log = RecordLog::open(&path).unwrap();
+        check(&log, 100);
+        assert!(log.truncated_bytes() > 0);
+        drop(log);
+
+        // corruption in the middle is an error
+        let mut bs = std::fs::read(&path).unwrap();
+        bs[100] ^= 0xff;
+        std::fs::write(&path, &bs).unwrap();
+        let e = RecordLog::open(&path).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+
+        // a corrupted length in the middle is an error too, and nothing is truncated
+        let mut bs = std::fs::read(&path).unwrap();
+        bs[100] ^= 0xff;
+        let frame = MAGIC.len();
+        bs[frame + 3] ^= 0x40;
+        std::fs::write(&path, &bs).unwrap();
+        let e = RecordLog::open(&path).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), bs.len() as u64);
+
+        // a header that was not persisted, after a valid log, is torn
+        let mut bs = std::fs::read(&path).unwrap();
+        bs[frame + 3] ^= 0x40;
+        bs.extend_from_slice(&[0; FRAME_HEADER_LEN as usize]);
+        std::fs::write(&path, &bs).unwrap();
+        let mut log = RecordLog::open(&path).unwrap();
+        assert_eq!(log.truncated_bytes(), FRAME_HEADER_LEN);
+        check(&log, 100);
+        append(&mut log, 100);
+        drop(log);
+        // and so is a record followed by zeros
+        let mut bs = std::fs::read(&path).unwrap();
+        let n = bs.len();
+        bs[n - 30..].fill(0);
+        bs.extend_from_slice(&[0; 100]);
+        std::fs::write(&path, &bs).unwrap();
+        let log = RecordLog::open(&path).unwrap();
+        check(&log, 100);
+
+        // reading doesn't disturb appending
+        let mut log = RecordLog::open(&path).unwrap();
+        let mut buf = vec![];
+        for i in 100..110 {
+            log.read(0, &mut buf).unwrap();
+            append(&mut log, i);
+        }
+        check(&log, 110);
+        drop(log);
+        check(&RecordLog::open(&path).unwrap(), 110);
+
+        std::fs::write(&path, b"not a log").unwrap();
+        assert!(RecordLog::open(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
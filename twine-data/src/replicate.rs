@@ -0,0 +1,187 @@
+//! Incremental replication.
+//!
+//! Blobs that are only modified by appending (see [`crate::Encoder::append_to_blob`],
+//! [`crate::edit`], [`crate::versions`]) never change their existing bytes.
+//! A replica that holds a previous version of such a blob, of length `prev_len`,
+//! can therefore catch up by receiving only the bytes appended since then:
+//! a [`Segment`].
+//!
+//! Segments are checked before they are used (see [`Decoder::with_suffix`]):
+//! they must consist of values that only point into the prefix the replica
+//! already has, or to earlier values of the segment, followed by a new postfix.
+//! If the blob has a checksum, the new checksum must match.
+
+use crate::{
+    types::{Error, Offset, Result},
+    Decoder,
+};
+
+/// The bytes appended to a blob since it had length `prev_len`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment<'a> {
+    /// Length of the blob before the segment was appended.
+    pub prev_len: Offset,
+    /// The appended bytes.
+    pub bytes: &'a [u8],
+    /// Entrypoint of the blob after the segment was appended.
+    pub entrypoint: Offset,
+}
+
+/// The segment appended to `blob` since it had length `prev_len`.
+///
+/// This checks the segment like a replica would.
+pub fn segment_since(blob: &[u8], prev_len: Offset) -> Result<Segment<'_>> {
+    let (prefix, bytes) = usize::try_from(prev_len)
+        .ok()
+        .and_then(|n| blob.split_at_checked(n))
+        .ok_or(Error {
+            msg: "previous length out of bounds",
+            off: prev_len,
+        })?;
+    let dec = Decoder::with_suffix(&Decoder::new(prefix)?, bytes)?;
+    Ok(Segment {
+        prev_len,
+        bytes,
+        entrypoint: dec.entrypoint()?,
+    })
+}
+
+/// Append the segment `bytes` to `blob`, after checking it.
+///
+/// `prev_len` is the length of the blob the segment was produced from; it must be
+/// the length of `blob`. Returns the new entrypoint. On error, `blob` is unchanged.
+pub fn apply_segment(blob: &mut Vec<u8>, prev_len: Offset, bytes: &[u8]) -> Result<Offset> {
+    if blob.len() as Offset != prev_len {
+        return Err(Error {
+            msg: "segment does not start at the end of the blob",
+            off: blob.len() as Offset,
+        });
+    }
+    let entrypoint = Decoder::with_suffix(&Decoder::new(blob)?, bytes)?.entrypoint()?;
+    blob.extend_from_slice(bytes);
+    Ok(entrypoint)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        edit::{self, PathElem},
+        header::{Header, FLAG_CHECKSUM},
+        value::{self, Value},
+        Encoder, Immediate,
+    };
+
+    fn doc() -> Value {
+        Value::Map(vec![
+            (
+                Value::String("title".to_string()),
+                Value::String("a shared document".to_string()),
+            ),
+            (
+                Value::String("lines".to_string()),
+                Value::Array(
+                    (0..20)
+                        .map(|i| Value::String(format!("line {i}")))
+                        .collect(),
+                ),
+            ),
+        ])
+    }
+
+    fn sync(header: Header) {
+        let mut primary = vec![];
+        let mut enc = Encoder::with_header(&mut primary, header).unwrap();
+        let root = value::write_value(&mut enc, &doc()).unwrap();
+        enc.finalize(Immediate::Pointer(root)).unwrap();
+        let mut replica = primary.clone();
+
+        let mut expected = doc();
+        for i in 0..5 {
+            let prev_len = primary.len() as Offset;
+            let new_line = Value::String(format!("edited line {i}"));
+            let mut suffix = vec![];
+            let root = {
+                let dec = Decoder::new(&primary).unwrap();
+                let path = [PathElem::Key("lines"), PathElem::Index(i)];
+                edit::edit(&dec, &path, &new_line, &mut suffix).unwrap()
+            };
+            primary.extend_from_slice(&suffix);
+            let Value::Map(m) = &mut expected else {
+                unreachable!()
+            };
+            let Value::Array(lines) = &mut m[1].1 else {
+                unreachable!()
+            };
+            lines[i] = new_line;
+
+            let seg = segment_since(&primary, prev_len).unwrap();
+            assert_eq!(seg.bytes, &suffix[..]);
+            assert_eq!(seg.entrypoint, root);
+
+            // zero-copy view, before applying
+            {
+                let dec = Decoder::new(&replica).unwrap();
+                let dec = Decoder::with_suffix(&dec, seg.bytes).unwrap();
+                assert_eq!(value::read_value_from_entrypoint(&dec).unwrap(), expected);
+                if header.has_flag(FLAG_CHECKSUM) {
+                    dec.verify_checksum().unwrap();
+                }
+            }
+
+            // a replica that is behind or ahead can't apply the segment
+            assert!(apply_segment(&mut replica.clone(), prev_len + 1, seg.bytes).is_err());
+            let entrypoint = apply_segment(&mut replica, seg.prev_len, seg.bytes).unwrap();
+            assert_eq!(entrypoint, root);
+            assert!(apply_segment(&mut replica, seg.prev_len, seg.bytes).is_err());
+            assert_eq!(replica, primary);
+        }
+
+        let dec = Decoder::new(&replica).unwrap();
+        assert_eq!(value::read_value_from_entrypoint(&dec).unwrap(), expected);
+
+        // the prefix can't be empty
+        assert!(segment_since(&primary, 0).is_err());
+    }
+
+    #[test]
+    fn test_sync() {
+        sync(Header::default());
+        sync(Header::with_flags(FLAG_CHECKSUM));
+    }
+
+    #[test]
+    fn test_bad_segments() {
+        let mut blob = vec![];
+        let mut enc = Encoder::with_header(&mut blob, Header::with_flags(FLAG_CHECKSUM)).unwrap();
+        let root = value::write_value(&mut enc, &doc()).unwrap();
+        enc.finalize(Immediate::Pointer(root)).unwrap();
+        let prev_len = blob.len();
+
+        let mut suffix = vec![];
+        {
+            let dec = Decoder::new(&blob).unwrap();
+            let mut enc = Encoder::append_to_blob(&dec, &mut suffix);
+            let s = enc.write_string("appended").unwrap();
+            let arr = enc
+                .write_array(&[Immediate::Pointer(s), Immediate::Pointer(root)])
+                .unwrap();
+            enc.finalize(Immediate::Pointer(arr)).unwrap();
+        }
+        let dec = Decoder::new(&blob).unwrap();
+        Decoder::with_suffix(&dec, &suffix).unwrap();
+
+        // truncated, or corrupted
+        for n in 1..suffix.len() {
+            assert!(Decoder::with_suffix(&dec, &suffix[..n]).is_err());
+        }
+        assert!(Decoder::with_suffix(&dec, &[]).is_err());
+        for i in 0..suffix.len() {
+            let mut bad = suffix.clone();
+            bad[i] ^= 0x01;
+            assert!(apply_segment(&mut blob, prev_len as Offset, &bad).is_err());
+        }
+        assert_eq!(blob.len(), prev_len);
+        assert!(segment_since(&blob, blob.len() as Offset + 1).is_err());
+    }
+}
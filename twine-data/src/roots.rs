@@ -0,0 +1,74 @@
+//! Named roots and metadata.
+//!
+//! Instead of a single toplevel value, a blob can contain a _root directory_,
+//! written by [`crate::Encoder::finalize_with_roots`]. It maps names to
+//! toplevel values, and also carries a map of metadata (creator, schema, timestamp, etc.).
+//!
+//! The directory is the entrypoint of the blob. It is a value tagged with
+//! [`TAG_ROOT_DIRECTORY`], wrapping an array `[roots, metadata]` where
+//! both `roots` and `metadata` are maps with string keys.
+
+use crate::types::Tag;
+
+/// Tag of a root directory.
+pub const TAG_ROOT_DIRECTORY: Tag = 0x7477_0001;
+
+#[cfg(test)]
+mod tests {
+    use crate::{value::Value, Decoder, Encoder, Immediate};
+
+    #[test]
+    fn test_roots() {
+        let mut res: Vec<u8> = vec![];
+        let mut enc = Encoder::new(&mut res);
+        let doc1 = crate::value::write_value(
+            &mut enc,
+            &Value::Array(vec![Value::Int64(1), Value::String("a".to_string())]),
+        )
+        .unwrap();
+        let doc2 = enc.write_string("second document").unwrap();
+        enc.finalize_with_roots(
+            &[
+                ("doc1", Immediate::Pointer(doc1)),
+                ("doc2", Immediate::Pointer(doc2)),
+                ("small", Immediate::Int64(3)),
+            ],
+            &[
+                ("creator", Immediate::String("me")),
+                ("timestamp", Immediate::Int64(1_700_000_000)),
+            ],
+        )
+        .unwrap();
+
+        let dec = Decoder::new(&res).unwrap();
+        let roots = dec.roots().unwrap();
+        assert_eq!(
+            roots.iter().map(|(k, _)| *k).collect::<Vec<_>>(),
+            vec!["doc1", "doc2", "small"]
+        );
+        assert_eq!(dec.root("doc1").unwrap(), Some(doc1));
+        assert_eq!(
+            dec.get_str(dec.root("doc2").unwrap().unwrap()).unwrap(),
+            "second document"
+        );
+        assert_eq!(dec.get_i64(dec.root("small").unwrap().unwrap()).unwrap(), 3);
+        assert_eq!(dec.root("nope").unwrap(), None);
+
+        let meta = dec.metadata().unwrap();
+        assert_eq!(meta.len(), 2);
+        assert_eq!(meta[0].0, "creator");
+        assert_eq!(dec.get_str(meta[0].1).unwrap(), "me");
+        assert_eq!(dec.get_i64(meta[1].1).unwrap(), 1_700_000_000);
+    }
+
+    #[test]
+    fn test_no_directory() {
+        let mut res: Vec<u8> = vec![];
+        let enc = Encoder::new(&mut res);
+        enc.finalize(Immediate::Int64(1)).unwrap();
+
+        let dec = Decoder::new(&res).unwrap();
+        assert_eq!(dec.roots().unwrap_err().msg, "no root directory");
+        assert!(dec.metadata().is_err());
+    }
+}
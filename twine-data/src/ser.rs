@@ -6,8 +6,16 @@
 use std::io;
 
 use crate::{
+    checksum::Crc32c,
+    copy::{Copier, CopyTable},
+    fragment::Fragment,
+    header::{Header, FLAG_CHECKSUM, HEADER_LEN},
+    packed::PackedElem,
+    roots::TAG_ROOT_DIRECTORY,
+    shallow_value::ShallowValue,
     types::{Offset, Tag, VariantIdx},
-    Immediate,
+    versions::TAG_VERSION_RECORD,
+    Decoder, Immediate,
 };
 
 /// Encode `n` as LEB128 into `buf`, returns how many bytes were used.
@@ -33,9 +41,38 @@ pub(crate) fn enc_leb128(mut n: u64, buf: &mut [u8]) -> usize {
     }
 }
 
+/// Number of bytes used by `first_byte_and_u64` to write the integer `n`.
+fn len_first_byte_and_u64(n: u64) -> u64 {
+    if n < 15 {
+        return 1;
+    }
+    let mut buf = [0u8; 10];
+    1 + enc_leb128(n - 15, &mut buf) as u64
+}
+
+macro_rules! write_packed_array_of {
+    ($name:ident, $typ:ty) => {
+        #[doc = concat!("Write a packed array of `", stringify!($typ), "`.")]
+        ///
+        /// See [`Encoder::write_packed_array`].
+        #[inline]
+        pub fn $name(&mut self, xs: &[$typ]) -> Result<Offset> {
+            self.write_packed_array(xs)
+        }
+    };
+}
+
 pub struct Encoder<W: io::Write> {
     w: W,
     offset: Offset,
+    /// Running checksum, if the blob has a checksum footer.
+    crc: Option<Crc32c>,
+    /// Entrypoint of the blob we're appending to, if known.
+    prev_entrypoint: Option<Offset>,
+    /// Do we write a version record upon finalizing?
+    versioned: bool,
+    /// Was a packed array written? Its padding depends on its offset.
+    wrote_packed: bool,
 }
 
 pub type Result<T> = std::result::Result<T, io::Error>;
@@ -43,15 +80,132 @@ pub type Result<T> = std::result::Result<T, io::Error>;
 impl<W: io::Write> Encoder<W> {
     /// Create an encoder.
     pub fn new(w: W) -> Self {
-        Encoder { w, offset: 0 }
+        Encoder {
+            w,
+            offset: 0,
+            crc: None,
+            prev_entrypoint: None,
+            versioned: false,
+            wrote_packed: false,
+        }
+    }
+
+    /// Create an encoder that starts by writing the given header.
+    ///
+    /// Blobs written this way can be recognized with [`Header::parse`].
+    ///
+    /// If the header has the [`FLAG_CHECKSUM`] flag, [`Encoder::finalize`]
+    /// will also write a checksum footer.
+    pub fn with_header(mut w: W, header: Header) -> Result<Self> {
+        let bytes = header.to_bytes();
+        w.write_all(&bytes)?;
+        let crc = header.has_flag(FLAG_CHECKSUM).then(|| {
+            let mut crc = Crc32c::new();
+            crc.update(&bytes);
+            crc
+        });
+        Ok(Encoder {
+            w,
+            offset: HEADER_LEN as Offset,
+            crc,
+            prev_entrypoint: None,
+            versioned: false,
+            wrote_packed: false,
+        })
+    }
+
+    /// Create an encoder that appends to an existing blob of length `existing_len`.
+    ///
+    /// `w` must write right after the end of the existing blob (eg. a file opened
+    /// in append mode). Since pointers can only go backwards, the new values can
+    /// point to values of the existing blob, but not the other way around.
+    /// Calling [`Encoder::finalize`] writes a new postfix, which then supersedes
+    /// the existing one.
+    ///
+    /// This ignores the header of the existing blob, if any; for blobs with
+    /// a checksum, use [`Encoder::append_to_blob`] instead.
+    pub fn append_to(existing_len: Offset, w: W) -> Self {
+        Encoder {
+            w,
+            offset: existing_len,
+            crc: None,
+            prev_entrypoint: None,
+            versioned: false,
+            wrote_packed: false,
+        }
+    }
+
+    /// Create an encoder that appends to the blob read by `dec`.
+    ///
+    /// This is like [`Encoder::append_to`], but it also takes the existing blob's
+    /// header into account: if the blob has a checksum, the new checksum
+    /// written by [`Encoder::finalize`] will cover the whole extended blob.
+    /// If the blob has a version history, finalizing will add a new version to it
+    /// (see [`crate::versions`]).
+    ///
+    /// When appending to a delta (see [`Decoder::with_base`]), `w` must
+    /// write right after the delta, and no checksum is written.
+    pub fn append_to_blob(dec: &Decoder, w: W) -> Self {
+        let crc = dec.running_checksum();
+        let prev_entrypoint = dec.raw_entrypoint().ok();
+        let versioned =
+            prev_entrypoint.is_some_and(|off| matches!(dec.version_record(off), Ok(Some(_))));
+        Encoder {
+            w,
+            offset: dec.len(),
+            crc,
+            prev_entrypoint,
+            versioned,
+            wrote_packed: false,
+        }
+    }
+
+    /// Create an encoder for a delta on top of the base blob read by `base`.
+    ///
+    /// Offsets start right after the base blob, so the new values can point to
+    /// values of the base (eg. a shared dictionary of strings) without copying them.
+    /// Unlike [`Encoder::append_to_blob`], the output is meant to be stored separately
+    /// from the base, and read with [`Decoder::with_base`]. The delta has no header
+    /// and no checksum.
+    pub fn with_base(base: &Decoder, w: W) -> Self {
+        Self::append_to(base.len(), w)
+    }
+
+    /// Current offset, ie. the offset at which the next value will be written.
+    #[inline]
+    pub fn offset(&self) -> Offset {
+        self.offset
+    }
+
+    /// Was a packed array written by this encoder?
+    #[inline]
+    #[cfg_attr(not(feature = "rayon"), allow(dead_code))]
+    pub(crate) fn wrote_packed(&self) -> bool {
+        self.wrote_packed
+    }
+
+    /// The underlying writer.
+    #[inline]
+    #[cfg_attr(not(feature = "tokio"), allow(dead_code))]
+    pub(crate) fn get_mut(&mut self) -> &mut W {
+        &mut self.w
+    }
+
+    /// Write raw bytes to the underlying writer.
+    pub(crate) fn write_raw(&mut self, bs: &[u8]) -> Result<()> {
+        self.w.write_all(bs)?;
+        if let Some(crc) = &mut self.crc {
+            crc.update(bs);
+        }
+        self.offset += bs.len() as Offset;
+        Ok(())
     }
 
     /// Write the tag and small integer.
     #[inline(always)]
     fn first_byte(&mut self, high: u8, low: u8) -> Result<Offset> {
-        self.w.write(&[(high << 4) | low])?;
         let off = self.offset;
-        self.offset += 1;
+        self.write_raw(&[(high << 4) | low])?;
         Ok(off)
     }
 
@@ -64,10 +218,8 @@ impl<W: io::Write> Encoder<W> {
         let mut buf = [0u8; 11];
         buf[0] = (high << 4) | 15;
         let len = enc_leb128(n - 15, &mut buf[1..]);
-        self.w.write(&buf[0..len + 1])?;
-
         let off = self.offset;
-        self.offset += len as u64 + 1;
+        self.write_raw(&buf[0..len + 1])?;
         Ok(off)
     }
 
@@ -112,16 +264,14 @@ impl<W: io::Write> Encoder<W> {
     pub fn write_f32(&mut self, f: f32) -> Result<Offset> {
         let bytes = f32::to_le_bytes(f);
         let off = self.first_byte(3, 0)?;
-        self.w.write(&bytes)?;
-        self.offset += bytes.len() as u64;
+        self.write_raw(&bytes)?;
         Ok(off)
     }
 
     pub fn write_f64(&mut self, f: f64) -> Result<Offset> {
         let bytes = f64::to_le_bytes(f);
         let off = self.first_byte(3, 1)?;
-        self.w.write(&bytes)?;
-        self.offset += bytes.len() as u64;
+        self.write_raw(&bytes)?;
         Ok(off)
     }
 
@@ -129,8 +279,7 @@ impl<W: io::Write> Encoder<W> {
     pub fn write_string(&mut self, s: &str) -> Result<Offset> {
         let len = s.len() as u64;
         let off = self.first_byte_and_u64(4, len)?;
-        self.w.write(s.as_bytes())?;
-        self.offset += len;
+        self.write_raw(s.as_bytes())?;
         Ok(off)
     }
 
@@ -138,8 +287,7 @@ impl<W: io::Write> Encoder<W> {
     pub fn write_bytes(&mut self, b: &[u8]) -> Result<Offset> {
         let len = b.len() as u64;
         let off = self.first_byte_and_u64(5, len)?;
-        self.w.write(b)?;
-        self.offset += len;
+        self.write_raw(b)?;
         Ok(off)
     }
 
@@ -182,6 +330,101 @@ impl<W: io::Write> Encoder<W> {
         Ok(off)
     }
 
+    /// Copy the value at offset `off` in `dec`, and everything it points to,
+    /// into this encoder. Returns an immediate referring to the copy.
+    ///
+    /// Offsets are translated along the way, including the targets of
+    /// [`Immediate::Ref`]. `table` remembers the values already copied from `dec`,
+    /// so that sharing is preserved, including across several calls with the same table.
+    /// See [`crate::copy`].
+    pub fn copy_from<'a>(
+        &mut self,
+        dec: &Decoder<'a>,
+        off: Offset,
+        table: &mut CopyTable,
+    ) -> Result<Immediate<'a>> {
+        let off = dec.deref(off)?;
+        // small scalars, and variants without arguments, are returned as is.
+        match dec.get_shallow_value(off)? {
+            ShallowValue::Imm(
+                imm @ (Immediate::Null
+                | Immediate::Bool(_)
+                | Immediate::Int64(_)
+                | Immediate::Float(_)),
+            ) => Ok(imm),
+            ShallowValue::Variant(c, args) if args.len() == 0 => Ok(Immediate::Variant0(c)),
+            _ => Ok(Immediate::Pointer(self.copy_value_from(dec, off, table)?)),
+        }
+    }
+
+    /// Like [`Encoder::copy_from`], but always writes the value and returns its offset.
+    pub fn copy_value_from(
+        &mut self,
+        dec: &Decoder,
+        off: Offset,
+        table: &mut CopyTable,
+    ) -> Result<Offset> {
+        Copier { dec, table }.copy_value(self, off)
+    }
+
+    /// Paste a fragment's bytes verbatim at the current offset.
+    ///
+    /// Returns a pointer to the fragment's root. See [`crate::fragment`].
+    pub fn splice(&mut self, frag: &Fragment) -> Result<Immediate<'static>> {
+        let base = self.offset;
+        self.write_raw(frag.bytes())?;
+        Ok(Immediate::Pointer(base + frag.root()))
+    }
+
+    /// Write a packed array of numbers.
+    ///
+    /// This is much more compact than an array of immediates, and the elements
+    /// can be read back without copying. See [`crate::packed`] for the layout.
+    pub fn write_packed_array<T: PackedElem>(&mut self, xs: &[T]) -> Result<Offset> {
+        let off = self.first_byte_and_u64(8, T::TAG)?;
+        self.wrote_packed = true;
+
+        // find how much padding is needed for the elements to be aligned.
+        // Padding more can make the length header longer, so we just try.
+        let data_len = (xs.len() * T::SIZE) as u64;
+        let align = T::SIZE as u64;
+        let mut pad = 0;
+        loop {
+            let len = 1 + pad + data_len;
+            let start = self.offset + len_first_byte_and_u64(len) + 1 + pad;
+            if start.is_multiple_of(align) {
+                break;
+            }
+            pad += 1;
+        }
+        debug_assert!(pad < 2 * align);
+
+        let _ = self.first_byte_and_u64(5, 1 + pad + data_len)?;
+        let mut buf = [0u8; 512];
+        buf[0] = pad as u8;
+        self.write_raw(&buf[..1 + pad as usize])?;
+
+        for chunk in xs.chunks(buf.len() / T::SIZE) {
+            let n_bytes = chunk.len() * T::SIZE;
+            for (x, out) in chunk.iter().zip(buf.chunks_exact_mut(T::SIZE)) {
+                x.write_le(out)
+            }
+            self.write_raw(&buf[..n_bytes])?;
+        }
+        Ok(off)
+    }
+
+    write_packed_array_of!(write_u8_array, u8);
+    write_packed_array_of!(write_u16_array, u16);
+    write_packed_array_of!(write_u32_array, u32);
+    write_packed_array_of!(write_u64_array, u64);
+    write_packed_array_of!(write_i8_array, i8);
+    write_packed_array_of!(write_i16_array, i16);
+    write_packed_array_of!(write_i32_array, i32);
+    write_packed_array_of!(write_i64_array, i64);
+    write_packed_array_of!(write_f32_array, f32);
+    write_packed_array_of!(write_f64_array, f64);
+
     /// Write an array.
     ///
     /// The values in the array must be converted to immediates already,
@@ -224,8 +467,7 @@ impl<W: io::Write> Encoder<W> {
                 // now write number of arguments as LEB128
                 let mut buf_len = [0u8; 10];
                 let len_of_len = enc_leb128(args.len() as u64, &mut buf_len[..]);
-                self.w.write(&buf_len[0..len_of_len])?;
-                self.offset += len_of_len as u64;
+                self.write_raw(&buf_len[0..len_of_len])?;
 
                 for a in args {
                     let _ = self.write_immediate(*a)?;
@@ -235,10 +477,78 @@ impl<W: io::Write> Encoder<W> {
         }
     }
 
+    /// Write a root directory with the given named roots and metadata,
+    /// make it the entrypoint, and consume the encoder.
+    ///
+    /// See [`crate::roots`] for more details.
+    pub fn finalize_with_roots(
+        mut self,
+        roots: &[(&str, Immediate)],
+        metadata: &[(&str, Immediate)],
+    ) -> Result<()> {
+        let dir = self.write_root_directory(roots, metadata)?;
+        self.finalize(Immediate::Pointer(dir))
+    }
+
+    /// Write a root directory, return its offset.
+    pub(crate) fn write_root_directory(
+        &mut self,
+        roots: &[(&str, Immediate)],
+        metadata: &[(&str, Immediate)],
+    ) -> Result<Offset> {
+        let roots: Vec<_> = roots
+            .iter()
+            .map(|(name, v)| (Immediate::String(name), *v))
+            .collect();
+        let roots = self.write_map(&roots)?;
+
+        let metadata: Vec<_> = metadata
+            .iter()
+            .map(|(k, v)| (Immediate::String(k), *v))
+            .collect();
+        let metadata = self.write_map(&metadata)?;
+
+        let arr = self.write_array(&[Immediate::Pointer(roots), Immediate::Pointer(metadata)])?;
+        self.write_tag(TAG_ROOT_DIRECTORY, Immediate::Pointer(arr))
+    }
+
+    /// Like [`Encoder::finalize`], but also record the new version in the
+    /// blob's version history.
+    ///
+    /// If this encoder appends to an existing blob (see [`Encoder::append_to_blob`]),
+    /// the previous entrypoint becomes the previous version, even if the blob
+    /// had no version history so far. See [`crate::versions`].
+    pub fn finalize_versioned(mut self, entrypoint: Immediate) -> Result<()> {
+        self.set_versioned();
+        self.finalize(entrypoint)
+    }
+
+    /// Record a new version upon finalizing.
+    #[cfg_attr(not(feature = "tokio"), allow(dead_code))]
+    pub(crate) fn set_versioned(&mut self) {
+        self.versioned = true;
+    }
+
     /// Write the postfix to point to `entrypoint`, and consume the encoder.
+    ///
+    /// If the blob has a checksum, the footer is written after the postfix.
     pub fn finalize(mut self, entrypoint: Immediate) -> Result<()> {
+        self.write_postfix(entrypoint)
+    }
+
+    /// Write the postfix and footer. Nothing must be written afterwards.
+    pub(crate) fn write_postfix(&mut self, entrypoint: Immediate) -> Result<()> {
         // first, write the entrypoint.
-        let entrypoint = self.write_immediate_or_return_pointer(entrypoint)?;
+        let mut entrypoint = self.write_immediate_or_return_pointer(entrypoint)?;
+
+        if self.versioned {
+            let prev = match self.prev_entrypoint {
+                Some(p) => Immediate::Pointer(p),
+                None => Immediate::Null,
+            };
+            let record = self.write_array(&[Immediate::Pointer(entrypoint), prev])?;
+            entrypoint = self.write_tag(TAG_VERSION_RECORD, Immediate::Pointer(record))?;
+        }
 
         let mut top = self.offset;
         debug_assert!(top > entrypoint);
@@ -255,11 +565,126 @@ impl<W: io::Write> Encoder<W> {
         }
 
         debug_assert!(delta <= 250);
-        self.w.write(&[delta as u8])?;
-        self.offset += 1;
+        self.write_raw(&[delta as u8])?;
+
+        if let Some(crc) = self.crc {
+            self.w.write_all(&crc.finish().to_le_bytes())?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A writer that can discard the bytes written last.
+///
+/// See [`Encoder::checkpoint`].
+pub trait Truncate: io::Write {
+    /// Number of bytes written so far.
+    fn written_len(&self) -> u64;
+
+    /// Discard all the bytes written after the first `len` ones.
+    fn truncate_to(&mut self, len: u64) -> Result<()>;
+}
+
+impl Truncate for Vec<u8> {
+    fn written_len(&self) -> u64 {
+        self.len() as u64
+    }
+
+    fn truncate_to(&mut self, len: u64) -> Result<()> {
+        self.truncate(len as usize);
+        Ok(())
+    }
+}
+
+impl Truncate for io::Cursor<Vec<u8>> {
+    fn written_len(&self) -> u64 {
+        self.position()
+    }
+
+    fn truncate_to(&mut self, len: u64) -> Result<()> {
+        self.get_mut().truncate(len as usize);
+        self.set_position(len);
+        Ok(())
+    }
+}
+
+impl<T: Truncate + ?Sized> Truncate for &mut T {
+    fn written_len(&self) -> u64 {
+        (**self).written_len()
+    }
+
+    fn truncate_to(&mut self, len: u64) -> Result<()> {
+        (**self).truncate_to(len)
+    }
+}
+
+/// A point in the output of an [`Encoder`], to roll back to.
+#[derive(Debug, Clone, Copy)]
+pub struct Checkpoint {
+    offset: Offset,
+    written_len: u64,
+    crc: Option<Crc32c>,
+}
+
+impl Checkpoint {
+    /// Offset at which the encoder was when the checkpoint was taken.
+    #[inline]
+    pub fn offset(&self) -> Offset {
+        self.offset
+    }
+}
 
+impl<W: Truncate> Encoder<W> {
+    /// Remember the current state of the encoder, to go back to it
+    /// with [`Encoder::rollback`].
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            offset: self.offset,
+            written_len: self.w.written_len(),
+            crc: self.crc,
+        }
+    }
+
+    /// Discard everything written since `cp` was taken.
+    ///
+    /// The offsets of the values written since then become invalid, and
+    /// will be reused by the next values. `cp` must come from this encoder;
+    /// this fails if it is ahead of the encoder, eg. after an earlier rollback.
+    ///
+    /// A [`CopyTable`] used since `cp` was taken still refers to the discarded
+    /// copies: roll it back too, with [`CopyTable::rollback`].
+    pub fn rollback(&mut self, cp: Checkpoint) -> Result<()> {
+        let written = self.w.written_len();
+        if cp.offset > self.offset
+            || cp.written_len > written
+            || self.offset - cp.offset != written - cp.written_len
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid checkpoint",
+            ));
+        }
+        self.w.truncate_to(cp.written_len)?;
+        self.offset = cp.offset;
+        self.crc = cp.crc;
         Ok(())
     }
+
+    /// Call `f`, and if it fails, discard everything it wrote.
+    ///
+    /// Like for [`Encoder::rollback`], a [`CopyTable`] used by `f` must be
+    /// rolled back separately if `f` fails.
+    pub fn transaction<T>(&mut self, f: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+        let cp = self.checkpoint();
+        match f(self) {
+            Ok(x) => Ok(x),
+            Err(e) => {
+                self.rollback(cp)?;
+                Err(e)
+            }
+        }
+    }
 }
 
 #[cfg(test)]
@@ -301,6 +726,118 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_append() {
+        use crate::Decoder;
+
+        let mut blob: Vec<u8> = vec![];
+        let mut enc = Encoder::new(&mut blob);
+        let s = enc.write_string("hello").unwrap();
+        let arr1 = enc
+            .write_array(&[Immediate::Pointer(s), Immediate::Int64(1)])
+            .unwrap();
+        enc.finalize(Immediate::Pointer(arr1)).unwrap();
+
+        let mut suffix: Vec<u8> = vec![];
+        let mut enc = Encoder::append_to(blob.len() as Offset, &mut suffix);
+        let arr2 = enc
+            .write_array(&[Immediate::Pointer(s), Immediate::Pointer(arr1)])
+            .unwrap();
+        enc.finalize(Immediate::Pointer(arr2)).unwrap();
+        blob.extend_from_slice(&suffix);
+
+        let dec = Decoder::new(&blob).unwrap();
+        assert_eq!(dec.entrypoint().unwrap(), arr2);
+        let mut offs = vec![];
+        dec.get_array(arr2, &mut offs).unwrap();
+        assert_eq!(dec.get_str(offs[0]).unwrap(), "hello");
+        // the old version is still there
+        dec.get_array(offs[1], &mut offs).unwrap();
+        assert_eq!(dec.get_str(offs[0]).unwrap(), "hello");
+        assert_eq!(dec.get_i64(offs[1]).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_rollback() {
+        use crate::header::{Header, FLAG_CHECKSUM};
+        use crate::Decoder;
+
+        let mut blob: Vec<u8> = vec![];
+        let mut enc = Encoder::with_header(&mut blob, Header::with_flags(FLAG_CHECKSUM)).unwrap();
+        let s = enc.write_string("kept").unwrap();
+
+        let cp = enc.checkpoint();
+        let discarded = enc.write_string("discarded").unwrap();
+        assert_eq!(discarded, cp.offset());
+        enc.write_array(&[Immediate::Pointer(s)]).unwrap();
+        enc.rollback(cp).unwrap();
+        assert_eq!(enc.offset(), cp.offset());
+
+        // a failed transaction leaves no trace, a successful one is kept
+        let e = enc
+            .transaction(|enc| {
+                enc.write_string("invalid record")?;
+                Err::<(), _>(io::Error::new(io::ErrorKind::InvalidData, "validation"))
+            })
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        let arr = enc
+            .transaction(|enc| enc.write_array(&[Immediate::Pointer(s), Immediate::Null]))
+            .unwrap();
+        assert_eq!(arr, cp.offset());
+
+        // stale checkpoints are rejected
+        let cp2 = enc.checkpoint();
+        enc.write_null().unwrap();
+        enc.rollback(cp).unwrap();
+        assert!(enc.rollback(cp2).is_err());
+        let arr = enc.write_array(&[Immediate::Pointer(s)]).unwrap();
+        enc.finalize(Immediate::Pointer(arr)).unwrap();
+
+        // the checksum only covers what was kept
+        let dec = Decoder::new_verified(&blob).unwrap();
+        let mut offs = vec![];
+        dec.get_array(dec.entrypoint().unwrap(), &mut offs).unwrap();
+        assert_eq!(offs.len(), 1);
+        assert_eq!(dec.get_str(offs[0]).unwrap(), "kept");
+
+        // the same, when appending
+        let mut suffix = io::Cursor::new(vec![]);
+        let mut enc = Encoder::append_to_blob(&dec, &mut suffix);
+        let cp = enc.checkpoint();
+        enc.write_string("discarded").unwrap();
+        enc.rollback(cp).unwrap();
+        enc.finalize(Immediate::Pointer(s)).unwrap();
+        blob.extend_from_slice(suffix.get_ref());
+        let dec = Decoder::new_verified(&blob).unwrap();
+        assert_eq!(dec.get_str(dec.entrypoint().unwrap()).unwrap(), "kept");
+    }
+
+    #[test]
+    fn test_append_with_checksum() {
+        use crate::header::{Header, FLAG_CHECKSUM};
+        use crate::Decoder;
+
+        let mut blob: Vec<u8> = vec![];
+        let mut enc = Encoder::with_header(&mut blob, Header::with_flags(FLAG_CHECKSUM)).unwrap();
+        let s = enc.write_string("hello").unwrap();
+        enc.finalize(Immediate::Pointer(s)).unwrap();
+
+        for i in 0..3 {
+            let mut suffix: Vec<u8> = vec![];
+            let dec = Decoder::new_verified(&blob).unwrap();
+            let mut enc = Encoder::append_to_blob(&dec, &mut suffix);
+            let arr = enc
+                .write_array(&[Immediate::Pointer(s), Immediate::Int64(i)])
+                .unwrap();
+            enc.finalize(Immediate::Pointer(arr)).unwrap();
+            blob.extend_from_slice(&suffix);
+
+            let dec = Decoder::new_verified(&blob).unwrap();
+            assert_eq!(dec.entrypoint().unwrap(), arr);
+        }
+    }
+
     #[test]
     fn test_ref() {
         use crate::value::Value as V;
@@ -1,10 +1,18 @@
 //! Shallow values.
 
 use crate::{
-    types::{VariantIdx, Offset, Tag},
+    types::{Error, VariantIdx, Offset, Tag},
     Decoder, Immediate, Result,
 };
 
+/// Convert a number of items to `usize`, eg. to allocate them.
+fn to_usize(n_items: u64, off: Offset) -> Result<usize> {
+    usize::try_from(n_items).map_err(|_| Error {
+        msg: "too many items for this platform",
+        off,
+    })
+}
+
 /// A value, potentially containing other values.
 ///
 /// Lifetime 'a is the lifetime of the decode (and string slices in it)
@@ -21,12 +29,18 @@ pub enum ShallowValue<'a> {
 pub struct ArrayCursor<'a> {
     pub(crate) dec: Decoder<'a>,
     pub(crate) off: Offset,
-    pub(crate) n_items: u32,
+    pub(crate) n_items: u64,
 }
 
 impl<'a> ArrayCursor<'a> {
-    pub fn len(&self) -> usize {
-        self.n_items as usize
+    /// Number of remaining items.
+    pub fn len(&self) -> u64 {
+        self.n_items
+    }
+
+    /// Number of remaining items, as a `usize`.
+    pub(crate) fn len_usize(&self) -> Result<usize> {
+        to_usize(self.n_items, self.off)
     }
 }
 
@@ -58,12 +72,18 @@ impl<'a> Iterator for ArrayCursor<'a> {
 pub struct MapCursor<'a> {
     pub(crate) dec: Decoder<'a>,
     pub(crate) off: Offset,
-    pub(crate) n_items: u32,
+    pub(crate) n_items: u64,
 }
 
 impl<'a> MapCursor<'a> {
-    pub fn len(&self) -> usize {
-        self.n_items as usize
+    /// Number of remaining items.
+    pub fn len(&self) -> u64 {
+        self.n_items
+    }
+
+    /// Number of remaining items, as a `usize`.
+    pub(crate) fn len_usize(&self) -> Result<usize> {
+        to_usize(self.n_items, self.off)
     }
 }
 
@@ -0,0 +1,783 @@
+//! Storage backends.
+//!
+//! A [`Decoder`](crate::Decoder) normally reads a blob from a single contiguous
+//! slice of bytes (see [`crate::Decoder::new`]). With [`crate::Decoder::with_storage`],
+//! it can instead read from any implementation of [`Storage`], such as
+//! a list of chunks ([`ChunkedStorage`]), or a file that is read on demand
+//! ([`PagedFile`]), so that large blobs can be queried without loading them
+//! entirely in memory.
+//!
+//! Strings, byte strings, and packed arrays are returned as slices that
+//! borrow from the storage. When such a slice is not contiguous in the storage,
+//! it is copied into a buffer that the storage retains until it is dropped
+//! (or until [`ChunkedStorage::clear_retained`] or [`PagedFile::clear_retained`]
+//! is called). Each range of bytes is copied at most once, so the memory retained
+//! is bounded by the size of the blob, not by the number of reads; it can be
+//! limited further with [`ChunkedStorage::set_max_retained`] or
+//! [`PagedFile::set_max_retained`].
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use crate::types::{Error, Offset, Result};
+
+/// A source of bytes for a [`crate::Decoder`].
+pub trait Storage: Send + Sync {
+    /// Total length, in bytes.
+    fn len(&self) -> Offset;
+
+    /// Is the storage empty?
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Copy the bytes at `off` into `buf`. Fails if there aren't enough bytes.
+    fn read_at(&self, off: Offset, buf: &mut [u8]) -> Result<()>;
+
+    /// Borrow `len` bytes at `off`.
+    fn slice(&self, off: Offset, len: u64) -> Result<&[u8]>;
+
+    /// Read the byte at `off`.
+    fn byte(&self, off: Offset) -> Result<u8> {
+        let mut buf = [0u8];
+        self.read_at(off, &mut buf)?;
+        Ok(buf[0])
+    }
+}
+
+fn out_of_bounds(off: Offset) -> Error {
+    Error {
+        msg: "length out of bounds",
+        off,
+    }
+}
+
+/// A contiguous slice of bytes.
+impl Storage for [u8] {
+    fn len(&self) -> Offset {
+        <[u8]>::len(self) as Offset
+    }
+
+    fn read_at(&self, off: Offset, buf: &mut [u8]) -> Result<()> {
+        buf.copy_from_slice(self.slice(off, buf.len() as u64)?);
+        Ok(())
+    }
+
+    fn slice(&self, off: Offset, len: u64) -> Result<&[u8]> {
+        let end = off.checked_add(len).ok_or(out_of_bounds(off))?;
+        self.get(off as usize..end as usize)
+            .ok_or(out_of_bounds(off))
+    }
+
+    fn byte(&self, off: Offset) -> Result<u8> {
+        self.get(off as usize).copied().ok_or(Error {
+            msg: "offset out of bounds",
+            off,
+        })
+    }
+}
+
+macro_rules! storage_as_slice {
+    ($typ:ty) => {
+        impl Storage for $typ {
+            fn len(&self) -> Offset {
+                self[..].len() as Offset
+            }
+
+            fn read_at(&self, off: Offset, buf: &mut [u8]) -> Result<()> {
+                self[..].read_at(off, buf)
+            }
+
+            fn slice(&self, off: Offset, len: u64) -> Result<&[u8]> {
+                Storage::slice(&self[..], off, len)
+            }
+
+            fn byte(&self, off: Offset) -> Result<u8> {
+                self[..].byte(off)
+            }
+        }
+    };
+}
+
+storage_as_slice!(&[u8]);
+storage_as_slice!(Vec<u8>);
+
+/// Retained byte ranges, by offset and length.
+type RangeMap = HashMap<(Offset, u64), Arc<[u8]>>;
+
+/// Byte ranges that were lent out.
+#[derive(Debug)]
+struct Retained {
+    /// The ranges, and their total length.
+    bufs: Mutex<(RangeMap, usize)>,
+    max_bytes: usize,
+}
+
+impl Default for Retained {
+    fn default() -> Self {
+        Retained {
+            bufs: Default::default(),
+            max_bytes: usize::MAX,
+        }
+    }
+}
+
+impl Retained {
+    /// Borrow the `len` bytes at `off`, obtaining them from `get` if they
+    /// are not retained yet, and keep them alive as long as `self`.
+    fn keep_shared(
+        &self,
+        off: Offset,
+        len: u64,
+        get: impl FnOnce() -> Result<Arc<[u8]>>,
+    ) -> Result<&[u8]> {
+        let mut bufs = self.bufs.lock().unwrap();
+        let buf = match bufs.0.get(&(off, len)) {
+            Some(buf) => buf,
+            None => {
+                let fits = usize::try_from(len)
+                    .ok()
+                    .and_then(|len| bufs.1.checked_add(len))
+                    .is_some_and(|total| total <= self.max_bytes);
+                if !fits {
+                    return Err(Error {
+                        msg: "too many bytes retained by the storage",
+                        off,
+                    });
+                }
+                let buf = get()?;
+                bufs.1 += buf.len();
+                bufs.0.entry((off, len)).or_insert(buf)
+            }
+        };
+        let ptr: *const [u8] = &**buf;
+        // SAFETY: moving the `Arc` in the map doesn't move its content.
+        // Buffers are only dropped by `clear` (which requires `&mut self`, so
+        // no slice is borrowed anymore), or along with `self`.
+        Ok(unsafe { &*ptr })
+    }
+
+    /// Like [`Retained::keep_shared`], copying the bytes with `read`.
+    fn keep(
+        &self,
+        off: Offset,
+        len: u64,
+        read: impl FnOnce(&mut [u8]) -> Result<()>,
+    ) -> Result<&[u8]> {
+        self.keep_shared(off, len, || {
+            let mut buf = vec![0u8; len as usize];
+            read(&mut buf)?;
+            Ok(buf.into())
+        })
+    }
+
+    fn n_bytes(&self) -> usize {
+        self.bufs.lock().unwrap().1
+    }
+
+    fn clear(&mut self) {
+        *self.bufs.get_mut().unwrap() = Default::default();
+    }
+}
+
+/// A blob split into several chunks (a rope).
+///
+/// This is useful for blobs that were received in several pieces, eg. from
+/// the network, to avoid concatenating them.
+#[derive(Debug, Default)]
+pub struct ChunkedStorage {
+    chunks: Vec<Vec<u8>>,
+    /// Offset of the beginning of each chunk.
+    starts: Vec<Offset>,
+    len: Offset,
+    retained: Retained,
+}
+
+impl ChunkedStorage {
+    /// Create a storage from the given chunks, in order.
+    pub fn new(chunks: Vec<Vec<u8>>) -> Self {
+        let mut st = Self::default();
+        for c in chunks {
+            st.push(c);
+        }
+        st
+    }
+
+    /// Add a chunk at the end.
+    pub fn push(&mut self, chunk: Vec<u8>) {
+        if chunk.is_empty() {
+            return;
+        }
+        self.starts.push(self.len);
+        self.len += chunk.len() as Offset;
+        self.chunks.push(chunk);
+    }
+
+    /// Number of bytes copied so far because a slice spanned several chunks.
+    pub fn retained_bytes(&self) -> usize {
+        self.retained.n_bytes()
+    }
+
+    /// Free the copies made because a slice spanned several chunks.
+    pub fn clear_retained(&mut self) {
+        self.retained.clear()
+    }
+
+    /// Retain at most `max_bytes` bytes (there is no limit by default).
+    ///
+    /// Past that, slices that need to be retained fail until [`Self::clear_retained`]
+    /// is called.
+    pub fn set_max_retained(&mut self, max_bytes: usize) {
+        self.retained.max_bytes = max_bytes;
+    }
+
+    /// Index of the chunk containing `off`, and offset within this chunk.
+    fn locate(&self, off: Offset) -> Option<(usize, usize)> {
+        if off >= self.len {
+            return None;
+        }
+        let i = self.starts.partition_point(|&s| s <= off) - 1;
+        Some((i, (off - self.starts[i]) as usize))
+    }
+}
+
+impl Storage for ChunkedStorage {
+    fn len(&self) -> Offset {
+        self.len
+    }
+
+    fn read_at(&self, off: Offset, buf: &mut [u8]) -> Result<()> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let end = off.checked_add(buf.len() as u64);
+        if end.is_none_or(|end| end > self.len) {
+            return Err(out_of_bounds(off));
+        }
+        let (mut i, mut in_chunk) = self.locate(off).ok_or(out_of_bounds(off))?;
+        let mut n = 0;
+        while n < buf.len() {
+            let c = &self.chunks[i][in_chunk..];
+            let k = c.len().min(buf.len() - n);
+            buf[n..n + k].copy_from_slice(&c[..k]);
+            n += k;
+            i += 1;
+            in_chunk = 0;
+        }
+        Ok(())
+    }
+
+    fn slice(&self, off: Offset, len: u64) -> Result<&[u8]> {
+        if len == 0 {
+            return Ok(&[]);
+        }
+        let (i, in_chunk) = self.locate(off).ok_or(out_of_bounds(off))?;
+        let c = &self.chunks[i];
+        if in_chunk as u64 + len <= c.len() as u64 {
+            return Ok(&c[in_chunk..in_chunk + len as usize]);
+        }
+        // spans several chunks, copy.
+        if off.checked_add(len).is_none_or(|end| end > self.len) {
+            return Err(out_of_bounds(off));
+        }
+        self.retained.keep(off, len, |buf| self.read_at(off, buf))
+    }
+
+    fn byte(&self, off: Offset) -> Result<u8> {
+        let (i, in_chunk) = self.locate(off).ok_or(Error {
+            msg: "offset out of bounds",
+            off,
+        })?;
+        Ok(self.chunks[i][in_chunk])
+    }
+}
+
+/// Read `buf.len()` bytes at `off` in the file.
+#[cfg(unix)]
+pub(crate) fn read_exact_at(file: &File, buf: &mut [u8], off: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, off)
+}
+
+/// Read `buf.len()` bytes at `off` in the file.
+#[cfg(windows)]
+pub(crate) fn read_exact_at(file: &File, mut buf: &mut [u8], mut off: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, off) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => {
+                buf = &mut buf[n..];
+                off += n as u64;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Read `buf.len()` bytes at `off` in the file.
+///
+/// Without positional reads, this seeks, reads, and then restores the position
+/// of the file, so that writes at the current position are unaffected.
+/// Reads are serialized, since the position is shared by all handles of the file.
+#[cfg(not(any(unix, windows)))]
+pub(crate) fn read_exact_at(mut file: &File, buf: &mut [u8], off: u64) -> io::Result<()> {
+    use std::io::{Read, Seek, SeekFrom};
+    static LOCK: Mutex<()> = Mutex::new(());
+    let _guard = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let pos = file.stream_position()?;
+    let res = file
+        .seek(SeekFrom::Start(off))
+        .and_then(|_| file.read_exact(buf));
+    file.seek(SeekFrom::Start(pos))?;
+    res
+}
+
+/// Cache of recently used pages.
+#[derive(Debug, Default)]
+struct PageCache {
+    /// Page index -> (content, last use).
+    pages: HashMap<u64, (Arc<[u8]>, u64)>,
+    clock: u64,
+}
+
+/// A blob stored in a file, read on demand, page by page.
+///
+/// The most recently used pages are kept in a cache. Slices of the blob
+/// (strings, etc.) borrow the page that contains them, which is then retained
+/// by the storage; slices that span several pages are copied and retained.
+/// See [`PagedFile::clear_retained`].
+#[derive(Debug)]
+pub struct PagedFile {
+    file: File,
+    len: Offset,
+    page_size: usize,
+    max_pages: usize,
+    cache: Mutex<PageCache>,
+    retained: Retained,
+}
+
+impl PagedFile {
+    /// Default size of a page, in bytes.
+    pub const DEFAULT_PAGE_SIZE: usize = 64 * 1024;
+
+    /// Default number of pages in the cache.
+    pub const DEFAULT_MAX_PAGES: usize = 64;
+
+    /// Open the file at `path`.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::new(File::open(path)?)
+    }
+
+    /// Read from `file`, with the default cache size.
+    pub fn new(file: File) -> io::Result<Self> {
+        Self::with_cache(file, Self::DEFAULT_PAGE_SIZE, Self::DEFAULT_MAX_PAGES)
+    }
+
+    /// Read from `file`, caching at most `max_pages` pages of `page_size` bytes.
+    pub fn with_cache(file: File, page_size: usize, max_pages: usize) -> io::Result<Self> {
+        if page_size == 0 || max_pages == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "page cache cannot be empty",
+            ));
+        }
+        let len = file.metadata()?.len();
+        Ok(PagedFile {
+            file,
+            len,
+            page_size,
+            max_pages,
+            cache: Default::default(),
+            retained: Default::default(),
+        })
+    }
+
+    /// Number of bytes retained so far: pages that slices borrow from,
+    /// and copies of slices that span several pages.
+    pub fn retained_bytes(&self) -> usize {
+        self.retained.n_bytes()
+    }
+
+    /// Free the pages and copies retained for slices.
+    pub fn clear_retained(&mut self) {
+        self.retained.clear()
+    }
+
+    /// Retain at most `max_bytes` bytes (there is no limit by default).
+    ///
+    /// Past that, slices that need to be retained fail until [`Self::clear_retained`]
+    /// is called.
+    pub fn set_max_retained(&mut self, max_bytes: usize) {
+        self.retained.max_bytes = max_bytes;
+    }
+
+    /// Call `f` on page number `idx`, reading it if needed.
+    fn with_page<R>(&self, idx: u64, f: impl FnOnce(&Arc<[u8]>) -> R) -> Result<R> {
+        let mut cache = self.cache.lock().unwrap();
+        cache.clock += 1;
+        let clock = cache.clock;
+        if let Some((page, last_use)) = cache.pages.get_mut(&idx) {
+            *last_use = clock;
+            return Ok(f(page));
+        }
+
+        if cache.pages.len() >= self.max_pages {
+            // evict the least recently used page
+            let lru = cache
+                .pages
+                .iter()
+                .min_by_key(|(_, (_, t))| *t)
+                .map(|(i, _)| *i);
+            if let Some(lru) = lru {
+                cache.pages.remove(&lru);
+            }
+        }
+
+        let start = idx * self.page_size as u64;
+        let len = (self.len - start).min(self.page_size as u64) as usize;
+        let mut page = vec![0u8; len];
+        read_exact_at(&self.file, &mut page, start).map_err(|_| Error {
+            msg: "error while reading file",
+            off: start,
+        })?;
+        let page: Arc<[u8]> = page.into();
+        let res = f(&page);
+        cache.pages.insert(idx, (page, clock));
+        Ok(res)
+    }
+}
+
+impl Storage for PagedFile {
+    fn len(&self) -> Offset {
+        self.len
+    }
+
+    fn read_at(&self, off: Offset, buf: &mut [u8]) -> Result<()> {
+        let end = off.checked_add(buf.len() as u64);
+        if end.is_none_or(|end| end > self.len) {
+            return Err(out_of_bounds(off));
+        }
+        let page_size = self.page_size as u64;
+        let mut n = 0;
+        while n < buf.len() {
+            let cur = off + n as u64;
+            let in_page = (cur % page_size) as usize;
+            n += self.with_page(cur / page_size, |page| {
+                let k = (page.len() - in_page).min(buf.len() - n);
+                buf[n..n + k].copy_from_slice(&page[in_page..in_page + k]);
+                k
+            })?;
+        }
+        Ok(())
+    }
+
+    fn slice(&self, off: Offset, len: u64) -> Result<&[u8]> {
+        if len == 0 {
+            return Ok(&[]);
+        }
+        if off.checked_add(len).is_none_or(|end| end > self.len) {
+            return Err(out_of_bounds(off));
+        }
+        let page_size = self.page_size as u64;
+        let idx = off / page_size;
+        if (off + len - 1) / page_size != idx {
+            // spans several pages, copy.
+            return self.retained.keep(off, len, |buf| self.read_at(off, buf));
+        }
+        // borrow the page, and keep it alive after it's evicted from the cache
+        let start = idx * page_size;
+        let page_len = (self.len - start).min(page_size);
+        let page = self
+            .retained
+            .keep_shared(start, page_len, || self.with_page(idx, Arc::clone))?;
+        let in_page = (off - start) as usize;
+        Ok(&page[in_page..in_page + len as usize])
+    }
+
+    fn byte(&self, off: Offset) -> Result<u8> {
+        if off >= self.len {
+            return Err(Error {
+                msg: "offset out of bounds",
+                off,
+            });
+        }
+        let page_size = self.page_size as u64;
+        self.with_page(off / page_size, |page| page[(off % page_size) as usize])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        header::{Header, FLAG_CHECKSUM},
+        shallow_value::ShallowValue,
+        value::{self, Value},
+        Decoder, Encoder, Immediate,
+    };
+
+    fn sample() -> (Vec<u8>, Value) {
+        let v = Value::Array(
+            (0..300)
+                .map(|i| {
+                    Value::Map(vec![
+                        (Value::String("id".to_string()), Value::Int64(i)),
+                        (
+                            Value::String("name".to_string()),
+                            Value::String(format!("item {i}")),
+                        ),
+                        (
+                            Value::String("data".to_string()),
+                            Value::Bytes(vec![i as u8; 20]),
+                        ),
+                    ])
+                })
+                .collect(),
+        );
+        let mut blob = vec![];
+        let mut enc = Encoder::with_header(&mut blob, Header::with_flags(FLAG_CHECKSUM)).unwrap();
+        let off = value::write_value(&mut enc, &v).unwrap();
+        let xs = enc.write_u32_array(&[1, 2, 3, 4]).unwrap();
+        let top = enc
+            .write_array(&[Immediate::Pointer(off), Immediate::Pointer(xs)])
+            .unwrap();
+        enc.finalize(Immediate::Pointer(top)).unwrap();
+        (blob, v)
+    }
+
+    fn check(st: &dyn Storage, v: &Value) {
+        let dec = Decoder::with_storage(st).unwrap();
+        dec.verify_checksum().unwrap();
+        let mut top = vec![];
+        dec.get_array(dec.entrypoint().unwrap(), &mut top).unwrap();
+        assert_eq!(&value::read_value(&dec, top[0]).unwrap(), v);
+        assert_eq!(&*dec.get_u32_array(top[1]).unwrap(), &[1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_slice() {
+        let (blob, v) = sample();
+        check(&blob, &v);
+        check(&&blob[..], &v);
+    }
+
+    #[test]
+    fn test_chunked() {
+        let (blob, v) = sample();
+        for chunk_len in [1, 7, 100, blob.len()] {
+            let st = ChunkedStorage::new(blob.chunks(chunk_len).map(|c| c.to_vec()).collect());
+            assert_eq!(st.len(), blob.len() as Offset);
+            check(&st, &v);
+            if chunk_len == blob.len() {
+                assert_eq!(st.retained_bytes(), 0);
+            }
+        }
+
+        let mut st = ChunkedStorage::new(vec![b"ab".to_vec(), vec![], b"cde".to_vec()]);
+        assert_eq!(st.slice(1, 3).unwrap(), b"bcd");
+        assert_eq!(st.slice(2, 2).unwrap(), b"cd");
+        assert_eq!(st.retained_bytes(), 3);
+        assert!(st.slice(4, 2).is_err());
+        assert_eq!(st.byte(4).unwrap(), b'e');
+        assert!(st.byte(5).is_err());
+        st.clear_retained();
+        assert_eq!(st.retained_bytes(), 0);
+
+        // each range is copied once, and copies can be bounded
+        st.set_max_retained(4);
+        assert_eq!(st.slice(1, 3).unwrap(), b"bcd");
+        assert_eq!(st.slice(1, 3).unwrap(), b"bcd");
+        assert!(st.slice(0, 3).is_err());
+        assert_eq!(st.slice(2, 3).unwrap(), b"cde");
+        assert_eq!(st.retained_bytes(), 3);
+
+        // lowering the bound below what is retained
+        st.set_max_retained(2);
+        assert!(st.slice(0, 4).is_err());
+        assert_eq!(st.slice(1, 3).unwrap(), b"bcd");
+        st.clear_retained();
+        st.set_max_retained(4);
+        assert_eq!(st.slice(0, 4).unwrap(), b"abcd");
+    }
+
+    #[test]
+    fn test_paged_file() {
+        let (blob, v) = sample();
+        let path = std::env::temp_dir().join(format!("twine-paged-{}", std::process::id()));
+        std::fs::write(&path, &blob).unwrap();
+
+        for (page_size, max_pages) in [(1, 1), (13, 3), (4096, 2)] {
+            let file = File::open(&path).unwrap();
+            let st = PagedFile::with_cache(file, page_size, max_pages).unwrap();
+            check(&st, &v);
+            assert!(st.cache.lock().unwrap().pages.len() <= max_pages);
+            let retained = st.retained_bytes();
+            assert!(retained > 0 && retained <= 2 * blob.len());
+            // reading again doesn't retain more
+            check(&st, &v);
+            assert_eq!(st.retained_bytes(), retained);
+        }
+
+        let mut st = PagedFile::open(&path).unwrap();
+        assert_eq!(st.slice(1, 5).unwrap(), b"twine");
+        assert!(st.slice(blob.len() as Offset - 2, 3).is_err());
+        st.clear_retained();
+        assert_eq!(st.retained_bytes(), 0);
+
+        // slices within a page borrow it, slices across pages are copied once
+        let file = File::open(&path).unwrap();
+        let mut st = PagedFile::with_cache(file, 16, 1).unwrap();
+        for _ in 0..100 {
+            assert_eq!(st.slice(1, 5).unwrap(), b"twine");
+            assert_eq!(st.slice(14, 4).unwrap(), &blob[14..18]);
+            let _ = st.slice(40, 2).unwrap();
+        }
+        assert_eq!(st.retained_bytes(), 16 + 4 + 16);
+        st.set_max_retained(40);
+        assert!(st.slice(60, 2).is_err());
+        assert_eq!(st.slice(2, 3).unwrap(), b"win");
+        st.clear_retained();
+        assert_eq!(st.slice(60, 2).unwrap(), &blob[60..62]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// A large blob made of zeros, except for a few chunks.
+    struct Sparse {
+        chunks: Vec<(Offset, Vec<u8>)>,
+        len: Offset,
+        retained: Retained,
+    }
+
+    impl Storage for Sparse {
+        fn len(&self) -> Offset {
+            self.len
+        }
+
+        fn read_at(&self, off: Offset, buf: &mut [u8]) -> Result<()> {
+            let end = off + buf.len() as Offset;
+            if end > self.len {
+                return Err(out_of_bounds(off));
+            }
+            buf.fill(0);
+            for (start, c) in &self.chunks {
+                let c_end = start + c.len() as Offset;
+                let (lo, hi) = (off.max(*start), end.min(c_end));
+                if lo < hi {
+                    buf[(lo - off) as usize..(hi - off) as usize]
+                        .copy_from_slice(&c[(lo - start) as usize..(hi - start) as usize]);
+                }
+            }
+            Ok(())
+        }
+
+        fn slice(&self, off: Offset, len: u64) -> Result<&[u8]> {
+            self.retained.keep(off, len, |buf| self.read_at(off, buf))
+        }
+    }
+
+    /// First byte and integer `n`, as written by the encoder.
+    fn first_byte_and_u64(high: u8, n: u64) -> Vec<u8> {
+        if n < 15 {
+            return vec![(high << 4) | n as u8];
+        }
+        let mut buf = vec![0u8; 11];
+        buf[0] = (high << 4) | 15;
+        let len = crate::ser::enc_leb128(n - 15, &mut buf[1..]);
+        buf.truncate(len + 1);
+        buf
+    }
+
+    #[test]
+    fn test_larger_than_4gib() {
+        let big = u32::MAX as u64 + 10;
+        let mut chunks = vec![];
+
+        // an array of `big` items, all `false` (ie. zeros)
+        let arr = 0;
+        let hd = first_byte_and_u64(6, big);
+        let mut off = hd.len() as Offset + big;
+        chunks.push((arr, hd));
+
+        // an array containing a byte string of length `big`, and an integer
+        let arr2 = off;
+        let mut hd = first_byte_and_u64(6, 2);
+        hd.extend(first_byte_and_u64(5, big));
+        off += hd.len() as Offset + big;
+        chunks.push((arr2, hd));
+        chunks.push((off, vec![0x17])); // 7
+        off += 1;
+
+        // a variant with `big` arguments, all `false`
+        let var = off;
+        let mut hd = first_byte_and_u64(12, 3);
+        let mut buf = [0u8; 10];
+        let n = crate::ser::enc_leb128(big, &mut buf);
+        hd.extend_from_slice(&buf[..n]);
+        off += hd.len() as Offset + big;
+        chunks.push((var, hd));
+
+        let mut suffix = vec![];
+        let mut enc = Encoder::append_to(off, &mut suffix);
+        let top = enc
+            .write_array(&[
+                Immediate::Pointer(arr),
+                Immediate::Pointer(arr2),
+                Immediate::Pointer(var),
+                Immediate::String("end"),
+            ])
+            .unwrap();
+        enc.finalize(Immediate::Pointer(top)).unwrap();
+        let len = off + suffix.len() as Offset;
+        chunks.push((off, suffix));
+
+        let st = Sparse {
+            chunks,
+            len,
+            retained: Default::default(),
+        };
+        let dec = Decoder::with_storage(&st).unwrap();
+        assert!(dec.len() > 3 * big);
+        assert_eq!(dec.entrypoint().unwrap(), top);
+
+        let mut items = vec![];
+        dec.get_array(top, &mut items).unwrap();
+        assert_eq!(items.len(), 4);
+        assert_eq!(dec.get_str(items[3]).unwrap(), "end");
+
+        let ShallowValue::Array(mut a) = dec.get_shallow_value(items[0]).unwrap() else {
+            panic!()
+        };
+        assert_eq!(a.len(), big);
+        for _ in 0..3 {
+            assert!(!dec.get_bool(a.next().unwrap().unwrap()).unwrap());
+        }
+
+        // skip over the large byte string
+        let mut a2 = vec![];
+        dec.get_array(items[1], &mut a2).unwrap();
+        assert_eq!(a2[1], arr2 + big + 7);
+        assert_eq!(dec.get_i64(a2[1]).unwrap(), 7);
+
+        let ShallowValue::Variant(c, args) = dec.get_shallow_value(items[2]).unwrap() else {
+            panic!()
+        };
+        assert_eq!(c.0, 3);
+        assert_eq!(args.len(), big);
+
+        // claimed lengths are checked against the size of the blob
+        let st = Sparse {
+            chunks: vec![(0, first_byte_and_u64(6, 1 << 40))],
+            len: 100,
+            retained: Default::default(),
+        };
+        let dec = Decoder::with_storage(&st).unwrap();
+        assert!(dec.get_shallow_value(0).is_err());
+    }
+}
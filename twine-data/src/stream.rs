@@ -0,0 +1,468 @@
+//! Streaming decoding.
+//!
+//! The entrypoint of a blob is at its end, so [`crate::Decoder`] needs the whole
+//! blob (or random access to it). [`StreamDecoder`] instead reads a blob
+//! forward from an [`io::Read`], eg. a pipe or a socket, and reports each
+//! toplevel value in the order it was written. Values are written
+//! children-first, so by the time a value is reported, the values it points to
+//! have already been reported.
+//!
+//! Only a bounded window of the bytes before the current value is kept in memory,
+//! in addition to the current value itself, which is buffered in full until it is
+//! reported. Values larger than a maximum size (see
+//! [`StreamDecoder::set_max_value_len`]) are rejected.
+//! Values can be read (with [`StreamDecoder::decoder`]) as long as
+//! they are in the window; older values have been evicted and reading them fails.
+//! Once the postfix is read, the entrypoint is reported with [`Event::Root`].
+
+use std::io::{self, Read};
+
+use crate::{
+    checksum::{Crc32c, FOOTER_LEN},
+    header::{Header, FLAG_CHECKSUM, HEADER_LEN},
+    storage::Storage,
+    types::{Error, Offset, Result},
+    Decoder,
+};
+
+/// Default size of the window, in bytes.
+pub const DEFAULT_WINDOW: usize = 1024 * 1024;
+
+/// Default maximum size of a toplevel value, in bytes.
+pub const DEFAULT_MAX_VALUE_LEN: usize = 64 * 1024 * 1024;
+
+/// Size of the reads from the underlying reader.
+const READ_LEN: usize = 8 * 1024;
+
+/// Max length of the beginning of a value, before its immediates
+/// (a byte and two LEB128 integers).
+const MAX_VALUE_HEADER: Offset = 21;
+
+/// Max length of an immediate, not counting the content of strings and bytes.
+const MAX_IMMEDIATE_HEADER: Offset = 11;
+
+/// An event produced by [`StreamDecoder::next_event`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// A toplevel value was read, it starts at this offset.
+    Value(Offset),
+    /// The postfix was read. This is the (dereferenced) offset
+    /// of the entrypoint. This is the last event.
+    Root(Offset),
+}
+
+/// The most recent bytes of the blob.
+#[derive(Debug, Default)]
+struct Window {
+    /// Offset of `buf[0]` in the blob.
+    start: Offset,
+    buf: Vec<u8>,
+}
+
+impl Window {
+    /// Offset right after the last byte read.
+    fn end(&self) -> Offset {
+        self.start + self.buf.len() as Offset
+    }
+
+    fn range(&self, off: Offset, len: u64) -> Result<std::ops::Range<usize>> {
+        if off < self.start {
+            return Err(Error {
+                msg: "offset was evicted from the window",
+                off,
+            });
+        }
+        let start = (off - self.start) as usize;
+        let end = off
+            .checked_add(len)
+            .filter(|&end| end <= self.end())
+            .ok_or(Error {
+                msg: "length out of bounds",
+                off,
+            })?;
+        Ok(start..(end - self.start) as usize)
+    }
+}
+
+impl Storage for Window {
+    fn len(&self) -> Offset {
+        self.end()
+    }
+
+    fn read_at(&self, off: Offset, buf: &mut [u8]) -> Result<()> {
+        buf.copy_from_slice(self.slice(off, buf.len() as u64)?);
+        Ok(())
+    }
+
+    fn slice(&self, off: Offset, len: u64) -> Result<&[u8]> {
+        Ok(&self.buf[self.range(off, len)?])
+    }
+}
+
+/// A forward decoder over a reader.
+///
+/// The reader must contain exactly one blob.
+#[derive(Debug)]
+pub struct StreamDecoder<R> {
+    r: R,
+    header: Option<Header>,
+    window: Window,
+    /// Number of bytes to keep before the current offset.
+    window_len: usize,
+    /// Maximum size of a toplevel value.
+    max_value_len: usize,
+    /// Offset of the next toplevel value.
+    off: Offset,
+    eof: bool,
+    done: bool,
+    /// Checksum of the bytes before `crc_off`.
+    crc: Option<Crc32c>,
+    crc_off: Offset,
+}
+
+impl<R: Read> StreamDecoder<R> {
+    /// Read a blob from `r`, keeping a window of [`DEFAULT_WINDOW`] bytes.
+    pub fn new(r: R) -> io::Result<Self> {
+        Self::with_window(r, DEFAULT_WINDOW)
+    }
+
+    /// Read a blob from `r`, keeping at least the last `window_len` bytes before
+    /// the current value in memory.
+    ///
+    /// This reads the header, if there is one.
+    pub fn with_window(r: R, window_len: usize) -> io::Result<Self> {
+        let mut st = StreamDecoder {
+            r,
+            header: None,
+            window: Window::default(),
+            window_len,
+            max_value_len: DEFAULT_MAX_VALUE_LEN,
+            off: 0,
+            eof: false,
+            done: false,
+            crc: None,
+            crc_off: 0,
+        };
+        st.fill(HEADER_LEN as Offset)?;
+        st.header = Header::parse(&st.window.buf)?;
+        if let Some(h) = st.header {
+            st.off = HEADER_LEN as Offset;
+            if h.has_flag(FLAG_CHECKSUM) {
+                st.crc = Some(Crc32c::new());
+            }
+        }
+        Ok(st)
+    }
+
+    /// Reject toplevel values larger than `max_len` bytes, instead of
+    /// [`DEFAULT_MAX_VALUE_LEN`].
+    pub fn set_max_value_len(&mut self, max_len: usize) {
+        self.max_value_len = max_len;
+    }
+
+    /// The header of the blob, if it has one.
+    #[inline]
+    pub fn header(&self) -> Option<Header> {
+        self.header
+    }
+
+    /// Offset of the next toplevel value.
+    #[inline]
+    pub fn offset(&self) -> Offset {
+        self.off
+    }
+
+    /// Offset of the oldest byte still in the window.
+    #[inline]
+    pub fn window_start(&self) -> Offset {
+        self.window.start
+    }
+
+    /// A decoder over the window, to read the values reported so far.
+    ///
+    /// Offsets are relative to the whole blob; reading values that were
+    /// evicted from the window fails.
+    pub fn decoder(&self) -> Decoder<'_> {
+        Decoder::with_storage_and_end(&self.window, self.header, self.window.end())
+    }
+
+    /// Read until the window contains the bytes before `upto`, or
+    /// the end of the reader is reached.
+    ///
+    /// The window only grows as bytes are actually read.
+    fn fill(&mut self, upto: Offset) -> io::Result<()> {
+        while !self.eof && self.window.end() < upto {
+            let len = self.window.buf.len();
+            self.window.buf.resize(len + READ_LEN, 0);
+            let res = self.r.read(&mut self.window.buf[len..]);
+            let n_read = *res.as_ref().unwrap_or(&0);
+            self.window.buf.truncate(len + n_read);
+            match res {
+                Ok(0) => self.eof = true,
+                Ok(_) => (),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Like [`Self::fill`], but fail if the reader ends before `upto`.
+    fn fill_exact(&mut self, upto: Offset) -> io::Result<()> {
+        self.fill(upto)?;
+        if self.window.end() < upto {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "unexpected end of blob",
+            ));
+        }
+        Ok(())
+    }
+
+    fn update_crc(&mut self, upto: Offset) -> Result<()> {
+        if let Some(crc) = &mut self.crc {
+            crc.update(self.window.slice(self.crc_off, upto - self.crc_off)?);
+            self.crc_off = upto;
+        }
+        Ok(())
+    }
+
+    /// Drop old bytes from the window, when there are enough of them.
+    fn evict(&mut self) -> Result<()> {
+        let keep_from = self.off.saturating_sub(self.window_len as Offset);
+        if keep_from > self.window.start + self.window_len as Offset {
+            self.update_crc(keep_from)?;
+            self.window
+                .buf
+                .drain(..(keep_from - self.window.start) as usize);
+            self.window.start = keep_from;
+        }
+        Ok(())
+    }
+
+    /// Read the postfix at `self.off`, and the footer if any.
+    fn postfix(&mut self) -> io::Result<Offset> {
+        let off = self.off;
+        let delta = self.window.byte(off)?;
+        self.done = true;
+
+        self.update_crc(off + 1)?;
+        if let Some(crc) = self.crc {
+            let footer = self.window.slice(off + 1, FOOTER_LEN as u64)?;
+            if crc.finish() != u32::from_le_bytes(footer.try_into().unwrap()) {
+                return Err(Error {
+                    msg: "checksum mismatch",
+                    off: off + 1,
+                }
+                .into());
+            }
+        }
+
+        let err = Error {
+            msg: "invalid postfix",
+            off,
+        };
+        let mut entrypoint = off.checked_sub(delta as Offset + 1).ok_or(err)?;
+        // follow the pointers written right before the postfix. The entrypoint
+        // itself may be larger than the window, so we can't rely on [`Decoder::deref`].
+        let dec = self.decoder();
+        while entrypoint >= self.window.start {
+            let (high, low) = dec.first_byte(entrypoint)?;
+            if high != 15 {
+                break;
+            }
+            let (p, _) = dec.u64_with_low(entrypoint, low)?;
+            entrypoint = entrypoint.checked_sub(p + 1).ok_or(err)?;
+        }
+        Ok(entrypoint)
+    }
+
+    /// Read the next toplevel value, or the postfix.
+    ///
+    /// Returns `Ok(None)` once the postfix has been read.
+    pub fn next_event(&mut self) -> io::Result<Option<Event>> {
+        if self.done {
+            return Ok(None);
+        }
+        self.evict()?;
+
+        // the postfix is the last byte before the footer; anything
+        // longer is a value followed by (at least) the postfix.
+        let footer_len = if self.crc.is_some() { FOOTER_LEN } else { 0 } as Offset;
+        let off = self.off;
+        self.fill_exact(off + 1 + footer_len)?;
+        self.fill(off + 2 + footer_len)?;
+        if self.eof && self.window.end() == off + 1 + footer_len {
+            return Ok(Some(Event::Root(self.postfix()?)));
+        }
+
+        self.fill(off + MAX_VALUE_HEADER)?;
+        let max_end = off.saturating_add(self.max_value_len as Offset);
+        let too_large = || -> io::Error {
+            Error {
+                msg: "value is too large",
+                off,
+            }
+            .into()
+        };
+        let (n_imms, mut cur) = self.decoder().value_layout(off)?;
+        if n_imms > self.max_value_len as u64 {
+            return Err(too_large());
+        }
+        for _ in 0..n_imms {
+            if cur >= max_end {
+                return Err(too_large());
+            }
+            self.fill_exact(cur + 1)?;
+            self.fill(cur + MAX_IMMEDIATE_HEADER)?;
+            cur = self.decoder().skip(cur)?;
+        }
+        // content of a string or byte string
+        if cur > max_end {
+            return Err(too_large());
+        }
+        self.fill_exact(cur)?;
+
+        self.off = cur;
+        Ok(Some(Event::Value(off)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        header::FLAG_CHECKSUM,
+        value::{self, Value},
+        Encoder, Immediate,
+    };
+
+    /// A reader that returns a few bytes at a time.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = buf.len().min(self.0.len()).min(3);
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    fn sample(header: Option<Header>) -> (Vec<u8>, Value) {
+        let v = Value::Array(
+            (0..500)
+                .map(|i| {
+                    Value::Array(vec![
+                        Value::Int64(i),
+                        Value::String(format!("item {i}")),
+                        Value::Bytes(vec![i as u8; 40]),
+                    ])
+                })
+                .collect(),
+        );
+        let mut blob = vec![];
+        let mut enc = match header {
+            Some(h) => Encoder::with_header(&mut blob, h).unwrap(),
+            None => Encoder::new(&mut blob),
+        };
+        let off = value::write_value(&mut enc, &v).unwrap();
+        enc.finalize(Immediate::Pointer(off)).unwrap();
+        (blob, v)
+    }
+
+    #[test]
+    fn test_stream() {
+        for header in [None, Some(Header::with_flags(FLAG_CHECKSUM))] {
+            let (blob, v) = sample(header);
+            let full = Decoder::new(&blob).unwrap();
+
+            let mut st = StreamDecoder::with_window(Trickle(&blob), 128).unwrap();
+            assert_eq!(st.header(), header);
+            let mut n_values = 0;
+            let mut root = None;
+            let mut last = None;
+            loop {
+                let before = st.offset();
+                let Some(ev) = st.next_event().unwrap() else {
+                    break;
+                };
+                // at most two windows are kept before the current value
+                assert!(before - st.window_start() <= 2 * 128);
+                match ev {
+                    Event::Value(off) => {
+                        n_values += 1;
+                        last = Some(off);
+                        // small values are fully readable from the window
+                        if let Ok(Value::Array(items)) = value::read_value(&st.decoder(), off) {
+                            assert_eq!(Value::Array(items), value::read_value(&full, off).unwrap());
+                        }
+                    }
+                    Event::Root(off) => {
+                        assert!(root.is_none());
+                        root = Some(off);
+                    }
+                }
+            }
+            assert!(st.next_event().unwrap().is_none());
+
+            // the items, the toplevel array, and the pointer to it
+            // that `finalize` writes before the postfix
+            assert_eq!(n_values, 502);
+            assert_eq!(root, Some(full.entrypoint().unwrap()));
+            assert_eq!(full.deref(last.unwrap()).unwrap(), root.unwrap());
+            assert!(st.window_start() > 0);
+            // the root's children were evicted
+            assert!(value::read_value(&st.decoder(), root.unwrap()).is_err());
+            assert_eq!(value::read_value(&full, root.unwrap()).unwrap(), v);
+
+            // with a large window, everything is readable
+            let mut st = StreamDecoder::new(&blob[..]).unwrap();
+            let root = loop {
+                if let Some(Event::Root(off)) = st.next_event().unwrap() {
+                    break off;
+                }
+            };
+            assert_eq!(value::read_value(&st.decoder(), root).unwrap(), v);
+        }
+    }
+
+    #[test]
+    fn test_stream_errors() {
+        let drain = |bs: &[u8]| -> io::Result<()> {
+            let mut st = StreamDecoder::with_window(bs, 64)?;
+            while st.next_event()?.is_some() {}
+            Ok(())
+        };
+
+        let (mut blob, _) = sample(Some(Header::with_flags(FLAG_CHECKSUM)));
+        drain(&blob).unwrap();
+        let e = drain(&blob[..blob.len() / 2]).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(drain(&blob[..blob.len() - 1]).is_err());
+        assert!(drain(&[]).is_err());
+
+        blob[100] ^= 0x1;
+        assert!(drain(&blob).is_err());
+
+        // values that claim to be huge are rejected before they are buffered,
+        // even if the reader doesn't end
+        for len in [1u64 << 62, 1_000_000_000_000, 100_000] {
+            let mut prefix = vec![0x4f];
+            let mut buf = [0u8; 10];
+            let n = crate::ser::enc_leb128(len - 15, &mut buf);
+            prefix.extend_from_slice(&buf[..n]);
+            let r = (&prefix[..]).chain(io::repeat(b'a'));
+            let mut st = StreamDecoder::new(r).unwrap();
+            st.set_max_value_len(50_000);
+            let e = st.next_event().unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        }
+        let mut st = StreamDecoder::new(&[0x6f, 0xff, 0xff, 0xff, 0x7f][..]).unwrap();
+        st.set_max_value_len(1000);
+        assert_eq!(
+            st.next_event().unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+}
@@ -63,7 +63,7 @@ pub fn read_value(d: &Decoder, off: Offset) -> Result<Value> {
         ShallowValue::Imm(v) => Value::from(v),
         ShallowValue::Tag(tag, off) => Value::Tag(tag, Box::new(read_value(d, off)?)),
         ShallowValue::Array(arr) => {
-            let mut arr_v = Vec::with_capacity(arr.len());
+            let mut arr_v = Vec::with_capacity(arr.len_usize()?);
             for off in arr {
                 let off = off?;
                 arr_v.push(read_value(d, off)?)
@@ -71,7 +71,7 @@ pub fn read_value(d: &Decoder, off: Offset) -> Result<Value> {
             Value::Array(arr_v)
         }
         ShallowValue::Map(map) => {
-            let mut map_v = Vec::with_capacity(map.len());
+            let mut map_v = Vec::with_capacity(map.len_usize()?);
             for kv in map {
                 let (k, v) = kv?;
                 map_v.push((read_value(d, k)?, read_value(d, v)?))
@@ -79,7 +79,7 @@ pub fn read_value(d: &Decoder, off: Offset) -> Result<Value> {
             Value::Map(map_v)
         }
         ShallowValue::Variant(variant_idx, args) => {
-            let mut args_v = Vec::with_capacity(args.len());
+            let mut args_v = Vec::with_capacity(args.len_usize()?);
             for a in args {
                 let a = a?;
                 args_v.push(read_value(d, a)?)
@@ -96,7 +96,7 @@ pub fn read_value_from_entrypoint(d: &Decoder) -> Result<Value> {
     read_value(d, off)
 }
 
-fn write_value_or_imm<'a, W: io::Write>(
+pub(crate) fn write_value_or_imm<'a, W: io::Write>(
     enc: &'_ mut Encoder<W>,
     v: &'a Value,
 ) -> io::Result<Immediate<'a>> {
@@ -150,11 +150,11 @@ pub fn write_value<'a, W: io::Write>(enc: &mut Encoder<W>, v: &Value) -> io::Res
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
     use proptest::prelude::*;
 
-    fn arb_values() -> impl Strategy<Value = Value> {
+    pub(crate) fn arb_values() -> impl Strategy<Value = Value> {
         // https://proptest-rs.github.io/proptest/proptest/tutorial/recursive.html heck yeah
         let leaf = prop_oneof![
             Just(Value::Null),
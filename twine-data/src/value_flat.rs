@@ -46,7 +46,7 @@ pub fn get_value<'a, 'tmp>(
             Tag(tag, v)
         }
         ShallowValue::Array(arr) => {
-            let n_items = arr.len();
+            let n_items = arr.len_usize()?;
             let args: &'tmp mut [Value] = alloc.alloc_slice_fill_copy(n_items, Default::default());
             for (i, off) in arr.into_iter().enumerate() {
                 let off = off?;
@@ -55,7 +55,7 @@ pub fn get_value<'a, 'tmp>(
             Array(args)
         }
         ShallowValue::Map(dict) => {
-            let n_items = dict.len();
+            let n_items = dict.len_usize()?;
             let pairs: &'tmp mut [(Value, Value)] =
                 alloc.alloc_slice_fill_copy(n_items, Default::default());
             for (i, pair) in dict.into_iter().enumerate() {
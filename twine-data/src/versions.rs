@@ -0,0 +1,109 @@
+//! Version history.
+//!
+//! A blob that is repeatedly extended (see [`crate::Encoder::append_to_blob`])
+//! and re-finalized contains all the previous versions of its document.
+//! To be able to find them again, [`crate::Encoder::finalize_versioned`] makes the
+//! postfix point to a _version record_ rather than directly to the toplevel value.
+//!
+//! A version record is a value tagged with [`TAG_VERSION_RECORD`], wrapping
+//! an array `[root, prev]` where `root` is the toplevel value of this version,
+//! and `prev` is the previous entrypoint of the blob (either another version record,
+//! or a plain toplevel value for blobs that were not versioned before), or `null`
+//! for the first version.
+//!
+//! [`crate::Decoder::entrypoint`] transparently returns the root of the latest
+//! version, and [`crate::Decoder::versions`] enumerates all of them.
+
+use crate::types::Tag;
+
+/// Tag of a version record.
+pub const TAG_VERSION_RECORD: Tag = 0x7477_0002;
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        edit::{self, PathElem},
+        value::{self, Value},
+        Decoder, Encoder, Immediate,
+    };
+
+    #[test]
+    fn test_versions() {
+        let mut blob: Vec<u8> = vec![];
+        let mut enc = Encoder::new(&mut blob);
+        let v0 = enc.write_string("v0").unwrap();
+        enc.finalize_versioned(Immediate::Pointer(v0)).unwrap();
+
+        let dec = Decoder::new(&blob).unwrap();
+        assert_eq!(dec.entrypoint().unwrap(), v0);
+        assert_eq!(dec.versions().unwrap(), vec![v0]);
+
+        let mut expected = vec![v0];
+        for i in 1..5 {
+            let mut suffix = vec![];
+            let dec = Decoder::new(&blob).unwrap();
+            // plain `finalize` continues the history
+            let mut enc = Encoder::append_to_blob(&dec, &mut suffix);
+            let v = enc.write_string(&format!("v{i}")).unwrap();
+            enc.finalize(Immediate::Pointer(v)).unwrap();
+            blob.extend_from_slice(&suffix);
+            expected.push(v);
+        }
+
+        let dec = Decoder::new(&blob).unwrap();
+        let versions = dec.versions().unwrap();
+        assert_eq!(versions, expected);
+        for (i, v) in versions.into_iter().enumerate() {
+            assert_eq!(dec.get_str(v).unwrap(), format!("v{i}"));
+        }
+        assert_eq!(dec.get_str(dec.entrypoint().unwrap()).unwrap(), "v4");
+    }
+
+    #[test]
+    fn test_versions_of_unversioned_blob() {
+        let mut blob: Vec<u8> = vec![];
+        let mut enc = Encoder::new(&mut blob);
+        let doc = Value::Map(vec![(Value::String("x".to_string()), Value::Int64(1))]);
+        let v0 = value::write_value(&mut enc, &doc).unwrap();
+        enc.finalize(Immediate::Pointer(v0)).unwrap();
+
+        let dec = Decoder::new(&blob).unwrap();
+        assert_eq!(dec.versions().unwrap(), vec![v0]);
+
+        // edits do not record versions unless asked to
+        let mut suffix = vec![];
+        let v1 = edit::edit(&dec, &[PathElem::Key("x")], &Value::Int64(2), &mut suffix).unwrap();
+        blob.extend_from_slice(&suffix);
+        let dec = Decoder::new(&blob).unwrap();
+        assert_eq!(dec.versions().unwrap(), vec![v1]);
+
+        // start recording versions; `v1` becomes the first version.
+        let mut suffix = vec![];
+        let mut enc = Encoder::append_to_blob(&dec, &mut suffix);
+        let v2 =
+            edit::edit_in(&mut enc, &dec, v1, &[PathElem::Key("x")], &Value::Int64(3)).unwrap();
+        enc.finalize_versioned(Immediate::Pointer(v2)).unwrap();
+        blob.extend_from_slice(&suffix);
+
+        let dec = Decoder::new(&blob).unwrap();
+        assert_eq!(dec.versions().unwrap(), vec![v1, v2]);
+        assert_eq!(dec.entrypoint().unwrap(), v2);
+
+        // edits now add versions
+        let mut suffix = vec![];
+        let v3 = edit::edit(&dec, &[PathElem::Key("x")], &Value::Int64(4), &mut suffix).unwrap();
+        blob.extend_from_slice(&suffix);
+        let dec = Decoder::new(&blob).unwrap();
+        assert_eq!(dec.versions().unwrap(), vec![v1, v2, v3]);
+        let xs: Vec<_> = dec
+            .versions()
+            .unwrap()
+            .into_iter()
+            .map(|v| value::read_value(&dec, v).unwrap())
+            .collect();
+        assert_eq!(
+            xs,
+            [2, 3, 4].map(|i| Value::Map(vec![(Value::String("x".to_string()), Value::Int64(i))]))
+        );
+    }
+}